@@ -0,0 +1,117 @@
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use evdev_rs::{
+    enums::{EventCode, EV_ABS, EV_SYN},
+    Device, GrabMode, ReadFlag,
+};
+
+use crate::multitouch::Contacts;
+use crate::numpad_layout::NumpadLayout;
+use crate::signals;
+use crate::Point;
+
+/// Describe which part of the layout the position falls in.
+fn describe(layout: &NumpadLayout, pos: Point) -> String {
+    if layout.in_numlock_bbox(pos) {
+        "numlock".to_owned()
+    } else if layout.in_calc_bbox(pos) {
+        "calc".to_owned()
+    } else if let Some((row, col)) = layout.cell(pos) {
+        match layout.get_key(pos) {
+            Some(key) => format!("row {}, col {}: {:?}", row, col, key),
+            None => format!("row {}, col {}: margin", row, col),
+        }
+    } else if let Some(key) = layout.get_key(pos) {
+        format!("top row: {:?}", key)
+    } else {
+        "outside".to_owned()
+    }
+}
+
+/// Grab the touchpad and print the position of each touch, until interrupted.
+///
+/// No keys are sent. The positions are normalized like the regions of layout files, which makes
+/// this useful for measuring a new layout.
+pub(crate) fn calibrate(touchpad: &mut Device, layout: &NumpadLayout) -> Result<()> {
+    let wait_mask = signals::install_handlers()?;
+    touchpad
+        .grab(GrabMode::Grab)
+        .context("Couldn't grab the touchpad")?;
+    println!(
+        "Calibrating with layout {}. Touch the touchpad, press Ctrl-C to stop.",
+        layout.name()
+    );
+    let mut fds = [libc::pollfd {
+        fd: touchpad.file().as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    let mut contacts = Contacts::default();
+    while !signals::shutdown_requested() {
+        match unsafe { libc::ppoll(fds.as_mut_ptr(), 1, std::ptr::null(), &wait_mask) } {
+            -1 => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err).context("Failed to poll for events");
+                }
+            }
+            _ if fds[0].revents & (libc::POLLERR | libc::POLLHUP) != 0 => {
+                println!("Touchpad disconnected");
+                break;
+            }
+            _ => {
+                while let Ok((_, ev)) = touchpad.next_event(ReadFlag::NORMAL) {
+                    match ev.event_code {
+                        EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT) => contacts.set_slot(ev.value),
+                        EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID) => {
+                            contacts.set_tracking_id(ev.value)
+                        }
+                        EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X) => {
+                            contacts.set_x(layout.flip_x(ev.value))
+                        }
+                        EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y) => {
+                            contacts.set_y(layout.flip_y(ev.value))
+                        }
+                        EventCode::EV_SYN(EV_SYN::SYN_REPORT) => {
+                            for (slot, pos) in contacts.active() {
+                                let (x, y) = layout.normalize(pos);
+                                println!(
+                                    "slot {}: x = {:.3}, y = {:.3} ({})",
+                                    slot,
+                                    x,
+                                    y,
+                                    describe(layout, pos)
+                                );
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+    touchpad
+        .grab(GrabMode::Ungrab)
+        .context("Couldn't ungrab the touchpad")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::numpad_layout::BBox;
+
+    #[test]
+    fn test_describe() {
+        let layout = NumpadLayout::m433ia(BBox::new(0, 1000, 0, 1000));
+        assert_eq!(describe(&layout, Point { x: 975, y: 45 }), "numlock");
+        assert_eq!(describe(&layout, Point { x: 25, y: 45 }), "calc");
+        assert_eq!(
+            describe(&layout, Point { x: 140, y: 209 }),
+            "row 0, col 0: KEY_KP7"
+        );
+        assert_eq!(describe(&layout, Point { x: 500, y: 990 }), "outside");
+        assert_eq!(layout.normalize(Point { x: 250, y: 500 }), (0.25, 0.5));
+    }
+}
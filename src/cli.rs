@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::LevelFilter;
+
+use crate::numpad_layout::SupportedLayout;
+
+/// Command line arguments passed to the program.
+#[derive(Debug, Default)]
+pub(crate) struct Args {
+    /// Overrides the layout specified in the config.
+    layout: Option<SupportedLayout>,
+    /// Read the config from this path instead of searching for it.
+    config: Option<PathBuf>,
+    /// Start with this profile of the config active.
+    profile: Option<String>,
+    /// Number of times `--verbose` was passed.
+    verbosity: u8,
+    /// Print the status of the running daemon and exit.
+    status: bool,
+    /// Print the input devices and exit.
+    list_devices: bool,
+    /// Only print the actions, without sending keys, grabbing the touchpad or changing brightness.
+    dry_run: bool,
+    /// Step the LED through the brightness levels at startup.
+    self_test: bool,
+    /// Print the position of each touch instead of sending keys.
+    calibrate: bool,
+    /// Print the keys and touches as lines on stdout, see [`crate::protocol`].
+    emit_protocol: bool,
+    /// Start even if the kernel already handles the numpad, with `refuse_with_native_numpad`.
+    force: bool,
+    /// Print the usage and exit.
+    help: bool,
+    /// Print the version and exit.
+    version: bool,
+}
+
+impl Args {
+    /// The version, with the git commit and date of the build, for bug reports.
+    pub(crate) const VERSION: &'static str = concat!(
+        env!("CARGO_PKG_NAME"),
+        " ",
+        env!("CARGO_PKG_VERSION"),
+        " (",
+        env!("ASUS_NUMPAD_GIT_HASH"),
+        ", built ",
+        env!("ASUS_NUMPAD_BUILD_DATE"),
+        ")"
+    );
+
+    pub(crate) const USAGE: &'static str = "\
+Usage: asus-numpad [OPTIONS]
+
+Options:
+    -l, --layout <LAYOUT>  Numpad layout to use, overriding the config.
+                           One of UX433FA, M433IA, UX581, GX701 or GX531.
+    -c, --config <PATH>    Read the config from this file, instead of searching for it.
+    -p, --profile <NAME>   Start with this profile of the config active.
+    -v, --verbose          Increase logging verbosity. Can be repeated.
+        --status           Print the status of the running daemon as JSON and exit.
+                           Needs control_socket to be set in config.
+        --list-devices     Print all input devices, along with the ones that would be
+                           used as touchpad and keyboard, and exit.
+        --dry-run          Only print the keys that would be pressed and the brightness
+                           changes, without actually doing them.
+        --self-test        Step the LED through all brightness levels at startup, to
+                           check that brightness control works.
+        --calibrate        Grab the touchpad and print the position of each touch,
+                           along with the part of the layout it's in, instead of
+                           sending keys. Runs until Ctrl-C.
+        --emit-protocol    Also print a line on stdout for each key press, numlock
+                           toggle and touch position, for on-screen overlays.
+        --force            Start even if the kernel already handles the numpad,
+                           despite refuse_with_native_numpad in the config.
+    -V, --version          Print the version, git commit and build date, and exit.
+    -h, --help             Print this help and exit.";
+
+    /// Parse the arguments passed to the current process.
+    pub(crate) fn parse() -> Result<Self> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    fn parse_from(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // support both `--flag value` and `--flag=value`
+            let (flag, inline_val) = match arg.split_once('=') {
+                Some((flag, val)) if flag.starts_with("--") => (flag.to_owned(), Some(val)),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline_val
+                    .map(str::to_owned)
+                    .or_else(|| args.next())
+                    .ok_or_else(|| anyhow!("Missing value for {}", flag))
+            };
+            match flag.as_str() {
+                "-l" | "--layout" => {
+                    let layout = value()?.parse().context("Invalid value for --layout")?;
+                    parsed.layout = Some(layout);
+                }
+                "-c" | "--config" => parsed.config = Some(PathBuf::from(value()?)),
+                "-p" | "--profile" => parsed.profile = Some(value()?),
+                "-v" | "--verbose" => parsed.verbosity = parsed.verbosity.saturating_add(1),
+                "--status" => parsed.status = true,
+                "--list-devices" => parsed.list_devices = true,
+                "--dry-run" => parsed.dry_run = true,
+                "--self-test" => parsed.self_test = true,
+                "--calibrate" => parsed.calibrate = true,
+                "--emit-protocol" => parsed.emit_protocol = true,
+                "--force" => parsed.force = true,
+                "-V" | "--version" => parsed.version = true,
+                "-h" | "--help" => parsed.help = true,
+                _ => return Err(anyhow!("Unknown argument {}\n\n{}", arg, Self::USAGE)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Get the layout passed on the command line, if any.
+    pub(crate) fn layout(&self) -> Option<&SupportedLayout> {
+        self.layout.as_ref()
+    }
+
+    /// Get the config path passed on the command line, if any.
+    pub(crate) fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    /// Get the profile passed on the command line, if any.
+    pub(crate) fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The log level requested through `--verbose`.
+    ///
+    /// Returns `None` if the flag wasn't passed, so that `RUST_LOG` is respected.
+    pub(crate) fn log_level(&self) -> Option<LevelFilter> {
+        match self.verbosity {
+            0 => None,
+            1 => Some(LevelFilter::Debug),
+            _ => Some(LevelFilter::Trace),
+        }
+    }
+
+    /// Whether `--status` was passed.
+    pub(crate) fn status(&self) -> bool {
+        self.status
+    }
+
+    /// Whether `--list-devices` was passed.
+    pub(crate) fn list_devices(&self) -> bool {
+        self.list_devices
+    }
+
+    /// Whether `--dry-run` was passed.
+    pub(crate) fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Whether `--self-test` was passed.
+    pub(crate) fn self_test(&self) -> bool {
+        self.self_test
+    }
+
+    /// Whether `--calibrate` was passed.
+    pub(crate) fn calibrate(&self) -> bool {
+        self.calibrate
+    }
+
+    /// Whether `--emit-protocol` was passed.
+    pub(crate) fn emit_protocol(&self) -> bool {
+        self.emit_protocol
+    }
+
+    /// Whether `--force` was passed.
+    pub(crate) fn force(&self) -> bool {
+        self.force
+    }
+
+    /// Whether `--help` was passed.
+    pub(crate) fn help(&self) -> bool {
+        self.help
+    }
+
+    /// Whether `--version` was passed.
+    pub(crate) fn version(&self) -> bool {
+        self.version
+    }
+}
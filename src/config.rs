@@ -1,7 +1,18 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
 use evdev_rs::enums::EV_KEY;
-use serde::Deserialize;
+use log::{info, warn};
+use serde::{Deserialize, Deserializer};
 
-use crate::numpad_layout::SupportedLayout;
+use crate::dummy_keyboard::{KeyRepeat, UinputIdentity};
+use crate::numpad_layout::{Corner, SupportedLayout};
+use crate::touchpad_i2c::{Brightness, LedCommand};
+use crate::util::CustomDuration;
 
 #[derive(Debug, PartialEq, Eq, Hash, Deserialize)]
 #[serde(rename_all = "snake_case", untagged)]
@@ -19,9 +30,253 @@ impl Default for CustomCommand {
     }
 }
 
+/// The gesture used to toggle numlock in the numlock bbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ToggleMode {
+    /// Hold the finger for `hold_duration_ms`
+    Hold,
+    /// Tap twice within `double_tap_ms`
+    DoubleTap,
+    /// Either of the above
+    Either,
+}
+
+impl Default for ToggleMode {
+    fn default() -> Self {
+        Self::Hold
+    }
+}
+
+impl ToggleMode {
+    pub(crate) fn allows_hold(&self) -> bool {
+        matches!(self, Self::Hold | Self::Either)
+    }
+
+    pub(crate) fn allows_double_tap(&self) -> bool {
+        matches!(self, Self::DoubleTap | Self::Either)
+    }
+}
+
+/// Whether holding the numlock bbox turns numlock on for good, or only while the finger stays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NumpadMode {
+    /// Numlock stays on until toggled again
+    Toggle,
+    /// Numlock stays on while the finger that held the numlock bbox is down, and other
+    /// fingers press the keys
+    Momentary,
+}
+
+impl Default for NumpadMode {
+    fn default() -> Self {
+        Self::Toggle
+    }
+}
+
+/// The D-Bus bus to serve `org.asusnumpad.Control` on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DbusBus {
+    System,
+    Session,
+}
+
+impl std::fmt::Display for DbusBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::System => "system",
+            Self::Session => "session",
+        })
+    }
+}
+
+/// Replace the key at a position in the layout grid.
 #[derive(Debug, PartialEq, Eq, Hash, Deserialize)]
+pub(crate) struct KeyRemap {
+    pub(crate) row: usize,
+    pub(crate) col: usize,
+    /// `None` if the key name was invalid
+    #[serde(deserialize_with = "deserialize_key_lenient")]
+    pub(crate) key: Option<EV_KEY>,
+}
+
+/// A position in the layout grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub(crate) struct GridCell {
+    pub(crate) row: usize,
+    pub(crate) col: usize,
+}
+
+/// Send a key of the layout as other keys, pressed all at once.
+#[derive(Debug, PartialEq, Eq, Hash, Deserialize)]
+pub(crate) struct Multikey {
+    pub(crate) key: EV_KEY,
+    pub(crate) keys: Vec<EV_KEY>,
+}
+
+/// Press a key when swiping from a corner of the touchpad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub(crate) struct CornerSwipe {
+    pub(crate) corner: Corner,
+    pub(crate) key: EV_KEY,
+}
+
+/// Deserialize the key from its name, warning (instead of failing) if it is unknown.
+fn deserialize_key_lenient<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<EV_KEY>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    match name.parse() {
+        Ok(key) => Ok(Some(key)),
+        Err(_) => {
+            warn!("Unknown key {}, ignoring it", name);
+            Ok(None)
+        }
+    }
+}
+
+/// A time of the day, like `22:30`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct TimeOfDay {
+    /// Minutes since midnight
+    pub(crate) minutes: u32,
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid time {}, expected HH:MM", s);
+        let (hours, minutes) = s.trim().split_once(':').ok_or_else(invalid)?;
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Use a different brightness during the night.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NightSchedule {
+    pub(crate) start: TimeOfDay,
+    pub(crate) end: TimeOfDay,
+    pub(crate) brightness: Brightness,
+}
+
+impl NightSchedule {
+    /// Whether the time is during the night. The night can span midnight, like `22:00` to
+    /// `07:00`, and includes its start but not its end.
+    pub(crate) fn contains(&self, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// A key on the keyboard, optionally with modifiers, like `KEY_LEFTMETA+KEY_N`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Hotkey {
+    /// Keys that need to be held down
+    pub(crate) modifiers: Vec<EV_KEY>,
+    pub(crate) key: EV_KEY,
+}
+
+impl FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = s
+            .split('+')
+            .map(|name| {
+                let name = name.trim();
+                name.parse()
+                    .map_err(|_| format!("Unknown key {} in hotkey {}", name, s))
+            })
+            .collect::<Result<Vec<EV_KEY>, _>>()?;
+        let key = keys.pop().ok_or_else(|| format!("Empty hotkey {}", s))?;
+        Ok(Self {
+            modifiers: keys,
+            key,
+        })
+    }
+}
+
+impl TryFrom<String> for Hotkey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// A rectangle on the touchpad.
+///
+/// The coordinates are fractions of the touchpad size, with `(0, 0)` at the top left.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub(crate) struct Region {
+    pub(crate) left: f32,
+    pub(crate) top: f32,
+    pub(crate) right: f32,
+    pub(crate) bottom: f32,
+}
+
+impl Region {
+    pub(crate) fn is_valid(&self) -> bool {
+        let in_range = |val: f32| (0.0..=1.0).contains(&val);
+        [self.left, self.top, self.right, self.bottom]
+            .into_iter()
+            .all(in_range)
+            && self.left < self.right
+            && self.top < self.bottom
+    }
+}
+
+/// Coordinate range of the touchpad, overriding the one it reports.
+///
+/// Each bound is optional, and the touchpad's own value is used for the missing ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub(crate) struct TouchpadRange {
+    pub(crate) minx: Option<i32>,
+    pub(crate) maxx: Option<i32>,
+    pub(crate) miny: Option<i32>,
+    pub(crate) maxy: Option<i32>,
+}
+
+/// Brightness levels to light the numpad with, depending on what turned numlock on.
+///
+/// The brightness that was last used, which starts as `default_brightness`, is kept for the
+/// missing ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub(crate) struct BrightnessLevels {
+    /// Numlock was turned on through the touchpad or the control socket
+    pub(crate) on: Option<Brightness>,
+    /// Numlock was turned on by the system, like with the numlock key of a keyboard
+    pub(crate) system_on: Option<Brightness>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
 pub(crate) struct Config {
-    layout: SupportedLayout,
+    layout: Option<SupportedLayout>,
+
+    layout_file: Option<PathBuf>,
 
     #[serde(default = "default_numlock")]
     disable_numlock_on_start: bool,
@@ -30,16 +285,526 @@ pub(crate) struct Config {
     calc_start_command: CustomCommand,
 
     calc_stop_command: Option<CustomCommand>,
+
+    #[serde(default = "default_hold_duration_ms")]
+    hold_duration_ms: u64,
+
+    #[serde(default)]
+    default_brightness: Brightness,
+
+    brightness_percent: Option<u8>,
+
+    #[serde(default)]
+    brightness_levels: BrightnessLevels,
+
+    #[serde(default)]
+    calc_on_hold: bool,
+
+    #[serde(default = "default_enable_calc_key")]
+    enable_calc_key: bool,
+
+    #[serde(default = "default_enable_brightness_cycle")]
+    enable_brightness_cycle: bool,
+
+    #[serde(default)]
+    drag_to_new_key: bool,
+
+    #[serde(default)]
+    release_on_leave: bool,
+
+    #[serde(default = "default_key_margin")]
+    key_margin: f32,
+
+    toggle_jitter: Option<f32>,
+
+    #[serde(default = "default_numlock_margin")]
+    numlock_margin: f32,
+
+    invert_x: Option<bool>,
+
+    invert_y: Option<bool>,
+
+    repeat_delay_ms: Option<u32>,
+
+    repeat_rate_ms: Option<u32>,
+
+    #[serde(default = "default_sync_numlock")]
+    sync_numlock: bool,
+
+    #[serde(default)]
+    disable_on_external_mouse: bool,
+
+    #[serde(default = "default_emit_numlock_key")]
+    emit_numlock_key: bool,
+
+    #[serde(default)]
+    toggle_mode: ToggleMode,
+
+    #[serde(default = "default_double_tap_ms")]
+    double_tap_ms: u64,
+
+    #[serde(default)]
+    mode: NumpadMode,
+
+    #[serde(default = "default_min_toggle_interval_ms")]
+    min_toggle_interval_ms: u64,
+
+    #[serde(default)]
+    activation_grace_ms: u64,
+
+    multi_finger_toggle: Option<u8>,
+
+    control_socket: Option<PathBuf>,
+
+    dbus: Option<DbusBus>,
+
+    toggle_hotkey: Option<Hotkey>,
+
+    #[serde(default)]
+    key_remaps: Vec<KeyRemap>,
+
+    brightness_cell: Option<GridCell>,
+
+    #[serde(default)]
+    multikeys: Vec<Multikey>,
+
+    top_row_keys: Option<Vec<EV_KEY>>,
+
+    #[serde(default)]
+    corner_swipes: Vec<CornerSwipe>,
+
+    #[serde(default)]
+    layers: Vec<Vec<Vec<EV_KEY>>>,
+
+    layer_corner: Option<Corner>,
+
+    #[serde(default = "default_corner_swipe_distance")]
+    corner_swipe_distance: f32,
+
+    palm_threshold: Option<i32>,
+
+    #[serde(default = "default_palm_blocks_toggle")]
+    palm_blocks_toggle: bool,
+
+    min_pressure: Option<i32>,
+
+    #[serde(default)]
+    brightness_slide: bool,
+
+    #[serde(default)]
+    hold_drag_brightness: bool,
+
+    #[serde(default)]
+    brightness_fade_ms: u64,
+
+    night_start: Option<TimeOfDay>,
+
+    night_end: Option<TimeOfDay>,
+
+    night_brightness: Option<Brightness>,
+
+    #[serde(default)]
+    pointer_while_numlock: bool,
+
+    #[serde(default)]
+    persist_state: bool,
+
+    #[serde(default)]
+    status_files: bool,
+
+    metrics_file: Option<PathBuf>,
+
+    auto_disable_secs: Option<u64>,
+
+    disable_while_typing_ms: Option<u64>,
+
+    numlock_bbox: Option<Region>,
+
+    #[serde(default)]
+    i2c_addrs: Vec<u16>,
+
+    led_command: Option<LedCommand>,
+
+    touchpad_name: Option<String>,
+
+    #[serde(default)]
+    touchpad_range: TouchpadRange,
+
+    #[serde(default)]
+    refuse_with_native_numpad: bool,
+
+    #[serde(default)]
+    toggle_blink: bool,
+
+    #[serde(default)]
+    self_test: bool,
+
+    #[serde(default = "default_uinput_name")]
+    uinput_name: String,
+
+    #[serde(default)]
+    uinput_vendor_id: u16,
+
+    #[serde(default)]
+    uinput_product_id: u16,
+
+    /// Set through the `--dry-run` flag
+    #[serde(skip)]
+    dry_run: bool,
+
+    /// Set through the `--emit-protocol` flag
+    #[serde(skip)]
+    emit_protocol: bool,
+
+    /// Named sets of options, each overriding the ones above when it's active
+    #[serde(default)]
+    profiles: BTreeMap<String, toml::value::Table>,
+
+    /// Set through the `--config` flag
+    #[serde(skip)]
+    path: Option<PathBuf>,
+
+    /// The options as read from the file, without the active profile applied
+    #[serde(skip)]
+    table: toml::value::Table,
+
+    /// Name of the active profile, if any
+    #[serde(skip)]
+    profile: Option<String>,
 }
 
 fn default_numlock() -> bool {
     true
 }
 
+fn default_hold_duration_ms() -> u64 {
+    250
+}
+
+fn default_numlock_margin() -> f32 {
+    0.1
+}
+
+fn default_key_margin() -> f32 {
+    0.1
+}
+
+fn default_sync_numlock() -> bool {
+    true
+}
+
+fn default_emit_numlock_key() -> bool {
+    true
+}
+
+fn default_double_tap_ms() -> u64 {
+    300
+}
+
+fn default_min_toggle_interval_ms() -> u64 {
+    300
+}
+
+fn default_enable_calc_key() -> bool {
+    true
+}
+
+fn default_enable_brightness_cycle() -> bool {
+    true
+}
+
+fn default_palm_blocks_toggle() -> bool {
+    true
+}
+
+fn default_corner_swipe_distance() -> f32 {
+    0.15
+}
+
+fn default_uinput_name() -> String {
+    "asus_numpad".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            layout: None,
+            layout_file: None,
+            disable_numlock_on_start: default_numlock(),
+            calc_start_command: Default::default(),
+            calc_stop_command: None,
+            hold_duration_ms: default_hold_duration_ms(),
+            default_brightness: Default::default(),
+            brightness_percent: None,
+            brightness_levels: BrightnessLevels::default(),
+            calc_on_hold: false,
+            enable_calc_key: default_enable_calc_key(),
+            enable_brightness_cycle: default_enable_brightness_cycle(),
+            drag_to_new_key: false,
+            release_on_leave: false,
+            key_margin: default_key_margin(),
+            toggle_jitter: None,
+            numlock_margin: default_numlock_margin(),
+            invert_x: None,
+            invert_y: None,
+            repeat_delay_ms: None,
+            repeat_rate_ms: None,
+            sync_numlock: default_sync_numlock(),
+            disable_on_external_mouse: false,
+            emit_numlock_key: default_emit_numlock_key(),
+            toggle_mode: Default::default(),
+            double_tap_ms: default_double_tap_ms(),
+            mode: Default::default(),
+            min_toggle_interval_ms: default_min_toggle_interval_ms(),
+            activation_grace_ms: 0,
+            multi_finger_toggle: None,
+            control_socket: None,
+            dbus: None,
+            toggle_hotkey: None,
+            key_remaps: Vec::new(),
+            brightness_cell: None,
+            multikeys: Vec::new(),
+            top_row_keys: None,
+            corner_swipes: Vec::new(),
+            layers: Vec::new(),
+            layer_corner: None,
+            corner_swipe_distance: default_corner_swipe_distance(),
+            palm_threshold: None,
+            palm_blocks_toggle: default_palm_blocks_toggle(),
+            min_pressure: None,
+            brightness_slide: false,
+            hold_drag_brightness: false,
+            brightness_fade_ms: 0,
+            night_start: None,
+            night_end: None,
+            night_brightness: None,
+            pointer_while_numlock: false,
+            persist_state: false,
+            status_files: false,
+            metrics_file: None,
+            auto_disable_secs: None,
+            disable_while_typing_ms: None,
+            numlock_bbox: None,
+            i2c_addrs: Vec::new(),
+            led_command: None,
+            touchpad_name: None,
+            touchpad_range: TouchpadRange::default(),
+            refuse_with_native_numpad: false,
+            toggle_blink: false,
+            self_test: false,
+            uinput_name: default_uinput_name(),
+            uinput_vendor_id: 0,
+            uinput_product_id: 0,
+            dry_run: false,
+            emit_protocol: false,
+            profiles: BTreeMap::new(),
+            path: None,
+            table: toml::value::Table::new(),
+            profile: None,
+        }
+    }
+}
+
 impl Config {
+    /// System-wide config path.
+    const SYSTEM_PATH: &'static str = "/etc/asus-numpad/config.toml";
+
+    /// Path of the config relative to the user's config dir.
+    const USER_PATH: &'static str = "asus-numpad/config.toml";
+
+    /// Paths used before, which are still read if none of the others exist.
+    const LEGACY_SYSTEM_PATH: &'static str = "/etc/xdg/asus_numpad.toml";
+    const LEGACY_USER_PATH: &'static str = ".config/asus_numpad.toml";
+
+    /// Paths to look for the config file in, in decreasing order of priority.
+    ///
+    /// `$XDG_CONFIG_HOME` and `~/.config` come before the system-wide path, so that the config
+    /// can be tried out without touching `/etc`.
+    fn search_paths(xdg_config_home: Option<OsString>, home: Option<OsString>) -> Vec<PathBuf> {
+        let home = home.filter(|home| !home.is_empty()).map(PathBuf::from);
+        let mut paths = Vec::new();
+        if let Some(config_home) = xdg_config_home.filter(|dir| !dir.is_empty()) {
+            paths.push(PathBuf::from(config_home).join(Self::USER_PATH));
+        }
+        if let Some(home) = &home {
+            paths.push(home.join(".config").join(Self::USER_PATH));
+        }
+        paths.push(PathBuf::from(Self::SYSTEM_PATH));
+        paths.push(PathBuf::from(Self::LEGACY_SYSTEM_PATH));
+        if let Some(home) = &home {
+            paths.push(home.join(Self::LEGACY_USER_PATH));
+        }
+        paths
+    }
+
+    /// Read the config from `path` if given, or from the first path in [`Config::search_paths`]
+    /// that exists.
+    ///
+    /// Falls back to [`Config::default`] if no file is found. Fails if the file is malformed, or
+    /// if `path` was given and can't be read.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self> {
+        let paths = match path {
+            Some(path) => vec![path.to_owned()],
+            None => Self::search_paths(
+                std::env::var_os("XDG_CONFIG_HOME"),
+                std::env::var_os("HOME"),
+            ),
+        };
+        for found in &paths {
+            let data = match std::fs::read(found) {
+                Ok(data) => data,
+                Err(err) if path.is_some() => {
+                    return Err(err)
+                        .with_context(|| format!("Couldn't read config {}", found.display()));
+                }
+                Err(_) => continue,
+            };
+            info!("Reading config from {}", found.display());
+            if found.ends_with(Self::LEGACY_USER_PATH)
+                || found == Path::new(Self::LEGACY_SYSTEM_PATH)
+            {
+                warn!(
+                    "{} is deprecated, move it to {} or ~/.config/{}",
+                    found.display(),
+                    Self::SYSTEM_PATH,
+                    Self::USER_PATH
+                );
+            }
+            let mut config = Self::parse(&data)
+                .with_context(|| format!("Malformed config at {}", found.display()))?;
+            config.validate();
+            // so that reloading reads the same file
+            config.path = path.map(Path::to_owned);
+            return Ok(config);
+        }
+        let names: Vec<_> = paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        warn!(
+            "No config file found in {}, using defaults",
+            names.join(", ")
+        );
+        Ok(Self::default())
+    }
+
+    /// Parse the config file's contents, keeping the options to apply profiles over later.
+    fn parse(data: &[u8]) -> Result<Self, toml::de::Error> {
+        let table: toml::value::Table = toml::from_slice(data)?;
+        let mut config: Self = toml::Value::Table(table.clone()).try_into()?;
+        config.table = table;
+        Ok(config)
+    }
+
+    /// The config with the profile `name` applied over the options of the file, or with no
+    /// profile applied if `name` is `None`.
+    ///
+    /// The options that aren't read from the file, like `--dry-run`, are kept.
+    pub(crate) fn with_profile(&self, name: Option<&str>) -> Result<Self> {
+        let mut table = self.table.clone();
+        if let Some(name) = name {
+            let profile = self
+                .profiles
+                .get(name)
+                .ok_or_else(|| anyhow!("Unknown profile {}", name))?;
+            table.extend(profile.clone());
+        }
+        let mut config: Self = toml::Value::Table(table)
+            .try_into()
+            .with_context(|| format!("Invalid profile {}", name.unwrap_or_default()))?;
+        config.validate();
+        config.table = self.table.clone();
+        config.path = self.path.clone();
+        config.dry_run = self.dry_run;
+        config.emit_protocol = self.emit_protocol;
+        config.profile = name.map(str::to_owned);
+        Ok(config)
+    }
+
+    /// Fix up any values that are out of their sane ranges.
+    fn validate(&mut self) {
+        const HOLD_DURATION_RANGE_MS: (u64, u64) = (100, 3000);
+        let (min, max) = HOLD_DURATION_RANGE_MS;
+        if !(min..=max).contains(&self.hold_duration_ms) {
+            let clamped = self.hold_duration_ms.clamp(min, max);
+            warn!(
+                "hold_duration_ms should be between {} and {}, clamping {} to {}",
+                min, max, self.hold_duration_ms, clamped
+            );
+            self.hold_duration_ms = clamped;
+        }
+        const MAX_BRIGHTNESS_FADE_MS: u64 = 500;
+        if self.brightness_fade_ms > MAX_BRIGHTNESS_FADE_MS {
+            warn!(
+                "brightness_fade_ms should be at most {}, clamping {} to {}",
+                MAX_BRIGHTNESS_FADE_MS, self.brightness_fade_ms, MAX_BRIGHTNESS_FADE_MS
+            );
+            self.brightness_fade_ms = MAX_BRIGHTNESS_FADE_MS;
+        }
+        if let Some(fingers) = self.multi_finger_toggle {
+            if !(2..=5).contains(&fingers) {
+                warn!(
+                    "multi_finger_toggle should be between 2 and 5, ignoring {}",
+                    fingers
+                );
+                self.multi_finger_toggle = None;
+            }
+        }
+        for (name, profile) in &mut self.profiles {
+            if profile.remove("profiles").is_some() {
+                warn!(
+                    "Profiles can't define profiles, ignoring the ones in {}",
+                    name
+                );
+            }
+        }
+        if let Some(jitter) = self.toggle_jitter {
+            if !(0.0..=1.0).contains(&jitter) {
+                warn!(
+                    "toggle_jitter should be between 0 and 1, ignoring {}",
+                    jitter
+                );
+                self.toggle_jitter = None;
+            }
+        }
+        if self.layer_corner == Some(Corner::TopRight) {
+            warn!("layer_corner can't be top_right, since that's where numlock is. Ignoring it");
+            self.layer_corner = None;
+        }
+        let night = [
+            self.night_start.is_some(),
+            self.night_end.is_some(),
+            self.night_brightness.is_some(),
+        ];
+        if night.contains(&true) && night.contains(&false) {
+            warn!(
+                "The night schedule needs night_start, night_end and night_brightness. Ignoring it"
+            );
+            self.night_start = None;
+            self.night_end = None;
+            self.night_brightness = None;
+        }
+        if let Some(region) = self.numlock_bbox {
+            if !region.is_valid() {
+                warn!(
+                    "numlock_bbox should be within 0 and 1, with left < right and top < bottom. \
+                    Ignoring {:?}",
+                    region
+                );
+                self.numlock_bbox = None;
+            }
+        }
+        if let Some(command) = &self.led_command {
+            if let Err(err) = command.validate() {
+                warn!("Ignoring led_command: {}", err);
+                self.led_command = None;
+            }
+        }
+    }
+
     /// Get a reference to the config's layout.
-    pub(crate) fn layout(&self) -> &SupportedLayout {
-        &self.layout
+    pub(crate) fn layout(&self) -> Option<&SupportedLayout> {
+        self.layout.as_ref()
     }
 
     /// Get a reference to the config's disable numlock on start.
@@ -56,4 +821,484 @@ impl Config {
     pub(crate) fn calc_stop_command(&self) -> Option<&CustomCommand> {
         self.calc_stop_command.as_ref()
     }
+
+    /// How long the numlock or calc bbox needs to be held to trigger it.
+    pub(crate) fn hold_duration(&self) -> CustomDuration {
+        CustomDuration::from_millis(self.hold_duration_ms)
+    }
+
+    /// The brightness to light the LED with when numlock is turned on.
+    pub(crate) fn default_brightness(&self) -> Brightness {
+        self.default_brightness
+    }
+
+    /// The brightness to light the LED with, depending on what turned numlock on.
+    pub(crate) fn brightness_levels(&self) -> BrightnessLevels {
+        self.brightness_levels
+    }
+
+    /// Whether holding the calc bbox also triggers the calc key while numlock is off.
+    pub(crate) fn calc_on_hold(&self) -> bool {
+        self.calc_on_hold
+    }
+
+    /// Get whether the config enables the calc key.
+    pub(crate) fn enable_calc_key(&self) -> bool {
+        self.enable_calc_key
+    }
+
+    /// Get whether the config enables cycling the brightness by holding the calc bbox.
+    pub(crate) fn enable_brightness_cycle(&self) -> bool {
+        self.enable_brightness_cycle
+    }
+
+    /// Whether a finger dragged onto another key presses it, instead of moving the pointer.
+    pub(crate) fn drag_to_new_key(&self) -> bool {
+        self.drag_to_new_key
+    }
+
+    /// Whether a key is released as soon as the finger leaves it, even into the margins.
+    pub(crate) fn release_on_leave(&self) -> bool {
+        self.release_on_leave
+    }
+
+    /// The fraction of a key's width and height along its edges where touches are ignored.
+    pub(crate) fn key_margin(&self) -> f32 {
+        self.key_margin
+    }
+
+    /// How far the finger may move while holding the numlock bbox, as a fraction of the
+    /// touchpad size. Any movement within the bbox is allowed if `None`.
+    pub(crate) fn toggle_jitter(&self) -> Option<f32> {
+        self.toggle_jitter
+    }
+
+    /// Whether to flip the touchpad's x axis, or `None` to keep the layout's.
+    pub(crate) fn invert_x(&self) -> Option<bool> {
+        self.invert_x
+    }
+
+    /// Whether to flip the touchpad's y axis, or `None` to keep the layout's.
+    pub(crate) fn invert_y(&self) -> Option<bool> {
+        self.invert_y
+    }
+
+    /// The fraction of the numlock bbox's width and height along its edges that doesn't toggle.
+    pub(crate) fn numlock_margin(&self) -> f32 {
+        self.numlock_margin
+    }
+
+    /// The kernel's key repeat for the numpad keys, if either of the repeat params is set.
+    pub(crate) fn key_repeat(&self) -> Option<KeyRepeat> {
+        // same as the kernel defaults
+        const DEFAULT_DELAY_MS: u32 = 250;
+        const DEFAULT_PERIOD_MS: u32 = 33;
+        if self.repeat_delay_ms.is_none() && self.repeat_rate_ms.is_none() {
+            return None;
+        }
+        Some(KeyRepeat {
+            delay_ms: self.repeat_delay_ms.unwrap_or(DEFAULT_DELAY_MS) as i32,
+            period_ms: self.repeat_rate_ms.unwrap_or(DEFAULT_PERIOD_MS) as i32,
+        })
+    }
+
+    /// Whether the numpad follows the system's NumLock, like when another keyboard toggles it.
+    pub(crate) fn sync_numlock(&self) -> bool {
+        self.sync_numlock
+    }
+
+    /// Whether plugging in a USB or Bluetooth mouse turns numlock off.
+    pub(crate) fn disable_on_external_mouse(&self) -> bool {
+        self.disable_on_external_mouse
+    }
+
+    /// Get whether the config sends `KEY_NUMLOCK` when the numpad is toggled.
+    pub(crate) fn emit_numlock_key(&self) -> bool {
+        self.emit_numlock_key
+    }
+
+    /// The gesture that toggles numlock in the numlock bbox.
+    pub(crate) fn toggle_mode(&self) -> ToggleMode {
+        self.toggle_mode
+    }
+
+    /// The max time between the two taps of a double tap.
+    pub(crate) fn double_tap_duration(&self) -> CustomDuration {
+        CustomDuration::from_millis(self.double_tap_ms)
+    }
+
+    /// The min time between two numlock toggles from the touchpad, sooner ones are ignored.
+    pub(crate) fn min_toggle_interval(&self) -> CustomDuration {
+        CustomDuration::from_millis(self.min_toggle_interval_ms)
+    }
+
+    /// How long touches don't press keys after numlock is turned on, zero if disabled.
+    pub(crate) fn activation_grace(&self) -> CustomDuration {
+        CustomDuration::from_millis(self.activation_grace_ms)
+    }
+
+    /// Get the touchpad tool reported when tapping with the configured number of fingers.
+    pub(crate) fn multi_finger_toggle_tool(&self) -> Option<EV_KEY> {
+        match self.multi_finger_toggle? {
+            2 => Some(EV_KEY::BTN_TOOL_DOUBLETAP),
+            3 => Some(EV_KEY::BTN_TOOL_TRIPLETAP),
+            4 => Some(EV_KEY::BTN_TOOL_QUADTAP),
+            5 => Some(EV_KEY::BTN_TOOL_QUINTTAP),
+            _ => None,
+        }
+    }
+
+    /// The path of the Unix socket to listen on for control commands, if any.
+    pub(crate) fn control_socket(&self) -> Option<&Path> {
+        self.control_socket.as_deref()
+    }
+
+    /// The bus to serve `org.asusnumpad.Control` on, if the D-Bus service is enabled.
+    pub(crate) fn dbus(&self) -> Option<DbusBus> {
+        self.dbus
+    }
+
+    /// The keys replacing the layout's at some of its grid positions.
+    pub(crate) fn key_remaps(&self) -> &[KeyRemap] {
+        self.key_remaps.as_ref()
+    }
+
+    /// The grid cell that cycles the brightness while numlock is on, instead of its key.
+    pub(crate) fn brightness_cell(&self) -> Option<GridCell> {
+        self.brightness_cell
+    }
+
+    /// The keys of the layout that are sent as several keys pressed at once.
+    pub(crate) fn multikeys(&self) -> &[Multikey] {
+        self.multikeys.as_ref()
+    }
+
+    /// The contact size above which a touch is a palm and presses no keys, if set.
+    pub(crate) fn palm_threshold(&self) -> Option<i32> {
+        self.palm_threshold
+    }
+
+    /// Whether palms are also ignored for toggling numlock and the calc key.
+    pub(crate) fn palm_blocks_toggle(&self) -> bool {
+        self.palm_blocks_toggle
+    }
+
+    /// The pressure a finger needs to reach before it presses keys, if set.
+    pub(crate) fn min_pressure(&self) -> Option<i32> {
+        self.min_pressure
+    }
+
+    /// Whether sliding along the top edge sets the brightness while numlock is on.
+    pub(crate) fn brightness_slide(&self) -> bool {
+        self.brightness_slide
+    }
+
+    /// Whether the numlock state and brightness are saved, and restored on the next start.
+    pub(crate) fn persist_state(&self) -> bool {
+        self.persist_state
+    }
+
+    /// How long numlock stays on without a key press before it's turned off, if enabled.
+    pub(crate) fn auto_disable_duration(&self) -> Option<CustomDuration> {
+        match self.auto_disable_secs {
+            Some(0) | None => None,
+            Some(secs) => Some(CustomDuration::from_millis(secs * 1000)),
+        }
+    }
+
+    /// The region that toggles numlock, overriding the layout's if set.
+    pub(crate) fn numlock_bbox(&self) -> Option<Region> {
+        self.numlock_bbox
+    }
+
+    /// Whether to only print the keys and brightness changes, instead of making them.
+    pub(crate) fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Set whether to only print the keys and brightness changes, as with `--dry-run`.
+    pub(crate) fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Whether to also print a line on stdout for each event, for on-screen overlays.
+    pub(crate) fn emit_protocol(&self) -> bool {
+        self.emit_protocol
+    }
+
+    /// Set whether to print the events on stdout, as with `--emit-protocol`.
+    pub(crate) fn set_emit_protocol(&mut self, emit_protocol: bool) {
+        self.emit_protocol = emit_protocol;
+    }
+
+    /// The I2C addresses to probe for the LED controller, in order.
+    pub(crate) fn i2c_addrs(&self) -> &[u16] {
+        self.i2c_addrs.as_ref()
+    }
+
+    /// The I2C messages that set the LED brightness, overriding the layout's if set.
+    pub(crate) fn led_command(&self) -> Option<&LedCommand> {
+        self.led_command.as_ref()
+    }
+
+    /// A part of the name of the input device to use as the touchpad, if set.
+    pub(crate) fn touchpad_name(&self) -> Option<&str> {
+        self.touchpad_name.as_deref()
+    }
+
+    /// Whether the LED briefly flashes at full brightness when numlock is toggled.
+    pub(crate) fn toggle_blink(&self) -> bool {
+        self.toggle_blink
+    }
+
+    /// The LED brightness in percent, taking precedence over `default_brightness` if set.
+    pub(crate) fn brightness_percent(&self) -> Option<u8> {
+        self.brightness_percent
+    }
+
+    /// Get the name and IDs of the virtual keyboard.
+    pub(crate) fn uinput_identity(&self) -> UinputIdentity<'_> {
+        UinputIdentity {
+            name: &self.uinput_name,
+            vendor_id: self.uinput_vendor_id,
+            product_id: self.uinput_product_id,
+        }
+    }
+
+    /// Get the keys pressed by the calculator commands, corner swipes and the top row.
+    pub(crate) fn extra_keys(&self) -> Vec<EV_KEY> {
+        std::iter::once(&self.calc_start_command)
+            .chain(self.calc_stop_command.as_ref())
+            .flat_map(|cmd| match cmd {
+                CustomCommand::Keys(keys) => keys.clone(),
+                CustomCommand::Command { .. } => Vec::new(),
+            })
+            .chain(self.corner_swipes.iter().map(|swipe| swipe.key))
+            .chain(self.top_row_keys.iter().flatten().copied())
+            .collect()
+    }
+
+    /// Whether numlock stays on after a toggle, or only while the toggling finger is down.
+    pub(crate) fn mode(&self) -> NumpadMode {
+        self.mode
+    }
+
+    /// Whether to step the LED through the brightness levels at startup.
+    pub(crate) fn self_test(&self) -> bool {
+        self.self_test
+    }
+
+    /// Enable the self test, as requested through `--self-test`.
+    pub(crate) fn set_self_test(&mut self) {
+        self.self_test = true;
+    }
+
+    /// The keys along the top edge, overriding the layout's top row if set.
+    pub(crate) fn top_row_keys(&self) -> Option<&[EV_KEY]> {
+        self.top_row_keys.as_deref()
+    }
+
+    /// How long touches don't press keys after a key press on the keyboard, if enabled.
+    pub(crate) fn disable_while_typing_duration(&self) -> Option<CustomDuration> {
+        match self.disable_while_typing_ms {
+            Some(0) | None => None,
+            Some(millis) => Some(CustomDuration::from_millis(millis)),
+        }
+    }
+
+    /// The keys pressed by swiping from a corner of the touchpad towards its center.
+    pub(crate) fn corner_swipes(&self) -> &[CornerSwipe] {
+        self.corner_swipes.as_ref()
+    }
+
+    /// How far the finger needs to move for a corner swipe, as a fraction of the touchpad size.
+    pub(crate) fn corner_swipe_distance(&self) -> f32 {
+        self.corner_swipe_distance
+    }
+
+    /// Whether to keep the numlock and brightness status files up to date in the runtime dir.
+    pub(crate) fn status_files(&self) -> bool {
+        self.status_files
+    }
+
+    /// The file to keep up to date with metrics in the OpenMetrics format, if any.
+    pub(crate) fn metrics_file(&self) -> Option<&Path> {
+        self.metrics_file.as_deref()
+    }
+
+    /// The layout file to load, taking precedence over `layout` if set.
+    pub(crate) fn layout_file(&self) -> Option<&Path> {
+        self.layout_file.as_deref()
+    }
+
+    /// The keyboard key, with its modifiers, that toggles numlock, if any.
+    pub(crate) fn toggle_hotkey(&self) -> Option<&Hotkey> {
+        self.toggle_hotkey.as_ref()
+    }
+
+    /// The alternate keymaps that `layer_corner` switches the numpad keys to.
+    pub(crate) fn layers(&self) -> &[Vec<Vec<EV_KEY>>] {
+        self.layers.as_ref()
+    }
+
+    /// The corner to hold to switch to the next of the `layers`, if any.
+    pub(crate) fn layer_corner(&self) -> Option<Corner> {
+        self.layer_corner
+    }
+
+    /// Get the name of the config's active profile, if any.
+    pub(crate) fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Get the config path passed through `--config`, if any.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Whether dragging up or down after holding the numlock bbox changes the brightness.
+    pub(crate) fn hold_drag_brightness(&self) -> bool {
+        self.hold_drag_brightness
+    }
+
+    /// How long the LED takes to fade to a new brightness, zero to change it instantly.
+    pub(crate) fn brightness_fade(&self) -> Duration {
+        Duration::from_millis(self.brightness_fade_ms)
+    }
+
+    /// Get whether the config keeps the touchpad usable as a pointer while numlock is on.
+    pub(crate) fn pointer_while_numlock(&self) -> bool {
+        self.pointer_while_numlock
+    }
+
+    /// Set whether the touchpad stays usable as a pointer while numlock is on.
+    pub(crate) fn set_pointer_while_numlock(&mut self, pointer_while_numlock: bool) {
+        self.pointer_while_numlock = pointer_while_numlock;
+    }
+
+    /// The bounds that override the ones reported by the touchpad.
+    pub(crate) fn touchpad_range(&self) -> TouchpadRange {
+        self.touchpad_range
+    }
+
+    /// When and how bright to light the LED at night, if all of the night options are set.
+    pub(crate) fn night_schedule(&self) -> Option<NightSchedule> {
+        Some(NightSchedule {
+            start: self.night_start?,
+            end: self.night_end?,
+            brightness: self.night_brightness?,
+        })
+    }
+
+    /// Whether to refuse to start when the kernel already handles the numpad, unless `--force`
+    /// is passed. Otherwise that's only warned about.
+    pub(crate) fn refuse_with_native_numpad(&self) -> bool {
+        self.refuse_with_native_numpad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_paths() {
+        let paths = Config::search_paths(Some("/xdg".into()), Some("/home/user".into()));
+        assert_eq!(
+            paths,
+            [
+                "/xdg/asus-numpad/config.toml",
+                "/home/user/.config/asus-numpad/config.toml",
+                "/etc/asus-numpad/config.toml",
+                "/etc/xdg/asus_numpad.toml",
+                "/home/user/.config/asus_numpad.toml",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+        // empty vars are treated like unset ones
+        let paths = Config::search_paths(Some("".into()), None);
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("/etc/asus-numpad/config.toml"),
+                PathBuf::from("/etc/xdg/asus_numpad.toml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_explicit_path() {
+        let path =
+            std::env::temp_dir().join(format!("asus-numpad-config-{}.toml", std::process::id()));
+        // a missing or malformed config that was asked for isn't replaced by the defaults
+        assert!(Config::load(Some(&path)).is_err());
+        std::fs::write(&path, "hold_duration_ms = \"long\"").unwrap();
+        assert!(Config::load(Some(&path)).is_err());
+
+        std::fs::write(&path, "hold_duration_ms = 500").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.hold_duration(), CustomDuration::from_millis(500));
+        assert_eq!(config.path(), Some(path.as_path()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_brightness_fade_clamped() {
+        let mut config: Config = toml::from_str("brightness_fade_ms = 2000").unwrap();
+        config.validate();
+        assert_eq!(config.brightness_fade(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_night_schedule() {
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert!("7".parse::<TimeOfDay>().is_err());
+        let time = |s: &str| s.parse::<TimeOfDay>().unwrap();
+        assert_eq!(time("07:05").minutes, 425);
+        let night = NightSchedule {
+            start: time("22:00"),
+            end: time("07:00"),
+            brightness: Brightness::Low,
+        };
+        assert!(night.contains(time("22:00")));
+        assert!(night.contains(time("00:00")));
+        assert!(!night.contains(time("07:00")));
+        assert!(!night.contains(time("12:00")));
+        let afternoon = NightSchedule {
+            start: time("13:00"),
+            end: time("14:00"),
+            ..night
+        };
+        assert!(afternoon.contains(time("13:30")));
+        assert!(!afternoon.contains(time("22:00")));
+    }
+
+    #[test]
+    fn test_with_profile() {
+        let config = Config::parse(
+            br#"
+            hold_duration_ms = 500
+            auto_disable_secs = 60
+
+            [profiles.gaming]
+            auto_disable_secs = 0
+            default_brightness = "Full"
+            "#,
+        )
+        .unwrap();
+        let gaming = config.with_profile(Some("gaming")).unwrap();
+        assert_eq!(gaming.profile(), Some("gaming"));
+        assert_eq!(gaming.hold_duration_ms, 500);
+        assert_eq!(gaming.auto_disable_secs, Some(0));
+        assert_eq!(gaming.default_brightness, Brightness::Full);
+
+        // switching back starts from the file's options again
+        let base = gaming.with_profile(None).unwrap();
+        assert_eq!(base.profile(), None);
+        assert_eq!(base.auto_disable_secs, Some(60));
+
+        assert!(config.with_profile(Some("work")).is_err());
+    }
 }
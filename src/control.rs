@@ -0,0 +1,228 @@
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error, Result};
+use log::{debug, warn};
+
+use crate::touchpad_i2c::Brightness;
+
+/// Commands accepted on the control socket, one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ControlCommand {
+    /// Toggle the numpad
+    Toggle,
+    /// Turn the numpad on, if it is off
+    On,
+    /// Turn the numpad off, if it is on
+    Off,
+    /// Set the brightness of the numpad LED
+    Brightness(Brightness),
+    /// Flash the LED this many times
+    Notify(u8),
+    /// Switch to the config's profile with this name, or back to no profile
+    Profile(Option<String>),
+    /// Only report the current state
+    Status,
+    /// Report the current state as JSON
+    StatusJson,
+}
+
+impl ControlCommand {
+    /// Pulses of `notify` without an argument.
+    const DEFAULT_PULSES: u8 = 2;
+    /// Max pulses of `notify`, so that a notification is over in a few seconds.
+    pub(crate) const MAX_PULSES: u8 = 10;
+}
+
+impl FromStr for ControlCommand {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let cmd = match (words.next(), words.next()) {
+            (Some("toggle"), None) => Self::Toggle,
+            (Some("on"), None) => Self::On,
+            (Some("off"), None) => Self::Off,
+            (Some("status"), None) => Self::Status,
+            (Some("status"), Some("json")) => Self::StatusJson,
+            (Some("notify"), None) => Self::Notify(Self::DEFAULT_PULSES),
+            (Some("profile"), name) => Self::Profile(name.map(str::to_owned)),
+            (Some("notify"), Some(pulses)) => match pulses.parse() {
+                Ok(pulses) if (1..=Self::MAX_PULSES).contains(&pulses) => Self::Notify(pulses),
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid number of pulses {}, expected 1 to {}",
+                        pulses,
+                        Self::MAX_PULSES
+                    ))
+                }
+            },
+            (Some("brightness"), Some(level)) => Self::Brightness(match level.parse::<u8>() {
+                Ok(raw) => Brightness::try_from(raw)?,
+                Err(_) => level.parse()?,
+            }),
+            _ => return Err(anyhow!("Unknown command {}", s.trim())),
+        };
+        if words.next().is_some() {
+            return Err(anyhow!("Too many arguments in {}", s.trim()));
+        }
+        Ok(cmd)
+    }
+}
+
+/// Snapshot of the current state, reported to control clients.
+#[derive(Debug)]
+pub(crate) struct Status<'a> {
+    pub(crate) numlock: bool,
+    pub(crate) brightness: Brightness,
+    pub(crate) layout: &'a str,
+    pub(crate) touchpad: &'a str,
+    pub(crate) i2c_id: u32,
+    /// Number of failed I2C writes since startup
+    pub(crate) i2c_errors: u64,
+    /// Index of the active keymap, `0` being the layout's own keys
+    pub(crate) layer: usize,
+    /// Number of numlock toggles since startup
+    pub(crate) toggles: u64,
+    /// Number of numpad keys pressed since startup
+    pub(crate) keys_pressed: u64,
+    /// Name of the active config profile, if any
+    pub(crate) profile: Option<&'a str>,
+}
+
+impl Status<'_> {
+    /// Short summary with only numlock and brightness.
+    pub(crate) fn to_line(&self) -> String {
+        format!(
+            "numlock={} brightness={}",
+            if self.numlock { "on" } else { "off" },
+            self.brightness
+        )
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        format!(
+            "{{\"numlock\":{},\"brightness\":\"{}\",\"layout\":{},\"touchpad\":{},\"i2c_id\":{},\"i2c_errors\":{},\"layer\":{},\"toggles\":{},\"keys_pressed\":{},\"profile\":{}}}",
+            self.numlock,
+            self.brightness,
+            json_string(self.layout),
+            json_string(self.touchpad),
+            self.i2c_id,
+            self.i2c_errors,
+            self.layer,
+            self.toggles,
+            self.keys_pressed,
+            self.profile.map_or_else(|| "null".to_owned(), json_string)
+        )
+    }
+}
+
+/// Quote and escape the string for use in JSON.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Ask the daemon listening on the socket at `path` for its status, as JSON.
+pub(crate) fn query_status(path: &Path) -> Result<String> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Unable to connect to control socket at {}", path.display()))?;
+    stream.write_all(b"status json\n")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response.trim_end().to_owned())
+}
+
+/// A Unix socket that accepts [`ControlCommand`]s from other processes.
+pub(crate) struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl std::fmt::Debug for ControlSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("ControlSocket: {}", self.path.display()))
+    }
+}
+
+impl ControlSocket {
+    /// Max time to wait for a client to send a command, so that the event loop isn't blocked.
+    const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Max time to wait for a client to read a response, for the same reason.
+    const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+    pub(crate) fn bind(path: &Path) -> Result<Self> {
+        // remove the socket left behind by a previous run
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                warn!("Unable to remove old socket {}: {}", path.display(), err);
+            }
+            _ => (),
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Unable to bind control socket at {}", path.display()))?;
+        listener
+            .set_nonblocking(true)
+            .context("Unable to make control socket non-blocking")?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Accept a pending connection, if any.
+    pub(crate) fn accept(&self) -> Option<UnixStream> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                // the accepted stream doesn't inherit non-blocking mode,
+                // so just make sure that a slow client can't hang us.
+                if let Err(err) = stream
+                    .set_read_timeout(Some(Self::READ_TIMEOUT))
+                    .and_then(|()| stream.set_write_timeout(Some(Self::WRITE_TIMEOUT)))
+                {
+                    warn!("Unable to set timeout on control client: {}", err);
+                    return None;
+                }
+                Some(stream)
+            }
+            Err(err) => {
+                if err.kind() != ErrorKind::WouldBlock {
+                    warn!("Unable to accept control client: {}", err);
+                }
+                None
+            }
+        }
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            debug!("Unable to remove socket {}: {}", self.path.display(), err);
+        }
+    }
+}
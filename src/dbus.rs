@@ -0,0 +1,247 @@
+//! A D-Bus service, serving [`NAME`] so that the numpad can be controlled from desktop shortcuts
+//! and applets.
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use zbus::blocking::{connection, Connection, MessageIterator};
+use zbus::{fdo, interface};
+
+use crate::config::DbusBus;
+use crate::control::ControlCommand;
+use crate::touchpad_i2c::Brightness;
+
+/// Bus name of the service, also used as its interface name.
+const NAME: &str = "org.asusnumpad.Control";
+/// Object path of the service.
+const PATH: &str = "/org/asusnumpad/Control";
+
+/// How long a method call waits for the main loop to run its command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to try to get back on the bus after losing the connection to it.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The command of a method call, to be run by the main loop.
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) cmd: ControlCommand,
+    /// Where the method call waits for the result, with the error as text
+    pub(crate) reply: Sender<Result<(), String>>,
+}
+
+/// The service, answering method calls from the threads of its connection.
+///
+/// Method calls are turned into [`Request`]s, which the main loop takes with
+/// [`requests`](Self::requests) once the fd becomes readable. The calls are answered only once
+/// the main loop replied, so that callers learn about failed commands.
+#[derive(Debug)]
+pub(crate) struct DbusService {
+    requests: Receiver<Request>,
+    /// Woken up by the method calls for every request
+    wake: UnixStream,
+}
+
+impl DbusService {
+    /// Connect to the bus and take the service's name, then keep serving it from a new thread.
+    pub(crate) fn start(bus: DbusBus) -> Result<Self> {
+        let (wake, wake_service) =
+            UnixStream::pair().context("Unable to create D-Bus wake pipe")?;
+        wake.set_nonblocking(true)?;
+        wake_service.set_nonblocking(true)?;
+        let (sender, requests) = mpsc::channel();
+        let control = Control {
+            requests: sender,
+            wake: wake_service,
+        };
+        let conn = connect(bus, &control)
+            .with_context(|| format!("Unable to serve {} on the D-Bus {} bus", NAME, bus))?;
+        info!("Serving {} on the D-Bus {} bus", NAME, bus);
+        std::thread::Builder::new()
+            .name("dbus".to_owned())
+            .spawn(move || keep_serving(bus, conn, &control))
+            .context("Unable to start D-Bus thread")?;
+        Ok(Self { requests, wake })
+    }
+
+    /// Take the requests received since the last call.
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        let mut buf = [0; 64];
+        while matches!((&self.wake).read(&mut buf), Ok(n) if n > 0) {}
+        self.requests.try_iter().collect()
+    }
+}
+
+impl AsRawFd for DbusService {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+}
+
+/// Connect to the bus, serving a copy of `control`.
+fn connect(bus: DbusBus, control: &Control) -> zbus::Result<Connection> {
+    let builder = match bus {
+        DbusBus::System => connection::Builder::system()?,
+        DbusBus::Session => connection::Builder::session()?,
+    };
+    builder
+        .name(NAME)?
+        .serve_at(PATH, control.try_clone()?)?
+        .build()
+}
+
+/// Watch the connection, and make a new one whenever it's lost, like when the bus restarts.
+fn keep_serving(bus: DbusBus, mut conn: Connection, control: &Control) {
+    loop {
+        // the calls are answered by the connection's own threads, this only sees them go by
+        for msg in MessageIterator::from(&conn) {
+            if let Err(err) = msg {
+                debug!("D-Bus connection failed: {}", err);
+                break;
+            }
+        }
+        warn!("Lost the D-Bus {} bus, reconnecting", bus);
+        conn = loop {
+            std::thread::sleep(RECONNECT_INTERVAL);
+            match connect(bus, control) {
+                Ok(conn) => break conn,
+                Err(err) => debug!("Unable to reconnect to the D-Bus {} bus: {}", bus, err),
+            }
+        };
+        info!("Serving {} on the D-Bus {} bus again", NAME, bus);
+    }
+}
+
+/// The object at [`PATH`].
+struct Control {
+    requests: Sender<Request>,
+    /// The other end of [`DbusService::wake`]
+    wake: UnixStream,
+}
+
+impl Control {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            requests: self.requests.clone(),
+            wake: self.wake.try_clone()?,
+        })
+    }
+
+    /// Have the main loop run the command, and wait for the result.
+    fn run(&self, cmd: ControlCommand) -> fdo::Result<()> {
+        debug!("D-Bus command {:?}", cmd);
+        let (reply, result) = mpsc::channel();
+        let stopped = || fdo::Error::Failed("The numpad is shutting down".to_owned());
+        self.requests
+            .send(Request { cmd, reply })
+            .map_err(|_| stopped())?;
+        if let Err(err) = (&self.wake).write(&[1]) {
+            // a full pipe already wakes up the main loop
+            if err.kind() != ErrorKind::WouldBlock {
+                return Err(fdo::Error::IOError(err.to_string()));
+            }
+        }
+        match result.recv_timeout(REPLY_TIMEOUT) {
+            Ok(result) => result.map_err(fdo::Error::Failed),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(fdo::Error::TimedOut(
+                "The numpad didn't run the command in time".to_owned(),
+            )),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(stopped()),
+        }
+    }
+}
+
+#[interface(name = "org.asusnumpad.Control")]
+impl Control {
+    /// Toggle the numpad.
+    fn toggle(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::Toggle)
+    }
+
+    /// Turn the numpad on, if it is off.
+    fn enable(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::On)
+    }
+
+    /// Turn the numpad off, if it is on.
+    fn disable(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::Off)
+    }
+
+    /// Set the LED brightness, to a level name like `half` or a percentage like `50%`.
+    fn set_brightness(&self, brightness: &str) -> fdo::Result<()> {
+        let brightness = parse_brightness(brightness).map_err(fdo::Error::InvalidArgs)?;
+        self.run(ControlCommand::Brightness(brightness))
+    }
+
+    /// Flash the LED this many times, and then restore its brightness.
+    fn notify(&self, pulses: u8) -> fdo::Result<()> {
+        if !(1..=ControlCommand::MAX_PULSES).contains(&pulses) {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Invalid number of pulses {}, expected 1 to {}",
+                pulses,
+                ControlCommand::MAX_PULSES
+            )));
+        }
+        self.run(ControlCommand::Notify(pulses))
+    }
+}
+
+/// Parse the argument of `SetBrightness`, see [`Brightness::from_percent`] for percentages.
+fn parse_brightness(brightness: &str) -> Result<Brightness, String> {
+    match brightness.strip_suffix('%') {
+        Some(percent) => percent
+            .parse()
+            .map(Brightness::from_percent)
+            .map_err(|_| format!("Invalid brightness percentage {}", brightness)),
+        None => brightness.parse().map_err(|err| format!("{}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_brightness() {
+        assert_eq!(parse_brightness("half"), Ok(Brightness::Half));
+        assert_eq!(parse_brightness("Off"), Ok(Brightness::Zero));
+        assert_eq!(parse_brightness("100%"), Ok(Brightness::Full));
+        assert_eq!(parse_brightness("40%"), Ok(Brightness::Low));
+        assert!(parse_brightness("24").is_err());
+        assert!(parse_brightness("300%").is_err());
+        assert!(parse_brightness("%").is_err());
+        assert!(parse_brightness("brightest").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let (wake, wake_service) = UnixStream::pair().unwrap();
+        wake.set_nonblocking(true).unwrap();
+        wake_service.set_nonblocking(true).unwrap();
+        let (sender, requests) = mpsc::channel();
+        let service = DbusService { requests, wake };
+        let control = Control {
+            requests: sender,
+            wake: wake_service,
+        };
+        let call = std::thread::spawn(move || control.run(ControlCommand::Toggle));
+        // the call waits for the main loop's result
+        let request = loop {
+            if let Some(request) = service.requests().pop() {
+                break request;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(request.cmd, ControlCommand::Toggle);
+        request.reply.send(Err("Couldn't".to_owned())).unwrap();
+        assert_eq!(
+            call.join().unwrap(),
+            Err(fdo::Error::Failed("Couldn't".to_owned()))
+        );
+    }
+}
@@ -1,10 +1,13 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use evdev_rs::{
-    enums::{EventCode, EV_ABS},
+    enums::{EventCode, EV_ABS, EV_KEY},
     Device, DeviceWrapper,
 };
-use std::{fs::OpenOptions, os::unix::prelude::OpenOptionsExt};
+use log::warn;
+use std::{fs::OpenOptions, os::unix::prelude::OpenOptionsExt, path::Path};
 
+use crate::config::TouchpadRange;
+use crate::error::NumpadError;
 use crate::numpad_layout::BBox;
 
 fn parse_id(line: &str, search_str: &str) -> Result<u32> {
@@ -12,97 +15,283 @@ fn parse_id(line: &str, search_str: &str) -> Result<u32> {
         .find(search_str)
         .ok_or_else(|| anyhow!("Can't find token {} in {}", search_str, line))?;
     let start_idx = pos + search_str.len();
-    let mut chars = line.chars();
-    // we know chars is at least as long as start_idx
-    unsafe { chars.advance_by(start_idx).unwrap_unchecked() };
-    let end_idx = start_idx
-        + chars
-            .position(|c| !c.is_numeric())
-            .ok_or(anyhow!("Reached end of line"))?;
+    let digits_len = line[start_idx..]
+        .chars()
+        .position(|c| !c.is_numeric())
+        .unwrap_or(line.len() - start_idx);
+    let end_idx = start_idx + digits_len;
     let digits = line[start_idx..end_idx].parse();
     digits.context("Could not parse u32 ID")
 }
 
-/// Parse `/proc/bus/input/devices` to find the keyboard and touchpad devices.
-/// Returns the evdev handles for keybard and touchpad, along with I2C ID of touchpad.
-pub(crate) fn read_proc_input() -> Result<(u32, u32, u32)> {
-    #[derive(Debug, PartialEq, Eq)]
-    enum Detection {
-        NotDetected,
-        Parsing,
-        Finished,
+/// Vendor IDs that the touchpads of ASUS laptops report, which are the ones of the touchpad
+/// makers (like `04f3` of ELAN) rather than of ASUS.
+const TOUCHPAD_VENDOR_IDS: [u32; 2] = [0x04f3, 0x2808];
+
+/// Buses of external devices, `BUS_USB` and `BUS_BLUETOOTH` of `linux/input.h`.
+const EXTERNAL_BUSES: [u32; 2] = [0x03, 0x05];
+
+/// An input device, as listed in `/proc/bus/input/devices`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ProcDevice {
+    pub(crate) name: String,
+    pub(crate) bus: Option<u32>,
+    pub(crate) vendor: Option<u32>,
+    /// Names of the handlers, like `mouse2 event14`
+    pub(crate) handlers: String,
+    /// The `N` of `/dev/input/eventN`
+    pub(crate) event_id: Option<u32>,
+    /// The `N` of `/dev/i2c-N`, for devices on the I2C bus
+    pub(crate) i2c_id: Option<u32>,
+    /// Bitmask of the supported absolute axes, least significant word first
+    abs: Vec<u64>,
+    /// Bitmask of the supported keys, least significant word first
+    keys: Vec<u64>,
+}
+
+/// Whether the bit is set in a bitmask of `/proc/bus/input/devices`.
+fn has_bit(mask: &[u64], bit: usize) -> bool {
+    mask.get(bit / 64)
+        .map_or(false, |word| (word >> (bit % 64)) & 1 == 1)
+}
+
+/// Parse a bitmask of `/proc/bus/input/devices`, which is printed most significant word first.
+fn parse_mask(mask: &str) -> Vec<u64> {
+    mask.split_whitespace()
+        .rev()
+        .map(|word| u64::from_str_radix(word, 16).unwrap_or(0))
+        .collect()
+}
+
+impl ProcDevice {
+    fn has_abs(&self, abs: EV_ABS) -> bool {
+        has_bit(&self.abs, abs as usize)
     }
-    let mut touchpad_detection = Detection::NotDetected;
-    let mut keyboard_detection = Detection::NotDetected;
 
-    let mut touchpad_i2c_id: Option<u32> = None;
-    let mut touchpad_ev_id: Option<u32> = None;
-    let mut keyboard_ev_id: Option<u32> = None;
+    fn has_key(&self, key: EV_KEY) -> bool {
+        has_bit(&self.keys, key as usize)
+    }
 
-    let data = std::fs::read_to_string("/proc/bus/input/devices")
-        .context("Could not read devices file")?;
+    /// Whether the device reports multitouch positions, like touchpads and touchscreens do.
+    pub(crate) fn is_multitouch(&self) -> bool {
+        self.has_abs(EV_ABS::ABS_MT_POSITION_X)
+    }
 
-    for line in data.lines() {
-        match touchpad_detection {
-            Detection::NotDetected => {
-                if (line.contains("Name=\"ASUE") || line.contains("Name=\"ELAN"))
-                    && line.contains("Touchpad")
-                {
-                    touchpad_detection = Detection::Parsing;
-                    continue;
-                }
-            }
-            Detection::Parsing => {
-                if line.starts_with("S:") {
-                    touchpad_i2c_id = Some(parse_id(line, "i2c-")?);
-                    continue;
-                } else if line.starts_with("H:") {
-                    touchpad_ev_id = Some(parse_id(line, "event")?);
-                    continue;
-                } else if line.is_empty() {
-                    // reset since we reached end of device info
-                    touchpad_detection = Detection::NotDetected;
-                }
-                if touchpad_i2c_id.is_some() && touchpad_ev_id.is_some() {
-                    touchpad_detection = Detection::Finished;
-                }
+    fn is_known_touchpad(&self) -> bool {
+        (self.name.contains("ASUE") || self.name.contains("ELAN")) && self.name.contains("Touchpad")
+    }
+
+    /// Whether the device is a mouse, or another pointing device, plugged into the laptop.
+    ///
+    /// Built-in devices aren't counted, even those that look like a mouse, like the extra mouse
+    /// device that some touchpads have. Neither are virtual ones, like our own.
+    fn is_external_mouse(&self) -> bool {
+        self.bus.map_or(false, |bus| EXTERNAL_BUSES.contains(&bus))
+            && !self.is_multitouch()
+            && self
+                .handlers
+                .split_whitespace()
+                .any(|handler| handler.starts_with("mouse"))
+    }
+
+    /// Whether the kernel toggles the numpad on its own through this device.
+    ///
+    /// Newer kernels report the numpad toggle as `KEY_NUMLOCK`, either from the ASUS WMI hotkeys
+    /// device or from the touchpad itself. Regular keyboards, which have a numlock key of their
+    /// own, aren't counted.
+    fn handles_numpad(&self, touchpad: &ProcDevice) -> bool {
+        (self.name.contains("Asus WMI") || self == touchpad) && self.has_key(EV_KEY::KEY_NUMLOCK)
+    }
+
+    fn is_keyboard(&self) -> bool {
+        self.name.contains("AT Translated Set 2 keyboard")
+            || (self.name.contains("ASUE") && self.name.contains("Keyboard"))
+    }
+}
+
+/// Parse the contents of `/proc/bus/input/devices`.
+fn parse_proc_devices(data: &str) -> Vec<ProcDevice> {
+    let mut devices = Vec::new();
+    let mut cur = ProcDevice::default();
+    for line in data.lines().chain(std::iter::once("")) {
+        if line.is_empty() {
+            // reached end of device info
+            if !cur.name.is_empty() {
+                devices.push(std::mem::take(&mut cur));
             }
-            _ => (),
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("N: Name=") {
+            cur.name = rest.trim_matches('"').to_owned();
+        } else if line.starts_with("I:") {
+            let field = |name: &str| {
+                line.split_whitespace()
+                    .find_map(|field| field.strip_prefix(name))
+                    .and_then(|value| u32::from_str_radix(value, 16).ok())
+            };
+            cur.bus = field("Bus=");
+            cur.vendor = field("Vendor=");
+        } else if line.starts_with("S:") {
+            cur.i2c_id = parse_id(line, "i2c-").ok();
+        } else if let Some(handlers) = line.strip_prefix("H: Handlers=") {
+            cur.handlers = handlers.trim().to_owned();
+            cur.event_id = parse_id(line, "event").ok();
+        } else if let Some(mask) = line.strip_prefix("B: ABS=") {
+            cur.abs = parse_mask(mask);
+        } else if let Some(mask) = line.strip_prefix("B: KEY=") {
+            cur.keys = parse_mask(mask);
         }
+    }
+    devices
+}
 
-        match keyboard_detection {
-            Detection::NotDetected => {
-                if line.contains("Name=\"AT Translated Set 2 keyboard")
-                    || (line.contains("Name=\"ASUE") && line.contains("Keyboard"))
-                {
-                    keyboard_detection = Detection::Parsing;
-                    continue;
-                }
-            }
-            Detection::Parsing => {
-                if line.starts_with("H:") {
-                    keyboard_ev_id = Some(parse_id(line, "event")?);
-                    // TODO: We should verify that the device actually supports KEY_NUMLOCK using evdev
-                    keyboard_detection = Detection::Finished;
-                    continue;
-                } else if line.is_empty() {
-                    // reset since we reached end of device info
-                    keyboard_detection = Detection::NotDetected;
-                }
-            }
-            _ => (),
+/// Find the touchpad among the devices, returning its evdev ID along with it.
+///
+/// If `name` is given, the touchpad is the first device whose name contains it. Otherwise, known
+/// touchpad names are looked for, falling back to any multitouch device on I2C with one of the
+/// [`TOUCHPAD_VENDOR_IDS`].
+fn find_touchpad<'a>(
+    devices: &'a [ProcDevice],
+    name: Option<&str>,
+) -> Option<(u32, &'a ProcDevice)> {
+    let with_evdev = || {
+        devices
+            .iter()
+            .filter_map(|dev| dev.event_id.map(|event_id| (event_id, dev)))
+    };
+    match name {
+        Some(name) => with_evdev().find(|(_, dev)| dev.name.contains(name)),
+        None => with_evdev()
+            .find(|(_, dev)| dev.is_known_touchpad())
+            .or_else(|| {
+                with_evdev().find(|(_, dev)| {
+                    dev.vendor
+                        .map_or(false, |vendor| TOUCHPAD_VENDOR_IDS.contains(&vendor))
+                        && dev.is_multitouch()
+                        && dev.i2c_id.is_some()
+                })
+            }),
+    }
+}
+
+/// Read all the input devices from `/proc/bus/input/devices`.
+pub(crate) fn list_proc_devices() -> Result<Vec<ProcDevice>> {
+    let data = std::fs::read_to_string("/proc/bus/input/devices")
+        .context("Could not read devices file")?;
+    Ok(parse_proc_devices(&data))
+}
+
+/// Print all the input devices, marking the ones that would be used as touchpad and keyboard.
+pub(crate) fn print_devices(touchpad_name: Option<&str>) -> Result<()> {
+    let devices = list_proc_devices()?;
+    let touchpad = find_touchpad(&devices, touchpad_name).map(|(_, dev)| dev);
+    let keyboard = devices
+        .iter()
+        .find(|dev| dev.is_keyboard() && dev.event_id.is_some());
+    for dev in &devices {
+        let role = if Some(dev) == touchpad {
+            " [touchpad]"
+        } else if Some(dev) == keyboard {
+            " [keyboard]"
+        } else {
+            ""
+        };
+        println!("{}{}", dev.name, role);
+        println!("    Handlers: {}", dev.handlers);
+        if let Some(i2c_id) = dev.i2c_id {
+            println!("    I2C: /dev/i2c-{}", i2c_id);
+        }
+        if dev.is_multitouch() {
+            println!("    Multitouch: yes");
         }
-        if touchpad_detection == Detection::Finished && keyboard_detection == Detection::Finished {
-            break;
+        if touchpad.map_or(false, |touchpad| dev.handles_numpad(touchpad)) {
+            println!("    Handles the numpad: yes, asus-numpad would conflict with it");
         }
     }
+    if touchpad.is_none() {
+        println!("\nNo touchpad found. Set touchpad_name in the config to pick one of the above.");
+    }
+    Ok(())
+}
+
+/// Find an external mouse in `/proc/bus/input/devices`, returning its name.
+pub(crate) fn find_external_mouse() -> Result<Option<String>> {
+    Ok(list_proc_devices()?
+        .into_iter()
+        .find(ProcDevice::is_external_mouse)
+        .map(|dev| dev.name))
+}
+
+/// Find a device through which the kernel already handles the numpad, returning its name.
+///
+/// See [`find_touchpad`] for how `touchpad_name` is used.
+pub(crate) fn find_native_numpad(touchpad_name: Option<&str>) -> Result<Option<String>> {
+    let devices = list_proc_devices()?;
+    Ok(native_numpad(&devices, touchpad_name).map(|dev| dev.name.clone()))
+}
+
+fn native_numpad<'a>(
+    devices: &'a [ProcDevice],
+    touchpad_name: Option<&str>,
+) -> Option<&'a ProcDevice> {
+    let (_, touchpad) = find_touchpad(devices, touchpad_name)?;
+    devices.iter().find(|dev| dev.handles_numpad(touchpad))
+}
+
+/// Parse `/proc/bus/input/devices` to find the keyboard and touchpad devices.
+/// Returns the evdev handles for keybard and touchpad, along with I2C ID of touchpad.
+///
+/// See [`find_touchpad`] for how `touchpad_name` is used.
+pub(crate) fn read_proc_input(touchpad_name: Option<&str>) -> Result<(u32, u32, u32)> {
+    let devices = list_proc_devices()?;
+    // TODO: We should verify that the device actually supports KEY_NUMLOCK using evdev
+    let keyboard_ev_id = devices
+        .iter()
+        .filter(|dev| dev.is_keyboard())
+        .find_map(|dev| dev.event_id)
+        .ok_or(NumpadError::NoKeyboard)?;
+    let (touchpad_ev_id, touchpad) = find_touchpad(&devices, touchpad_name).ok_or_else(|| {
+        let candidates = devices
+            .iter()
+            .filter(|dev| dev.is_multitouch())
+            .map(|dev| dev.name.clone())
+            .collect();
+        NumpadError::NoTouchpad { candidates }
+    })?;
     Ok((
-        keyboard_ev_id.ok_or(anyhow!("Can't find keyboard evdev"))?,
-        touchpad_ev_id.ok_or(anyhow!("Can't find touchpad evdev"))?,
-        touchpad_i2c_id.ok_or(anyhow!("Can't find touchpad I2C ID"))?,
+        keyboard_ev_id,
+        touchpad_ev_id,
+        touchpad.i2c_id.ok_or(NumpadError::NoTouchpadI2CId)?,
     ))
 }
 
+/// Find the processes that have the file open, as `name (pid)`, apart from ourselves.
+///
+/// Only the processes whose `/proc/PID/fd` we're allowed to read are found.
+pub(crate) fn processes_using(path: &Path) -> Vec<String> {
+    let own_pid = std::process::id().to_string();
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_string_lossy().into_owned();
+        if pid == own_pid || !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let uses_path = std::fs::read_dir(entry.path().join("fd")).map_or(false, |fds| {
+            fds.flatten()
+                .any(|fd| std::fs::read_link(fd.path()).map_or(false, |link| link == path))
+        });
+        if uses_path {
+            let name = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            found.push(format!("{} ({})", name.trim(), pid));
+        }
+    }
+    found
+}
+
 pub(crate) fn open_input_evdev(evdev_id: u32) -> Result<Device> {
     let path = format!("/dev/input/event{}", evdev_id);
     let file = OpenOptions::new()
@@ -117,17 +306,190 @@ pub(crate) fn open_input_evdev(evdev_id: u32) -> Result<Device> {
         .context("Unable to open evdev device")
 }
 
-pub(crate) fn get_touchpad_bbox(touchpad_evdev: &Device) -> Result<BBox> {
-    let absx = touchpad_evdev
-        .abs_info(&EventCode::EV_ABS(EV_ABS::ABS_X))
-        .ok_or(anyhow!("Could not get touchpad max x"))?;
-    let absy = touchpad_evdev
-        .abs_info(&EventCode::EV_ABS(EV_ABS::ABS_Y))
-        .ok_or(anyhow!("Could not get touchpad max y"))?;
-    Ok(BBox::new(
-        absx.minimum,
-        absx.maximum,
-        absy.minimum,
-        absy.maximum,
-    ))
+/// Get the range of a multitouch axis, falling back to its single touch variant.
+///
+/// The positions are read from the `ABS_MT_*` axes, so their range is what the layout should be
+/// scaled to. Some touchpads report a different range for the single touch axes, which would
+/// offset the keys, so a warning is logged if they differ.
+fn get_axis_range(touchpad_evdev: &Device, mt_axis: EV_ABS, axis: EV_ABS) -> Result<(i32, i32)> {
+    let range = |axis| {
+        touchpad_evdev
+            .abs_info(&EventCode::EV_ABS(axis))
+            .map(|info| (info.minimum, info.maximum))
+    };
+    match (range(mt_axis), range(axis)) {
+        (Some(mt_range), Some(range)) => {
+            if mt_range != range {
+                warn!(
+                    "{:?} range {:?} differs from {:?} range {:?}, using the former",
+                    mt_axis, mt_range, axis, range
+                );
+            }
+            Ok(mt_range)
+        }
+        (Some(range), None) | (None, Some(range)) => Ok(range),
+        (None, None) => Err(anyhow!(
+            "Touchpad reports neither {:?} nor {:?}",
+            mt_axis,
+            axis
+        )),
+    }
+}
+
+/// Get the range of the axis, with the configured bounds replacing the reported ones.
+///
+/// The touchpad isn't queried if both bounds are configured, so this also works for touchpads
+/// that don't report the axis at all.
+fn get_axis_range_or_override(
+    touchpad_evdev: &Device,
+    mt_axis: EV_ABS,
+    axis: EV_ABS,
+    (min, max): (Option<i32>, Option<i32>),
+) -> Result<(i32, i32)> {
+    let range = match (min, max) {
+        (Some(min), Some(max)) => (min, max),
+        _ => {
+            let (reported_min, reported_max) = get_axis_range(touchpad_evdev, mt_axis, axis)?;
+            (min.unwrap_or(reported_min), max.unwrap_or(reported_max))
+        }
+    };
+    if min.is_some() || max.is_some() {
+        warn!(
+            "Using the configured {:?} range {:?} instead of the one reported by the touchpad",
+            mt_axis, range
+        );
+    }
+    if range.0 >= range.1 {
+        bail!(
+            "Invalid {:?} range {:?}, min must be less than max",
+            mt_axis,
+            range
+        );
+    }
+    Ok(range)
+}
+
+pub(crate) fn get_touchpad_bbox(touchpad_evdev: &Device, overrides: TouchpadRange) -> Result<BBox> {
+    let (minx, maxx) = get_axis_range_or_override(
+        touchpad_evdev,
+        EV_ABS::ABS_MT_POSITION_X,
+        EV_ABS::ABS_X,
+        (overrides.minx, overrides.maxx),
+    )
+    .context("Could not get touchpad x range")?;
+    let (miny, maxy) = get_axis_range_or_override(
+        touchpad_evdev,
+        EV_ABS::ABS_MT_POSITION_Y,
+        EV_ABS::ABS_Y,
+        (overrides.miny, overrides.maxy),
+    )
+    .context("Could not get touchpad y range")?;
+    Ok(BBox::new(minx, maxx, miny, maxy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROC_DEVICES: &str = r#"I: Bus=0011 Vendor=0001 Product=0001 Version=ab83
+N: Name="AT Translated Set 2 keyboard"
+P: Phys=isa0060/serio0/input0
+S: Sysfs=/devices/platform/i8042/serio0/input/input3
+U: Uniq=
+H: Handlers=sysrq kbd leds event3
+B: PROP=0
+B: EV=120013
+B: KEY=402000000 3803078f800d001 feffffdfffefffff fffffffffffffffe
+B: MSC=10
+B: LED=7
+
+I: Bus=0018 Vendor=2808 Product=0202 Version=0100
+N: Name="ASUF1204:00 2808:0202 Touchpad"
+P: Phys=i2c-ASUF1204:00
+S: Sysfs=/devices/pci0000:00/0000:00:15.1/i2c_designware.1/i2c-2/i2c-ASUF1204:00/0018:2808:0202.0002/input/input17
+U: Uniq=
+H: Handlers=mouse2 event14
+B: PROP=5
+B: EV=1b
+B: KEY=e520 10000 0 0 0 0
+B: ABS=2e0800000000003
+B: MSC=20
+"#;
+
+    #[test]
+    fn test_parse_proc_devices() {
+        let devices = parse_proc_devices(PROC_DEVICES);
+        assert_eq!(devices.len(), 2);
+        let (keyboard, touchpad) = (&devices[0], &devices[1]);
+        assert!(keyboard.is_keyboard());
+        assert_eq!(keyboard.event_id, Some(3));
+        assert_eq!(keyboard.i2c_id, None);
+        assert!(!keyboard.is_multitouch());
+
+        assert_eq!(touchpad.name, "ASUF1204:00 2808:0202 Touchpad");
+        assert_eq!(touchpad.vendor, Some(0x2808));
+        assert_eq!(touchpad.event_id, Some(14));
+        assert_eq!(touchpad.i2c_id, Some(2));
+        assert!(touchpad.is_multitouch());
+        assert!(!touchpad.is_external_mouse());
+    }
+
+    #[test]
+    fn test_find_touchpad() {
+        let devices = parse_proc_devices(PROC_DEVICES);
+        // not a known name, found through the vendor
+        assert_eq!(find_touchpad(&devices, None), Some((14, &devices[1])));
+        assert_eq!(
+            find_touchpad(&devices, Some("keyboard")),
+            Some((3, &devices[0]))
+        );
+        assert_eq!(find_touchpad(&devices, Some("Trackpoint")), None);
+    }
+
+    #[test]
+    fn test_is_external_mouse() {
+        let devices = parse_proc_devices(
+            r#"I: Bus=0003 Vendor=046d Product=c52b Version=0111
+N: Name="Logitech USB Receiver"
+H: Handlers=mouse3 event20
+B: EV=17
+
+I: Bus=0018 Vendor=2808 Product=0202 Version=0100
+N: Name="ASUF1204:00 2808:0202 Mouse"
+S: Sysfs=/devices/pci0000:00/0000:00:15.1/i2c_designware.1/i2c-2/i2c-ASUF1204:00
+H: Handlers=mouse1 event13
+B: EV=17
+
+I: Bus=0003 Vendor=046d Product=c52b Version=0111
+N: Name="Logitech USB Receiver Consumer Control"
+H: Handlers=kbd event22
+B: EV=1f
+"#,
+        );
+        assert_eq!(devices[0].bus, Some(3));
+        let mice: Vec<_> = devices.iter().map(ProcDevice::is_external_mouse).collect();
+        assert_eq!(mice, vec![true, false, false]);
+    }
+
+    #[test]
+    fn test_native_numpad() {
+        let devices = parse_proc_devices(PROC_DEVICES);
+        // the keyboard has a numlock key, but it's not the numpad's
+        assert!(devices[0].has_key(EV_KEY::KEY_NUMLOCK));
+        assert_eq!(native_numpad(&devices, None), None);
+
+        let wmi = r#"
+I: Bus=0019 Vendor=0000 Product=0000 Version=0000
+N: Name="Asus WMI hotkeys"
+H: Handlers=kbd event6
+B: EV=100013
+B: KEY=80000000000000 0 0 0 0 0 0 0 20 0
+"#;
+        let devices = parse_proc_devices(&format!("{}{}", PROC_DEVICES, wmi));
+        assert_eq!(native_numpad(&devices, None), Some(&devices[2]));
+        // without a numlock key, the WMI device doesn't toggle the numpad
+        let wmi = wmi.replace(" 20 0", " 0 0");
+        let devices = parse_proc_devices(&format!("{}{}", PROC_DEVICES, wmi));
+        assert_eq!(native_numpad(&devices, None), None);
+    }
 }
@@ -2,40 +2,92 @@ use std::io::ErrorKind::{NotFound, PermissionDenied};
 
 use anyhow::{Context, Error, Result};
 use evdev_rs::{
-    enums::{EventCode, EV_KEY, EV_SYN},
+    enums::{EventCode, EV_KEY, EV_MSC, EV_REL, EV_REP, EV_SYN},
     DeviceWrapper, InputEvent, TimeVal, UInputDevice, UninitDevice,
 };
-use log::trace;
+use log::{info, trace};
 
 use crate::numpad_layout::NumpadLayout;
 
 pub(crate) struct DummyKeyboard {
-    pub(crate) udev: UInputDevice,
+    /// `None` in dry run mode
+    pub(crate) udev: Option<UInputDevice>,
 }
 
 impl std::fmt::Debug for DummyKeyboard {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DummyKeyboard")
-            .field("udev", &self.udev.devnode())
+            .field("udev", &self.udev.as_ref().map(UInputDevice::devnode))
             .finish()
     }
 }
 
+/// Parameters for the kernel's software autorepeat of held keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyRepeat {
+    /// Delay before a held key starts repeating
+    pub(crate) delay_ms: i32,
+    /// Time between two repeats
+    pub(crate) period_ms: i32,
+}
+
+/// How the virtual keyboard shows up to other programs, so they can recognize it across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UinputIdentity<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) vendor_id: u16,
+    pub(crate) product_id: u16,
+}
+
 impl DummyKeyboard {
-    pub(crate) fn new(layout: &NumpadLayout) -> Result<Self> {
+    /// Create the virtual keyboard, able to send numlock, the layout's keys and `extra_keys`.
+    ///
+    /// With `pointer`, it can also move the pointer, like a mouse.
+    pub(crate) fn new(
+        layout: &NumpadLayout,
+        extra_keys: &[EV_KEY],
+        repeat: Option<KeyRepeat>,
+        identity: UinputIdentity,
+        pointer: bool,
+    ) -> Result<Self> {
         let dev = UninitDevice::new().context("Unable to create uninit evdev device.")?;
-        dev.set_name("asus_numpad");
-        let default_keys = [EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_NUMLOCK, EV_KEY::KEY_CALC];
-        for key in default_keys {
+        dev.set_name(identity.name);
+        dev.set_vendor_id(identity.vendor_id);
+        dev.set_product_id(identity.product_id);
+        dev.enable(&EventCode::EV_MSC(EV_MSC::MSC_SCAN))
+            .context("Unable to enable scancodes")?;
+        // uinput drops the keys that aren't enabled here
+        let keys = std::iter::once(EV_KEY::KEY_NUMLOCK)
+            .chain(layout.emitted_keys())
+            .chain(extra_keys.iter().copied());
+        for key in keys {
             dev.enable(&EventCode::EV_KEY(key))
                 .with_context(|| format!("Unable to enable key {:?}", key))?;
         }
-        for row in layout.keys().iter() {
-            for key in row {
-                dev.enable(&EventCode::EV_KEY(*key))
-                    .with_context(|| format!("Unable to enable key {:?}", key))?;
+        if pointer {
+            // udev only tags it as a mouse if it has a button too
+            for code in [
+                EventCode::EV_REL(EV_REL::REL_X),
+                EventCode::EV_REL(EV_REL::REL_Y),
+                EventCode::EV_KEY(EV_KEY::BTN_LEFT),
+            ] {
+                dev.enable(&code)
+                    .with_context(|| format!("Unable to enable {:?}", code))?;
             }
         }
+        if let Some(repeat) = repeat {
+            // enabling EV_REP makes the kernel repeat held keys for us
+            dev.enable_event_code(
+                &EventCode::EV_REP(EV_REP::REP_DELAY),
+                Some(&repeat.delay_ms),
+            )
+            .context("Unable to enable key repeat")?;
+            dev.enable_event_code(
+                &EventCode::EV_REP(EV_REP::REP_PERIOD),
+                Some(&repeat.period_ms),
+            )
+            .context("Unable to enable key repeat")?;
+        }
         let udev = UInputDevice::create_from_device(&dev).map_err(|err| {
             let mut context = "Unable to create dummy UInput device".to_string();
             let extra_context = match err.kind() {
@@ -50,22 +102,65 @@ impl DummyKeyboard {
             Error::new(err).context(context)
         })?;
 
-        Ok(Self { udev })
+        if let Some(repeat) = repeat {
+            Self::set_repeat(&udev, repeat)?;
+        }
+        info!(
+            "Created virtual keyboard {:?} ({:04x}:{:04x}) at {}",
+            identity.name,
+            identity.vendor_id,
+            identity.product_id,
+            udev.devnode().unwrap_or("unknown path")
+        );
+        Ok(Self { udev: Some(udev) })
+    }
+
+    /// Create a keyboard that only prints the keys instead of sending them.
+    pub(crate) fn dry_run() -> Self {
+        Self { udev: None }
+    }
+
+    /// Configure the kernel's autorepeat parameters for this device.
+    ///
+    /// uinput can't set these at creation, they need to be written as events instead.
+    fn set_repeat(udev: &UInputDevice, repeat: KeyRepeat) -> Result<()> {
+        for (code, value) in [
+            (EV_REP::REP_DELAY, repeat.delay_ms),
+            (EV_REP::REP_PERIOD, repeat.period_ms),
+        ] {
+            udev.write_event(&InputEvent::new(
+                &Self::DUMMY_TIMEVAL,
+                &EventCode::EV_REP(code),
+                value,
+            ))
+            .context("Unable to set key repeat")?;
+        }
+        udev.write_event(&InputEvent::new(
+            &Self::DUMMY_TIMEVAL,
+            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+            0,
+        ))
+        .context("Unable to set key repeat")?;
+        Ok(())
     }
 }
 
-pub(crate) trait KeyEvents {
-    fn keydown(&self, key: EV_KEY);
-    fn keyup(&self, key: EV_KEY);
-    fn multi_keydown(&self, keys: &[EV_KEY]);
-    fn multi_keyup(&self, keys: &[EV_KEY]);
+/// Something that key events can be sent to, like the [`DummyKeyboard`].
+pub(crate) trait KeyboardSink {
+    fn keydown(&mut self, key: EV_KEY);
+    fn keyup(&mut self, key: EV_KEY);
+    /// Press all the keys at once, like `Shift` and another key.
+    fn multi_keydown(&mut self, keys: &[EV_KEY]);
+    fn multi_keyup(&mut self, keys: &[EV_KEY]);
+    /// Move the pointer by this many pixels.
+    fn move_pointer(&mut self, dx: i32, dy: i32);
 
-    fn keypress(&self, key: EV_KEY) {
+    fn keypress(&mut self, key: EV_KEY) {
         trace!("Pressing {:?}", key);
         self.keydown(key);
         self.keyup(key);
     }
-    fn multi_keypress(&self, keys: &[EV_KEY]) {
+    fn multi_keypress(&mut self, keys: &[EV_KEY]) {
         trace!("Pressing {:?}", keys);
         self.multi_keydown(keys);
         self.multi_keyup(keys);
@@ -78,76 +173,100 @@ pub(crate) trait KeyEvents {
     };
 }
 
-impl KeyEvents for DummyKeyboard {
-    fn keydown(&self, key: EV_KEY) {
-        self.udev
-            .write_event(&InputEvent::new(
-                &Self::DUMMY_TIMEVAL,
-                &EventCode::EV_KEY(key),
-                Self::KEYDOWN,
-            ))
-            .expect("Couldn't send keydown");
-        self.udev
-            .write_event(&InputEvent::new(
-                &Self::DUMMY_TIMEVAL,
-                &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-                0,
-            ))
-            .expect("No syn");
+impl DummyKeyboard {
+    /// The events that press or release all the keys at once.
+    ///
+    /// Like real keyboards, each key gets its scancode and then its key event. We don't have
+    /// real scancodes, so the keycode is used as the scancode. A single `SYN_REPORT` follows all
+    /// the keys, so that readers never see a key without the modifiers pressed along with it.
+    fn key_events(keys: &[EV_KEY], value: i32) -> Vec<InputEvent> {
+        let event = |code, value| InputEvent::new(&Self::DUMMY_TIMEVAL, &code, value);
+        let mut events: Vec<_> = keys
+            .iter()
+            .flat_map(|&key| {
+                [
+                    event(EventCode::EV_MSC(EV_MSC::MSC_SCAN), key as i32),
+                    event(EventCode::EV_KEY(key), value),
+                ]
+            })
+            .collect();
+        events.push(event(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0));
+        events
     }
 
-    fn keyup(&self, key: EV_KEY) {
-        self.udev
-            .write_event(&InputEvent::new(
-                &Self::DUMMY_TIMEVAL,
-                &EventCode::EV_KEY(key),
-                Self::KEYUP,
-            ))
-            .expect("Couldn't send keyup");
-        self.udev
-            .write_event(&InputEvent::new(
-                &Self::DUMMY_TIMEVAL,
-                &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-                0,
-            ))
-            .expect("No syn");
+    fn write_keys(&self, keys: &[EV_KEY], value: i32) {
+        let udev = match &self.udev {
+            Some(udev) => udev,
+            None => {
+                let action = if value == Self::KEYDOWN { "down" } else { "up" };
+                println!("Dry run: keys {:?} {}", keys, action);
+                return;
+            }
+        };
+        for ev in Self::key_events(keys, value) {
+            udev.write_event(&ev).expect("Couldn't send key event");
+        }
     }
+}
 
-    fn multi_keydown(&self, keys: &[EV_KEY]) {
-        for key in keys {
-            self.udev
-                .write_event(&InputEvent::new(
-                    &Self::DUMMY_TIMEVAL,
-                    &EventCode::EV_KEY(*key),
-                    Self::KEYDOWN,
-                ))
-                .expect("Couldn't send keydown");
-        }
-        self.udev
-            .write_event(&InputEvent::new(
-                &Self::DUMMY_TIMEVAL,
-                &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-                0,
-            ))
-            .expect("No syn");
+impl KeyboardSink for DummyKeyboard {
+    fn keydown(&mut self, key: EV_KEY) {
+        self.write_keys(&[key], Self::KEYDOWN);
     }
 
-    fn multi_keyup(&self, keys: &[EV_KEY]) {
-        for key in keys {
-            self.udev
-                .write_event(&InputEvent::new(
-                    &Self::DUMMY_TIMEVAL,
-                    &EventCode::EV_KEY(*key),
-                    Self::KEYUP,
-                ))
-                .expect("Couldn't send keyup");
+    fn keyup(&mut self, key: EV_KEY) {
+        self.write_keys(&[key], Self::KEYUP);
+    }
+
+    fn multi_keydown(&mut self, keys: &[EV_KEY]) {
+        self.write_keys(keys, Self::KEYDOWN);
+    }
+
+    /// Release the keys in the reverse order, so that the modifiers are released last.
+    fn multi_keyup(&mut self, keys: &[EV_KEY]) {
+        let reversed: Vec<_> = keys.iter().rev().copied().collect();
+        self.write_keys(&reversed, Self::KEYUP);
+    }
+
+    fn move_pointer(&mut self, dx: i32, dy: i32) {
+        let udev = match &self.udev {
+            Some(udev) => udev,
+            None => {
+                trace!("Dry run: pointer moved by {}, {}", dx, dy);
+                return;
+            }
+        };
+        for (code, value) in [
+            (EventCode::EV_REL(EV_REL::REL_X), dx),
+            (EventCode::EV_REL(EV_REL::REL_Y), dy),
+            (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ] {
+            udev.write_event(&InputEvent::new(&Self::DUMMY_TIMEVAL, &code, value))
+                .expect("Couldn't send pointer event");
         }
-        self.udev
-            .write_event(&InputEvent::new(
-                &Self::DUMMY_TIMEVAL,
-                &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-                0,
-            ))
-            .expect("No syn");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_events_sync_once() {
+        let events = DummyKeyboard::key_events(&[EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_5], 1);
+        let codes: Vec<_> = events.iter().map(|ev| (ev.event_code, ev.value)).collect();
+        assert_eq!(
+            codes,
+            [
+                (
+                    EventCode::EV_MSC(EV_MSC::MSC_SCAN),
+                    EV_KEY::KEY_LEFTSHIFT as i32
+                ),
+                (EventCode::EV_KEY(EV_KEY::KEY_LEFTSHIFT), 1),
+                (EventCode::EV_MSC(EV_MSC::MSC_SCAN), EV_KEY::KEY_5 as i32),
+                (EventCode::EV_KEY(EV_KEY::KEY_5), 1),
+                (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+            ]
+        );
     }
 }
@@ -0,0 +1,56 @@
+use std::fmt::Display;
+
+/// Common errors encountered while starting up, with hints on how to fix them.
+#[derive(Debug)]
+pub(crate) enum NumpadError {
+    /// No touchpad was found in `/proc/bus/input/devices`.
+    NoTouchpad {
+        /// Names of the multitouch devices that were found instead
+        candidates: Vec<String>,
+    },
+    /// No keyboard was found in `/proc/bus/input/devices`.
+    NoKeyboard,
+    /// The touchpad was found, but not its I2C ID.
+    NoTouchpadI2CId,
+    /// The touchpad I2C device could not be opened.
+    I2cUnavailable { i2c_id: u32, hint: &'static str },
+    /// The kernel already handles the numpad, through the named device.
+    NativeNumpad { device: String },
+}
+
+impl Display for NumpadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumpadError::NoTouchpad { candidates } => {
+                f.write_str("Can't find touchpad evdev")?;
+                if !candidates.is_empty() {
+                    write!(
+                        f,
+                        ". Found these touch devices: \"{}\". \
+                        Set touchpad_name in the config to use one of them",
+                        candidates.join("\", \"")
+                    )?;
+                }
+                Ok(())
+            }
+            NumpadError::NoKeyboard => f.write_str("Can't find keyboard evdev"),
+            NumpadError::NoTouchpadI2CId => f.write_str("Can't find touchpad I2C ID"),
+            NumpadError::I2cUnavailable { i2c_id, hint } => {
+                write!(f, "Unable to open Touchpad I2C at /dev/i2c-{}", i2c_id)?;
+                if !hint.is_empty() {
+                    write!(f, ". {}", hint)?;
+                }
+                Ok(())
+            }
+            NumpadError::NativeNumpad { device } => write!(
+                f,
+                "The kernel already handles the numpad through \"{}\", and running along with it \
+                causes double toggles. Pass --force to start anyway, or disable \
+                refuse_with_native_numpad in the config",
+                device
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NumpadError {}
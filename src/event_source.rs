@@ -0,0 +1,58 @@
+use std::io;
+use std::os::raw::c_ulong;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use evdev_rs::{Device, GrabMode, InputEvent, ReadFlag};
+
+/// A source of input events, like the touchpad.
+///
+/// Allows the gesture logic of [`crate::Numpad`] to be tested without the actual hardware.
+pub(crate) trait EventSource {
+    /// Read the next pending event.
+    ///
+    /// Fails with [`io::ErrorKind::WouldBlock`] if there are no more events.
+    fn next_event(&mut self) -> io::Result<InputEvent>;
+
+    /// Grab the device, so that its events aren't seen by anyone else. Or ungrab it.
+    fn grab(&mut self, mode: GrabMode) -> io::Result<()>;
+
+    /// Grab the device again, in case the grab was lost. Returns whether it was lost.
+    ///
+    /// Only call this while the device should be grabbed. If another process grabbed it, this
+    /// can't tell that apart from still holding the grab.
+    fn regrab(&mut self) -> io::Result<bool>;
+
+    /// Path of the device node, like `/dev/input/event14`, if known.
+    fn path(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+impl EventSource for Device {
+    fn next_event(&mut self) -> io::Result<InputEvent> {
+        Device::next_event(self, ReadFlag::NORMAL).map(|(_, ev)| ev)
+    }
+
+    fn grab(&mut self, mode: GrabMode) -> io::Result<()> {
+        Device::grab(self, mode)
+    }
+
+    fn regrab(&mut self) -> io::Result<bool> {
+        // libevdev skips the ioctl when it thinks the device is grabbed already
+        const EVIOCGRAB: c_ulong = 0x4004_4590;
+        if unsafe { libc::ioctl(self.file().as_raw_fd(), EVIOCGRAB, 1) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EBUSY) {
+            Ok(false)
+        } else {
+            Err(err)
+        }
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        std::fs::read_link(format!("/proc/self/fd/{}", self.file().as_raw_fd())).ok()
+    }
+}
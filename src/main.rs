@@ -1,34 +1,82 @@
-#![feature(iter_advance_by)]
-
+mod calibrate;
+mod cli;
 mod config;
+mod control;
+#[cfg(feature = "dbus")]
+mod dbus;
 mod devices;
 mod dummy_keyboard;
+mod error;
+mod event_source;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod multitouch;
 mod numpad_layout;
+mod protocol;
+#[cfg(test)]
+mod replay;
+mod saved_state;
+mod signals;
+mod status_files;
+mod systemd;
+#[cfg(test)]
+mod tests;
 mod touchpad_i2c;
 mod util;
 
+use std::collections::VecDeque;
 use std::fmt::Display;
-use std::hint::unreachable_unchecked;
+use std::io::{BufRead, BufReader, Write};
 use std::os::unix::io::AsRawFd;
 use std::process::Command;
+use std::time::{Duration, Instant};
 
-use crate::config::{Config, CustomCommand};
-use crate::devices::{get_touchpad_bbox, open_input_evdev, read_proc_input};
-use crate::dummy_keyboard::{DummyKeyboard, KeyEvents};
-use crate::numpad_layout::NumpadLayout;
-use crate::touchpad_i2c::{Brightness, TouchpadI2C};
-use crate::util::{CustomDuration, ElapsedSince};
-use anyhow::{Context, Result};
+use crate::calibrate::calibrate;
+use crate::cli::Args;
+use crate::config::{Config, CornerSwipe, CustomCommand, NumpadMode, TimeOfDay};
+use crate::control::{query_status, ControlCommand, ControlSocket, Status};
+#[cfg(feature = "dbus")]
+use crate::dbus::DbusService;
+use crate::devices::{
+    find_external_mouse, find_native_numpad, get_touchpad_bbox, open_input_evdev, print_devices,
+    processes_using, read_proc_input,
+};
+use crate::dummy_keyboard::{DummyKeyboard, KeyboardSink};
+use crate::error::NumpadError;
+use crate::event_source::EventSource;
+use crate::multitouch::Contacts;
+use crate::numpad_layout::{NumpadLayout, SupportedLayout};
+use crate::protocol::ProtocolEvent;
+use crate::saved_state::SavedState;
+use crate::status_files::StatusFiles;
+use crate::touchpad_i2c::{fade_steps, Brightness, LedCommand, LedControl, TouchpadI2C};
+use crate::util::{local_minutes, now, ElapsedSince, SuspendDetector};
+use anyhow::{anyhow, Context, Result};
 use evdev_rs::{
-    enums::{EventCode, EV_ABS, EV_KEY, EV_LED, EV_MSC},
+    enums::{EventCode, EV_ABS, EV_KEY, EV_LED, EV_MSC, EV_SYN},
     Device, DeviceWrapper, InputEvent, ReadFlag, TimeVal,
 };
 use log::{debug, error, info, trace, warn};
 
+/// State of the primary finger.
+///
+/// The transitions are:
+/// * `Lifted -> TouchStart` when the finger touches, in [`Numpad::on_finger_down`].
+/// * `TouchStart -> Touching` when the touch presses something: a key, the numlock or calc bbox,
+///   or the brightness slide.
+/// * `Touching -> TouchStart` when a gesture finished, like a numlock hold, so that the rest of
+///   the touch doesn't trigger it again. See [`Numpad::ignore_rest_of_touch`].
+/// * any state `-> Lifted` when the finger is lifted or moved too much, in
+///   [`Numpad::on_finger_up`].
 #[derive(PartialEq, Debug, Clone, Copy)]
 enum FingerState {
+    /// No finger is down, the next touch starts a tap
     Lifted,
+    /// The finger is down without pressing anything
     TouchStart,
+    /// The finger is pressing what `cur_key` says
     Touching,
 }
 
@@ -62,8 +110,14 @@ pub(crate) enum CurKey {
     None,
     Numlock,
     Calc,
+    /// Sliding along the top edge to set the brightness
+    BrightnessSlide,
     /// A key on the actual numpad bbox
     Numpad(EV_KEY),
+    /// Holding the corner that switches layers. Its key is pressed on lift instead.
+    LayerCorner,
+    /// Dragging up or down after holding numlock on, to adjust the brightness
+    BrightnessAdjust,
 }
 
 impl CurKey {
@@ -79,6 +133,18 @@ impl Default for CurKey {
     }
 }
 
+/// A change of the LED that is due later, so that the event loop doesn't have to wait for it.
+/// See [`Numpad::run_led_steps`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LedStep {
+    /// Set this brightness
+    Set(Brightness),
+    /// Set the brightness that the LED should have now, like at the end of a blink
+    Restore,
+    /// Like `Restore`, after a failed write. It's not retried again if it fails too.
+    Retry,
+}
+
 #[derive(Debug)]
 struct TouchpadState {
     pos: Point,
@@ -90,14 +156,82 @@ struct TouchpadState {
     tapped_outside_numlock_bbox: bool,
     finger_dragged_too_much: bool,
     dragged_finger_lifted_at: TimeVal,
+    /// Start of the last tap in the numlock bbox, used to detect double taps
+    last_numlock_tap_at: TimeVal,
+    /// When numlock was last toggled by a gesture, used to ignore accidental re-toggles
+    last_toggle_at: TimeVal,
+    /// When numlock was last turned on, in any way, for the activation grace period
+    enabled_at: TimeVal,
+    /// When the configured number of fingers touched the touchpad
+    multi_finger_tap_started_at: TimeVal,
+    multi_finger_tap_start_pos: Point,
     brightness: Brightness,
+    /// Brightness in percent, used instead of `brightness` when set
+    brightness_percent: Option<u8>,
     calc_open: bool,
+    /// Last reported contact size, used for palm rejection
+    touch_major: i32,
+    width_major: i32,
+    /// Last reported pressure, `None` if the touchpad doesn't report it
+    pressure: Option<i32>,
+    /// When a numpad key was last pressed, or numlock was turned on if none was pressed since,
+    /// for `auto_disable_secs`
+    last_key_at: Option<TimeVal>,
+    /// When a key was last pressed on the physical keyboard
+    last_kbd_key_at: Option<TimeVal>,
+    /// All the fingers on the touchpad. `pos` follows the primary one.
+    contacts: Contacts,
+    /// The swipe that the touch started in the corner of, if any
+    corner_swipe: Option<CornerSwipe>,
+    /// A finger touched while numlock is held on in momentary mode, and the tap starts once its
+    /// position is known
+    momentary_tap_pending: bool,
+    /// Numlock was turned on by holding the finger, which must be lifted before pressing keys
+    suppress_until_lift: bool,
+    /// Modifiers of the toggle hotkey that are held down on the keyboard
+    held_modifiers: Vec<EV_KEY>,
+    /// Index of the layout's keymap that the keys are taken from
+    active_layer: usize,
+    /// Y position at which the brightness was last adjusted by dragging after a hold
+    brightness_adjust_y: i32,
+    /// Last position sent as pointer motion, while the touch moves the pointer instead of
+    /// pressing keys, with `pointer_while_numlock`
+    pointer_pos: Option<Point>,
+    /// Key under the numlock bbox where the touch started, typed if it's released before the
+    /// hold toggles numlock
+    numlock_tap_key: Option<EV_KEY>,
+    /// Key of a short tap in the numlock bbox, with the start of the tap. With double taps
+    /// allowed, it's typed only once it's clear that the tap wasn't the first of a double tap.
+    pending_tap_key: Option<(EV_KEY, TimeVal)>,
+    /// Whether the touchpad is grabbed, as far as we know
+    grabbed: bool,
+    /// Number of times numlock was toggled by us since startup
+    toggles: u64,
+    /// Number of numpad keys pressed since startup
+    keys_pressed: u64,
+    /// Pulses of a notification that waits for the finger to be lifted
+    pending_notify: Option<u8>,
+    /// Number of failed reads from the touchpad in a row
+    read_errors: u32,
+    /// The night brightness is shown instead of `brightness`, until it's changed by the user
+    night: bool,
+    /// An external mouse was plugged in when last checked
+    external_mouse: bool,
+    /// Scheduled LED changes, in the order they are due
+    led_steps: VecDeque<(Instant, LedStep)>,
+    /// Brightness that was last written to the LED, to fade from. `None` if it's not known,
+    /// like after the touchpad was reset.
+    led: Option<Brightness>,
 }
 
 impl TouchpadState {
     #[inline]
-    fn toggle_numlock(&mut self) -> bool {
+    fn toggle_numlock(&mut self, time: TimeVal) -> bool {
         self.numlock = !self.numlock;
+        self.toggles += 1;
+        if self.numlock {
+            self.enabled_at = time;
+        }
         self.numlock
     }
 }
@@ -120,20 +254,72 @@ impl Default for TouchpadState {
                 tv_sec: 0,
                 tv_usec: 0,
             },
+            last_numlock_tap_at: TimeVal {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            last_toggle_at: TimeVal {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            enabled_at: TimeVal {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            multi_finger_tap_started_at: TimeVal {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            multi_finger_tap_start_pos: Default::default(),
             brightness: Default::default(),
+            brightness_percent: None,
             calc_open: false,
+            touch_major: 0,
+            width_major: 0,
+            pressure: None,
+            last_key_at: None,
+            last_kbd_key_at: None,
+            contacts: Default::default(),
+            corner_swipe: None,
+            momentary_tap_pending: false,
+            suppress_until_lift: false,
+            held_modifiers: Vec::new(),
+            active_layer: 0,
+            brightness_adjust_y: 0,
+            pointer_pos: None,
+            numlock_tap_key: None,
+            pending_tap_key: None,
+            grabbed: false,
+            toggles: 0,
+            keys_pressed: 0,
+            pending_notify: None,
+            read_errors: 0,
+            night: false,
+            external_mouse: false,
+            led_steps: VecDeque::new(),
+            led: None,
         }
     }
 }
 
-struct Numpad {
-    evdev: Device,
-    keyboard_evdev: Device,
-    touchpad_i2c: TouchpadI2C,
-    dummy_kb: DummyKeyboard,
+struct Numpad<S: EventSource = Device, K: KeyboardSink = DummyKeyboard, L: LedControl = TouchpadI2C>
+{
+    evdev: S,
+    keyboard_evdev: S,
+    touchpad_i2c: L,
+    dummy_kb: K,
     layout: NumpadLayout,
     state: TouchpadState,
     config: Config,
+    control: Option<ControlSocket>,
+    /// State as last written to the state file, if `persist_state` is enabled
+    saved_state: Option<SavedState>,
+    status_files: StatusFiles,
+    /// Metrics as last written to `metrics_file`
+    #[cfg(feature = "metrics")]
+    metrics_written: Option<String>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<DbusService>,
 }
 
 impl std::fmt::Debug for Numpad {
@@ -145,13 +331,12 @@ impl std::fmt::Debug for Numpad {
             .field("touchpad_i2c", &self.touchpad_i2c)
             .field("state", &self.state)
             .field("layout", &self.layout)
+            .field("control", &self.control)
             .finish()
     }
 }
 
-impl Numpad {
-    const HOLD_DURATION: CustomDuration = CustomDuration::from_millis(250);
-
+impl<S: EventSource, K: KeyboardSink, L: LedControl> Numpad<S, K, L> {
     /// Min Euclidean distance (squared) that a finger needs to move for a tap
     /// to be changed into a drag.  
     const TAP_JITTER_DIST: i32 = 10000;
@@ -160,104 +345,498 @@ impl Numpad {
     /// trigger the calculator key when numlock isn't active.
     const CALC_DRAG_DIST: i32 = 90000;
 
+    /// Failed reads in a row after which the touchpad is reopened.
+    const MAX_READ_ERRORS: u32 = 5;
+
+    /// Wait after a failed read, multiplied by the number of failures in a row.
+    const READ_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+
+    /// Pointer motion, in pixels, of moving the finger across the whole touchpad with
+    /// `pointer_while_numlock`.
+    const POINTER_RANGE: f32 = 1500.0;
+
+    /// Fraction of the touchpad height that a finger needs to be dragged after holding numlock
+    /// on, to change the brightness by one level.
+    const BRIGHTNESS_ADJUST_STEP: f32 = 0.1;
+
     fn new(
-        evdev: Device,
-        keyboard_evdev: Device,
-        touchpad_i2c: TouchpadI2C,
-        dummy_kb: DummyKeyboard,
+        evdev: S,
+        keyboard_evdev: S,
+        touchpad_i2c: L,
+        dummy_kb: K,
         layout: NumpadLayout,
         config: Config,
+        control: Option<ControlSocket>,
     ) -> Self {
+        let saved_state = if config.persist_state() {
+            SavedState::load()
+        } else {
+            None
+        };
+        let state = TouchpadState {
+            brightness: saved_state.map_or(config.default_brightness(), |saved| saved.brightness),
+            brightness_percent: config.brightness_percent(),
+            ..Default::default()
+        };
         Self {
             evdev,
             keyboard_evdev,
             touchpad_i2c,
             dummy_kb,
             layout,
-            state: TouchpadState::default(),
+            state,
             config,
+            control,
+            saved_state,
+            status_files: StatusFiles::new(),
+            #[cfg(feature = "metrics")]
+            metrics_written: None,
+            #[cfg(feature = "dbus")]
+            dbus: None,
         }
     }
 
     /// Toggle numlock when user presses the numlock bbox on touchpad.
-    fn toggle_numlock(&mut self) -> Result<()> {
-        if self.state.toggle_numlock() {
-            self.touchpad_i2c.set_brightness(self.state.brightness)?;
-            // don't grab touchpad - allow moving pointer even if active
+    ///
+    /// `time` is when it happened, as the timestamp of the triggering event or [`now`].
+    fn toggle_numlock(&mut self, time: TimeVal) -> Result<()> {
+        let numlock = self.state.toggle_numlock(time);
+        self.emit(ProtocolEvent::Toggle(numlock));
+        if numlock {
+            info!("Toggling numlock on");
+            self.state.last_key_at = Some(time);
+            self.pick_brightness(self.config.brightness_levels().on);
+            if !self.blink() {
+                self.light_up();
+            }
+            if self.config.pointer_while_numlock() {
+                // the pointer motion is forwarded through the virtual device instead
+                self.grab();
+            }
         } else {
-            self.touchpad_i2c.set_brightness(Brightness::Zero)?;
+            info!("Toggling numlock off");
+            if !self.blink() {
+                self.set_led(Brightness::Zero);
+            }
             // we might still be grabbing the touchpad. release it.
             self.ungrab();
         }
-        // Tell the system that we want to toggle the numlock
-        self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK);
+        if self.config.emit_numlock_key() {
+            // Tell the system that we want to toggle the numlock
+            self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK);
+        }
+        Ok(())
+    }
+
+    /// Toggle numlock because of a touchpad gesture, unless it was toggled too recently.
+    fn toggle_numlock_by_gesture(&mut self, time: TimeVal) -> Result<()> {
+        if time.elapsed_since(self.state.last_toggle_at) < self.config.min_toggle_interval() {
+            debug!("Toggled numlock too recently - ignoring");
+            return Ok(());
+        }
+        self.state.last_toggle_at = time;
+        self.toggle_numlock(time)
+    }
+
+    /// Handle a finished hold in the numlock bbox.
+    ///
+    /// In momentary mode, numlock is turned on only while the holding finger stays down.
+    fn on_numlock_hold(&mut self, time: TimeVal) -> Result<()> {
+        let momentary = self.config.mode() == NumpadMode::Momentary && !self.state.numlock;
+        self.toggle_numlock_by_gesture(time)?;
+        if momentary && self.state.numlock {
+            if self.state.contacts.hold_primary() {
+                debug!("Momentary - numlock on while holding");
+                if !self.grab_or_disable()? {
+                    return Ok(());
+                }
+                // ready for the taps of the other fingers
+                self.state.finger_state = FingerState::Lifted;
+                self.state.cur_key.reset();
+                return Ok(());
+            }
+            debug!("Touchpad doesn't report multiple fingers, numlock stays on");
+        }
+        // the toggle gesture shouldn't also type a key
+        self.state.suppress_until_lift = self.state.numlock;
+        if self.state.numlock && self.config.hold_drag_brightness() {
+            debug!("Hold finish - drag up or down to adjust brightness");
+            // stop the drag from moving the pointer
+            if !self.grab_or_disable()? {
+                return Ok(());
+            }
+            self.state.cur_key = CurKey::BrightnessAdjust;
+            self.state.brightness_adjust_y = self.state.pos.y;
+        }
+        // If user doesn't lift the finger quickly, we don't want to keep
+        // toggling, so assume finger was moved.
+        // Can't do finger_state = Lifted, since that would start another tap
+        self.state.finger_state = FingerState::TouchStart;
+        Ok(())
+    }
+
+    /// Handle a finger touching or lifting while numlock is held on in momentary mode.
+    ///
+    /// The `BTN_TOOL_*` events are about the number of fingers, so the taps of the fingers
+    /// pressing the keys follow their tracking IDs instead.
+    fn on_momentary_contact(
+        &mut self,
+        tracking_id: i32,
+        was_primary: bool,
+        time: TimeVal,
+    ) -> Result<()> {
+        if !self.state.contacts.has_holder() {
+            debug!("Momentary - holding finger lifted");
+            self.on_finger_up();
+            self.state.momentary_tap_pending = false;
+            if self.state.numlock {
+                self.toggle_numlock(time)?;
+            }
+            // ignore the fingers that are still down
+            self.ignore_rest_of_touch();
+        } else if tracking_id >= 0 {
+            self.state.momentary_tap_pending = true;
+        } else if was_primary {
+            self.on_finger_up();
+        }
         Ok(())
     }
 
+    /// Set the LED brightness, logging failures instead of returning them.
+    ///
+    /// The numpad is still usable without the LED, so this shouldn't stop the daemon. Replaces
+    /// the LED changes that were scheduled, so that they don't undo this one. Returns whether
+    /// the brightness was set, or the first step of the fade with `brightness_fade_ms`.
+    fn set_led(&mut self, brightness: Brightness) -> bool {
+        self.state.led_steps.clear();
+        let fade = self.config.brightness_fade();
+        let steps = match self.state.led {
+            Some(from) if !fade.is_zero() => fade_steps(from, brightness),
+            _ => Vec::new(),
+        };
+        // the rest of the fade is made from the event loop
+        let step_duration = fade / steps.len().max(1) as u32;
+        for (i, &step) in steps.iter().enumerate().skip(1) {
+            self.schedule_led(step_duration * i as u32, LedStep::Set(step));
+        }
+        self.write_led(steps.first().copied().unwrap_or(brightness))
+    }
+
+    /// Like [`Self::set_led`], but keeping the scheduled LED changes.
+    ///
+    /// The bus can be busy for a moment, so a failed write is retried once shortly after, by
+    /// setting the brightness that the LED should have then.
+    fn write_led(&mut self, brightness: Brightness) -> bool {
+        const RETRY_DELAY: Duration = Duration::from_millis(20);
+        match self.touchpad_i2c.set_brightness(brightness) {
+            Ok(()) => {
+                self.state.led = Some(brightness);
+                true
+            }
+            Err(err) => {
+                warn!("{:#}, retrying", err);
+                // what was scheduled would fail too, so only the retry is left
+                self.state.led_steps.clear();
+                self.schedule_led(RETRY_DELAY, LedStep::Retry);
+                false
+            }
+        }
+    }
+
+    /// Whether it's night according to the night schedule, if there is one.
+    fn is_night(&self) -> bool {
+        self.config.night_schedule().map_or(false, |schedule| {
+            schedule.contains(TimeOfDay {
+                minutes: local_minutes(),
+            })
+        })
+    }
+
+    /// Pick the brightness to light up with when numlock is turned on.
+    ///
+    /// Uses `level` if one is configured for what turned it on, and the night brightness during
+    /// the night.
+    fn pick_brightness(&mut self, level: Option<Brightness>) {
+        if let Some(level) = level {
+            self.state.brightness = level;
+            self.state.brightness_percent = None;
+        }
+        self.state.night = self.is_night();
+    }
+
+    /// Get the current brightness, preferring the night brightness during the night, and then
+    /// the percentage if one is set. The LED is off while numlock is off.
+    fn led_target(&self) -> Brightness {
+        if !self.state.numlock {
+            return Brightness::Zero;
+        }
+        let night_brightness = self
+            .config
+            .night_schedule()
+            .filter(|_| self.state.night)
+            .map(|schedule| schedule.brightness);
+        match (night_brightness, self.state.brightness_percent) {
+            (Some(brightness), _) => brightness,
+            (None, Some(percent)) => Brightness::from_percent(percent),
+            (None, None) => self.state.brightness,
+        }
+    }
+
+    /// Set the LED to the current brightness, see [`Self::led_target`].
+    ///
+    /// Like [`Self::set_led`], returns whether the brightness was set.
+    fn light_up(&mut self) -> bool {
+        self.set_led(self.led_target())
+    }
+
+    /// Briefly light up the LED at full brightness, if `toggle_blink` is enabled.
+    ///
+    /// The brightness for the new numlock state is restored once the blink is over, from the
+    /// event loop, so that the touchpad keeps responding. Returns whether it blinks.
+    fn blink(&mut self) -> bool {
+        const BLINK_DURATION: Duration = Duration::from_millis(120);
+        if !self.config.toggle_blink() {
+            return false;
+        }
+        // a flash, not a fade
+        self.state.led_steps.clear();
+        if !self.write_led(Brightness::Full) {
+            return false;
+        }
+        self.schedule_led(BLINK_DURATION, LedStep::Restore);
+        true
+    }
+
+    /// Make an LED change `delay` from now, see [`Self::run_led_steps`].
+    fn schedule_led(&mut self, delay: Duration, step: LedStep) {
+        let due = Instant::now() + delay;
+        let steps = &mut self.state.led_steps;
+        // keep the steps in the order they are due
+        let index = steps
+            .iter()
+            .position(|&(at, _)| at > due)
+            .unwrap_or(steps.len());
+        steps.insert(index, (due, step));
+    }
+
+    /// Make the scheduled LED changes that are due at `now`.
+    fn run_led_steps(&mut self, now: Instant) {
+        while let Some(&(due, step)) = self.state.led_steps.front() {
+            if due > now {
+                break;
+            }
+            self.state.led_steps.pop_front();
+            match step {
+                LedStep::Set(brightness) => {
+                    self.write_led(brightness);
+                }
+                LedStep::Restore => {
+                    self.light_up();
+                }
+                LedStep::Retry => {
+                    let brightness = self.led_target();
+                    match self.touchpad_i2c.set_brightness(brightness) {
+                        Ok(()) => self.state.led = Some(brightness),
+                        Err(err) => warn!("{:#}, giving up", err),
+                    }
+                }
+            }
+        }
+    }
+
+    /// How long until the next scheduled LED change is due, if there is one.
+    fn led_timeout(&self, now: Instant) -> Option<Duration> {
+        self.state
+            .led_steps
+            .front()
+            .map(|&(due, _)| due.saturating_duration_since(now))
+    }
+
+    /// Flash the LED `pulses` times, and then restore its brightness.
+    ///
+    /// Waits until the finger is lifted if a touch is in progress, so that the flashes don't get
+    /// in the way of the key presses. Like [`Self::blink`], the flashes are made from the event
+    /// loop.
+    fn notify(&mut self, pulses: u8) {
+        const PULSE_DURATION: Duration = Duration::from_millis(150);
+        if self.state.finger_state != FingerState::Lifted || self.state.cur_key != CurKey::None {
+            debug!("Touch in progress, notifying once the finger is lifted");
+            self.state.pending_notify = Some(pulses);
+            return;
+        }
+        info!("Notify - flashing the LED {} times", pulses);
+        self.state.led_steps.clear();
+        let pulses = u32::from(pulses);
+        for pulse in 0..pulses {
+            self.schedule_led(PULSE_DURATION * 2 * pulse, LedStep::Set(Brightness::Full));
+            self.schedule_led(
+                PULSE_DURATION * (2 * pulse + 1),
+                LedStep::Set(Brightness::Zero),
+            );
+        }
+        self.schedule_led(PULSE_DURATION * 2 * pulses, LedStep::Restore);
+        // the first flash starts right away
+        self.run_led_steps(Instant::now());
+    }
+
+    /// Step the LED through all the brightness levels, to check that brightness control works.
+    ///
+    /// Failures are only logged, since the numpad is still usable without the LED.
+    fn self_test(&mut self) {
+        const STEP_DURATION: Duration = Duration::from_millis(500);
+        info!("Self-test: stepping through the brightness levels");
+        let mut failed = false;
+        for brightness in [
+            Brightness::Low,
+            Brightness::Half,
+            Brightness::Full,
+            Brightness::Zero,
+        ] {
+            info!("Self-test: brightness {}", brightness);
+            if let Err(err) = self.touchpad_i2c.set_brightness(brightness) {
+                warn!(
+                    "Self-test: failed to set brightness to {}: {:#}",
+                    brightness, err
+                );
+                failed = true;
+            }
+            std::thread::sleep(STEP_DURATION);
+        }
+        if failed {
+            warn!("Self-test: brightness control doesn't work, check the I2C device and address");
+        } else {
+            info!("Self-test: done. If the LED didn't change, try setting i2c_addrs in the config");
+        }
+    }
+
     /// Handle numlock pressed *from an external keyboard*.
     ///
     /// This is to keep the touchpad state in sync with system's numlock.
-    fn handle_numlock_pressed(&mut self, val: i32) -> Result<()> {
+    fn handle_numlock_pressed(&mut self, val: i32, time: TimeVal) {
         if val == 0 {
-            debug!("setting off");
+            info!("System numlock turned off");
             self.state.numlock = false;
+            self.emit(ProtocolEvent::Toggle(false));
             // we might still be grabbing the touchpad. release it.
             self.ungrab();
-            self.touchpad_i2c.set_brightness(Brightness::Zero)
+            self.set_led(Brightness::Zero);
         } else {
-            debug!("setting on {}", self.state.brightness);
+            info!("System numlock turned on");
             self.state.numlock = true;
-            self.touchpad_i2c.set_brightness(self.state.brightness)
+            self.state.enabled_at = time;
+            self.emit(ProtocolEvent::Toggle(true));
+            self.state.last_key_at = Some(time);
+            self.pick_brightness(self.config.brightness_levels().system_on);
+            self.light_up();
+            if self.config.pointer_while_numlock() {
+                self.grab();
+            }
         }
         // The numlock has already been toggled on the system- no need to press
         // the Num_Lock evkey.
     }
 
-    /// Query the initial state of numlock led from the system.
-    fn initialize_numlock(&mut self) -> Result<()> {
-        let init_numlock = self
-            .keyboard_evdev
-            .event_value(&EventCode::EV_LED(EV_LED::LED_NUML));
-        match init_numlock {
-            Some(init_numlock) => {
-                if init_numlock != 0 {
-                    if self.config.disable_numlock_on_start() {
-                        self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK);
-                    } else {
-                        self.handle_numlock_pressed(init_numlock)?;
-                    }
-                }
+    /// Grab the touchpad, so that the touches pressing keys don't also move the pointer.
+    ///
+    /// Returns false if it failed, like when another process grabbed the touchpad already.
+    fn grab(&mut self) -> bool {
+        if self.config.dry_run() {
+            println!("Dry run: would grab the touchpad");
+            return true;
+        }
+        debug!("Grabbing");
+        let err = match self.evdev.grab(evdev_rs::GrabMode::Grab) {
+            Ok(()) => {
+                self.state.grabbed = true;
+                return true;
+            }
+            Err(err) => err,
+        };
+        warn!("Failed to grab the touchpad: {}", err);
+        if err.raw_os_error() == Some(libc::EBUSY) {
+            let holders = self
+                .evdev
+                .path()
+                .map(|path| processes_using(&path))
+                .unwrap_or_default();
+            if !holders.is_empty() {
+                warn!(
+                    "The touchpad might be grabbed by one of: {}",
+                    holders.join(", ")
+                );
             }
-            None => error!(
-                "Failed to get initial numlock state. \
-                There might be something wrong with evdev keyboard detection. \
-                {}",
-                self.keyboard_evdev.name().map_or_else(
-                    || "Unknown device".to_owned(),
-                    |n| format!("Using device: {}", n)
-                )
-            ),
         }
-        Ok(())
+        false
     }
 
-    fn grab(&mut self) {
-        debug!("Grabbing");
-        self.evdev
-            .grab(evdev_rs::GrabMode::Grab)
-            .unwrap_or_else(|err| warn!("Failed to grab {}", err));
+    /// Grab the touchpad, or turn numlock off if that fails.
+    ///
+    /// The keys can't be used without grabbing, since every touch would also move the pointer
+    /// or click. Returns whether the touchpad was grabbed.
+    fn grab_or_disable(&mut self) -> Result<bool> {
+        if self.grab() {
+            return Ok(true);
+        }
+        if self.state.numlock {
+            warn!("Turning numlock off, since the touchpad can't be grabbed");
+            self.toggle_numlock(now())?;
+            self.ignore_rest_of_touch();
+        }
+        Ok(false)
     }
 
     fn ungrab(&mut self) {
+        if self.config.dry_run() {
+            return;
+        }
+        self.state.grabbed = false;
         self.evdev
             .grab(evdev_rs::GrabMode::Ungrab)
             .unwrap_or_else(|err| warn!("Failed to ungrab {}", err));
     }
 
+    /// Grab the touchpad again if the grab was lost while numlock is on, like after a device
+    /// reset. Otherwise the touches would move the pointer too.
+    fn check_grab(&mut self) {
+        if !self.state.numlock || !self.state.grabbed {
+            return;
+        }
+        match self.evdev.regrab() {
+            Ok(true) => warn!("The touchpad grab was lost, grabbed it again"),
+            Ok(false) => (),
+            Err(err) => warn!("Failed to check the touchpad grab: {}", err),
+        }
+    }
+
+    /// Turn numlock off when an external mouse is plugged in, with `disable_on_external_mouse`.
+    ///
+    /// `mouse` is the name of the external mouse, if one is plugged in. Only the mouse appearing
+    /// turns numlock off, so that it can be turned back on while the mouse is plugged in.
+    fn on_external_mouse(&mut self, mouse: Option<String>) -> Result<()> {
+        let appeared = mouse.is_some() && !self.state.external_mouse;
+        self.state.external_mouse = mouse.is_some();
+        match mouse {
+            Some(name) if appeared => {
+                info!("External mouse {} plugged in", name);
+                if self.state.numlock {
+                    info!("Turning numlock off, since an external mouse is used");
+                    self.toggle_numlock(now())?;
+                    self.ignore_rest_of_touch();
+                }
+            }
+            Some(_) => (),
+            None => debug!("No external mouse"),
+        }
+        Ok(())
+    }
+
     fn start_calc(&mut self) {
         debug!("Starting calc");
         match self.config.calc_start_command() {
             CustomCommand::Keys(keys) => self.dummy_kb.multi_keypress(keys.as_slice()),
+            CustomCommand::Command { cmd, args } if self.config.dry_run() => {
+                println!("Dry run: would run command {} with args {:?}", cmd, args);
+            }
             CustomCommand::Command { cmd, args } => {
                 debug!("Running command {} with args {:?}", cmd, args);
                 let cmd = cmd.clone();
@@ -287,6 +866,9 @@ impl Numpad {
 
             match stop_cmd {
                 CustomCommand::Keys(keys) => self.dummy_kb.multi_keypress(keys.as_slice()),
+                CustomCommand::Command { cmd, args } if self.config.dry_run() => {
+                    println!("Dry run: would run command {} with args {:?}", cmd, args);
+                }
                 CustomCommand::Command { cmd, args } => {
                     debug!("Running command {} with args {:?}", cmd, args);
                     match Command::new(cmd).args(args).spawn() {
@@ -308,187 +890,1196 @@ impl Numpad {
         }
     }
 
-    fn on_lift(&mut self) {
-        // end of tap
-        debug!("End tap");
-        if self.state.cur_key == CurKey::Calc
-            && self.state.pos.dist_sq(self.state.tap_start_pos) >= Self::CALC_DRAG_DIST
-        {
-            if !self.state.calc_open {
-                self.start_calc();
-            } else {
-                self.stop_calc();
-            }
-            self.state.calc_open = !self.state.calc_open;
+    /// Start the calc if it isn't open, otherwise stop it.
+    fn toggle_calc(&mut self) {
+        if !self.state.calc_open {
+            self.start_calc();
+        } else {
+            self.stop_calc();
         }
+        self.state.calc_open = !self.state.calc_open;
+    }
 
-        if self.state.finger_state == FingerState::Touching {
-            if let CurKey::Numpad(key) = self.state.cur_key {
-                debug!("Keyup {:?}", key);
+    /// Print the event on stdout, if `--emit-protocol` was passed.
+    fn emit(&self, event: ProtocolEvent) {
+        if self.config.emit_protocol() {
+            event.emit();
+        }
+    }
 
-                if self.layout.needs_multikey(key) {
-                    self.dummy_kb.multi_keyup(&self.layout.multikeys(key));
-                } else {
-                    self.dummy_kb.keyup(key);
-                }
-                // if we ungrab here, it causes the pointer to jump
-                // so we only ungrab when finger is dragged
-            }
+    fn numpad_keydown(&mut self, key: EV_KEY) {
+        debug!("Keydown {:?}", key);
+        self.state.keys_pressed += 1;
+        self.emit(ProtocolEvent::Key(key, true));
+        match self.layout.multikeys(key) {
+            Some(keys) => self.dummy_kb.multi_keydown(keys),
+            None => self.dummy_kb.keydown(key),
         }
-        self.state.cur_key.reset();
-        self.state.finger_state = FingerState::Lifted;
     }
 
-    fn on_tap(&mut self, time: TimeVal) {
-        if self.state.finger_state == FingerState::Lifted {
-            // start of tap
-            debug!("Start tap");
-            self.state.finger_state = FingerState::TouchStart;
-            self.state.tap_started_at = time;
-            self.state.tap_start_pos = self.state.pos;
-            self.state.tapped_outside_numlock_bbox = false;
-            self.state.finger_dragged_too_much = false;
-            if self.state.numlock {
-                self.state.cur_key = match self.layout.get_key(self.state.pos) {
-                    Some(key) => {
-                        self.grab();
-                        self.state.finger_state = FingerState::Touching;
-
-                        debug!("Keydown {:?}", key);
-                        if self.layout.needs_multikey(key) {
-                            self.dummy_kb.multi_keydown(&self.layout.multikeys(key));
-                        } else {
-                            self.dummy_kb.keydown(key);
-                        }
-                        CurKey::Numpad(key)
-                    }
-                    None => CurKey::None,
-                };
-            }
+    fn numpad_keyup(&mut self, key: EV_KEY) {
+        debug!("Keyup {:?}", key);
+        self.emit(ProtocolEvent::Key(key, false));
+        match self.layout.multikeys(key) {
+            Some(keys) => self.dummy_kb.multi_keyup(keys),
+            None => self.dummy_kb.keyup(key),
         }
-        if self.layout.in_numlock_bbox(self.state.pos) {
-            debug!("In numlock - start");
-            self.state.finger_state = FingerState::Touching;
-            self.state.cur_key = CurKey::Numlock;
-        } else {
-            if self.layout.in_calc_bbox(self.state.pos) {
-                debug!("In calc - start");
-                self.state.finger_state = FingerState::Touching;
-                self.state.cur_key = CurKey::Calc;
+    }
+
+    /// Whether the finger is currently pressing a key while drag-to-new-key is enabled.
+    fn dragging_on_numpad(&self) -> bool {
+        self.config.drag_to_new_key()
+            && self.state.finger_state == FingerState::Touching
+            && matches!(self.state.cur_key, CurKey::Numpad(_))
+    }
+
+    /// Whether the finger left the key it's pressing, while release-on-leave is enabled.
+    fn left_key(&self) -> bool {
+        match self.state.cur_key {
+            CurKey::Numpad(key) => {
+                self.config.release_on_leave()
+                    && self.state.finger_state == FingerState::Touching
+                    && self.key_at(self.state.pos) != Some(key)
             }
-            self.state.tapped_outside_numlock_bbox = true
+            _ => false,
         }
     }
 
-    fn handle_touchpad_event(&mut self, ev: InputEvent) -> Result<()> {
-        // TODO: Double-taps when numpad is active should not be propagated.
-        //       Need to grab/ungrab the device intelligently.
+    /// Whether the finger moved further than `toggle_jitter` from where the touch started.
+    ///
+    /// Distances are fractions of the touchpad's size, like the regions of layout files.
+    fn moved_during_hold(&self) -> bool {
+        self.config.toggle_jitter().map_or(false, |jitter| {
+            let (start_x, start_y) = self.layout.normalize(self.state.tap_start_pos);
+            let (x, y) = self.layout.normalize(self.state.pos);
+            (x - start_x).hypot(y - start_y) > jitter
+        })
+    }
 
-        // no need to trace timestamp events - too noisy
-        if !matches!(ev.event_code, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP)) {
-            trace!("TP {:?} {}", ev.event_code, ev.value);
-        }
-        match ev.event_code {
-            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X) => {
-                self.state.pos.x = ev.value;
-            }
-            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y) => {
-                self.state.pos.y = ev.value;
-            }
-            EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER) if ev.value == 0 => {
-                if !self.state.finger_dragged_too_much {
-                    // only call on_lift if we did not already call it as a result of finger drag
-                    self.on_lift();
-                } else {
-                    self.state.dragged_finger_lifted_at = ev.time;
-                }
-            }
-            EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER) if ev.value == 1 => {
-                if !self.state.finger_dragged_too_much
-                    || ev.time.elapsed_since(self.state.dragged_finger_lifted_at)
-                        >= Self::HOLD_DURATION
-                {
-                    self.on_tap(ev.time);
-                }
-            }
-            EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP) => {
-                // The toggle should happen automatically after HOLD_DURATION, even if user is
-                // still touching the numpad bbox.
-                if self.state.finger_state == FingerState::TouchStart {
-                    trace!("Touch {}", self.state.pos);
-                }
+    /// Whether the current contact is too large to be a finger.
+    fn is_palm(&self) -> bool {
+        let size = if self.state.touch_major != 0 {
+            self.state.touch_major
+        } else {
+            self.state.width_major
+        };
+        self.config
+            .palm_threshold()
+            .map_or(false, |threshold| size > threshold)
+    }
 
-                if self.state.finger_state == FingerState::Touching
-                    && !self.state.tapped_outside_numlock_bbox
-                {
-                    if self.layout.in_numlock_bbox(self.state.pos) {
-                        if ev.time.elapsed_since(self.state.tap_started_at) >= Self::HOLD_DURATION {
-                            debug!("Hold finish - toggle numlock");
-                            self.toggle_numlock()?;
-                            // If user doesn't lift the finger quickly, we don't want to keep
-                            // toggling, so assume finger was moved.
-                            // Can't do finger_state = Lifted, since that would start another tap
-                            self.state.finger_state = FingerState::TouchStart;
-                        }
-                    } else {
-                        self.state.tapped_outside_numlock_bbox = true;
-                    }
-                }
-                if self.state.numlock
-                    && self.state.cur_key == CurKey::Calc
-                    && self.layout.in_calc_bbox(self.state.pos)
-                    && ev.time.elapsed_since(self.state.tap_started_at) >= Self::HOLD_DURATION
-                {
-                    debug!("Hold finish - cycle brightness");
-                    self.touchpad_i2c
-                        .set_brightness(self.state.brightness.cycle())?;
-                    self.state.cur_key.reset();
-                }
-            }
-            _ => (),
+    /// Whether the finger presses harder than the configured minimum pressure.
+    ///
+    /// Always true if the touchpad doesn't report pressure.
+    fn pressed_hard_enough(&self) -> bool {
+        match (self.state.pressure, self.config.min_pressure()) {
+            (Some(pressure), Some(min_pressure)) => pressure >= min_pressure,
+            _ => true,
         }
+    }
 
-        // if the finger drags too much, stop the tap
-        // TODO: Use the same logic for numlock bbox instead of `tapped_outside_numlock_bbox`
-        if self.state.numlock
-            && self.state.finger_state == FingerState::Touching
-            && self.state.cur_key != CurKey::Calc // we are fine if finger drags on calc box
-            && self.state.tap_start_pos.dist_sq(self.state.pos) > Self::TAP_JITTER_DIST
+    /// Handle a key event of the physical keyboard.
+    ///
+    /// The value is `1` for presses, `2` for autorepeats and `0` for releases.
+    fn on_keyboard_key(&mut self, key: EV_KEY, value: i32, time: TimeVal) -> Result<()> {
+        if value != 0 {
+            // for disable_while_typing_ms
+            self.state.last_kbd_key_at = Some(time);
+        }
+        let hotkey = match self.config.toggle_hotkey() {
+            Some(hotkey) => hotkey,
+            None => return Ok(()),
+        };
+        let held = &mut self.state.held_modifiers;
+        if hotkey.modifiers.contains(&key) {
+            held.retain(|&modifier| modifier != key);
+            if value != 0 {
+                held.push(key);
+            }
+        } else if key == hotkey.key
+            && value == 1
+            && hotkey
+                .modifiers
+                .iter()
+                .all(|modifier| held.contains(modifier))
         {
-            debug!("Moved too much");
-            self.state.finger_dragged_too_much = true;
-            self.ungrab();
-            self.on_lift();
+            debug!("Hotkey pressed - toggle numlock");
+            self.toggle_numlock(time)?;
         }
         Ok(())
     }
 
-    fn process(&mut self) -> Result<()> {
-        self.initialize_numlock()?;
+    /// Whether a key was pressed on the keyboard too recently for the touchpad to press keys.
+    fn typing(&self, time: TimeVal) -> bool {
+        match (
+            self.config.disable_while_typing_duration(),
+            self.state.last_kbd_key_at,
+        ) {
+            (Some(window), Some(last_kbd_key_at)) => time.elapsed_since(last_kbd_key_at) < window,
+            _ => false,
+        }
+    }
 
-        let tp_fd = libc::pollfd {
-            fd: self.evdev.file().as_raw_fd(),
-            events: libc::POLLIN,
-            revents: 0,
+    /// Get the key at the position, in the active layer.
+    fn key_at(&self, pos: Point) -> Option<EV_KEY> {
+        self.layout.get_key_in_layer(pos, self.state.active_layer)
+    }
+
+    /// Whether the finger is in the corner that switches layers, if there are any.
+    fn in_layer_corner(&self) -> bool {
+        match self.config.layer_corner() {
+            Some(corner) if self.layout.num_layers() > 1 => {
+                self.layout.in_corner(corner, self.state.pos)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the finger is on the key of the grid cell that cycles the brightness, if any.
+    fn in_brightness_cell(&self) -> bool {
+        self.config.brightness_cell().map_or(false, |cell| {
+            self.key_at(self.state.pos).is_some()
+                && self.layout.cell(self.state.pos) == Some((cell.row, cell.col))
+        })
+    }
+
+    /// Set the LED to the next brightness level.
+    fn cycle_brightness(&mut self) {
+        let brightness = self.state.brightness.next();
+        if self.set_led(brightness) {
+            info!("Cycle brightness to {}", brightness);
+            self.state.brightness = brightness;
+            self.state.brightness_percent = None;
+            self.state.night = false;
+        }
+    }
+
+    /// Switch to the next layer, wrapping around to the layout's own keys.
+    fn cycle_layer(&mut self) {
+        self.state.active_layer = (self.state.active_layer + 1) % self.layout.num_layers();
+        info!("Hold finish - switch to layer {}", self.state.active_layer);
+    }
+
+    /// Whether numlock was turned on too recently for the touch at `time` to press keys.
+    fn in_activation_grace(&self, time: TimeVal) -> bool {
+        self.state.numlock
+            && time.elapsed_since(self.state.enabled_at) < self.config.activation_grace()
+    }
+
+    fn press_key_at_pos(&mut self, time: TimeVal) -> Result<()> {
+        if self.state.suppress_until_lift {
+            debug!("Numlock was just toggled on by this touch, not pressing a key");
+            return Ok(());
+        }
+        if self.in_activation_grace(time) {
+            debug!("Numlock was just toggled on, not pressing a key yet");
+            return Ok(());
+        }
+        if self.in_brightness_cell() {
+            if !self.grab_or_disable()? {
+                return Ok(());
+            }
+            // pressing nothing, so that the lift doesn't release a key
+            self.state.finger_state = FingerState::Touching;
+            self.state.cur_key.reset();
+            self.cycle_brightness();
+            return Ok(());
+        }
+        self.state.cur_key = match self.key_at(self.state.pos) {
+            Some(key) => {
+                if !self.grab_or_disable()? {
+                    return Ok(());
+                }
+                self.state.finger_state = FingerState::Touching;
+                self.numpad_keydown(key);
+                CurKey::Numpad(key)
+            }
+            None => CurKey::None,
+        };
+        Ok(())
+    }
+
+    /// Set the brightness according to the finger's position along the top edge.
+    fn on_brightness_slide(&mut self) -> Result<()> {
+        let fraction = self.layout.top_edge_fraction(self.state.pos);
+        let brightness = Brightness::from_fraction(fraction);
+        if brightness != self.state.brightness && self.set_led(brightness) {
+            info!("Slide - set brightness to {}", brightness);
+            self.state.brightness = brightness;
+            self.state.brightness_percent = None;
+            self.state.night = false;
+        }
+        Ok(())
+    }
+
+    /// Send the finger's motion since the last call as pointer motion.
+    ///
+    /// The motion is scaled so that moving across the whole touchpad moves the pointer by
+    /// [`Self::POINTER_RANGE`] pixels. Motion smaller than half a pixel is kept for the next call.
+    fn on_pointer_move(&mut self) {
+        let last = match self.state.pointer_pos {
+            Some(last) => last,
+            None => return,
+        };
+        let (last_x, last_y) = self.layout.normalize(last);
+        let (x, y) = self.layout.normalize(self.state.pos);
+        let dx = ((x - last_x) * Self::POINTER_RANGE).round() as i32;
+        let dy = ((y - last_y) * Self::POINTER_RANGE).round() as i32;
+        if dx == 0 && dy == 0 {
+            return;
+        }
+        let mut new_last = last;
+        if dx != 0 {
+            new_last.x = self.state.pos.x;
+        }
+        if dy != 0 {
+            new_last.y = self.state.pos.y;
+        }
+        self.state.pointer_pos = Some(new_last);
+        // the touchpad's Y axis points down, like the screen's
+        self.dummy_kb.move_pointer(dx, dy);
+    }
+
+    /// Step the brightness for every [`Self::BRIGHTNESS_ADJUST_STEP`] the finger moved up or down.
+    fn on_brightness_adjust(&mut self) {
+        let step = self
+            .layout
+            .height_fraction(Self::BRIGHTNESS_ADJUST_STEP)
+            .max(1);
+        // moving up makes it brighter
+        let steps = (self.state.brightness_adjust_y - self.state.pos.y) / step;
+        if steps == 0 {
+            return;
+        }
+        self.state.brightness_adjust_y = self.state.pos.y;
+        let brightness = self.state.brightness.step(steps);
+        if brightness != self.state.brightness && self.set_led(brightness) {
+            info!("Drag - set brightness to {}", brightness);
+            self.state.brightness = brightness;
+            self.state.brightness_percent = None;
+            self.state.night = false;
+        }
+    }
+
+    /// If the finger was dragged onto a different key, release the old key and press the new one.
+    ///
+    /// Keeps the old key pressed if finger is dragged into the margins.
+    fn on_drag(&mut self) {
+        if let CurKey::Numpad(cur_key) = self.state.cur_key {
+            match self.key_at(self.state.pos) {
+                Some(key) if key != cur_key && !self.is_palm() => {
+                    debug!("Dragged from {:?} to {:?}", cur_key, key);
+                    self.numpad_keyup(cur_key);
+                    self.numpad_keydown(key);
+                    self.state.cur_key = CurKey::Numpad(key);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Release the key that the finger left, like a physical button would be.
+    ///
+    /// With `drag_to_new_key`, the key that the finger moved onto is pressed instead, as in
+    /// [`Self::on_drag`]. Moving from the margins back onto a key doesn't press it.
+    fn on_leave_key(&mut self) {
+        if let CurKey::Numpad(cur_key) = self.state.cur_key {
+            debug!("Left {:?}", cur_key);
+            self.numpad_keyup(cur_key);
+            self.state.cur_key.reset();
+            if self.config.drag_to_new_key() && !self.is_palm() {
+                if let Some(key) = self.key_at(self.state.pos) {
+                    self.numpad_keydown(key);
+                    self.state.cur_key = CurKey::Numpad(key);
+                }
+            }
+        }
+    }
+
+    /// Press the swipe's key once the finger moved far enough from the corner.
+    ///
+    /// Swipes need to be quick, so they are forgotten once the finger is held for
+    /// `hold_duration_ms`.
+    fn on_corner_swipe_move(&mut self, time: TimeVal) {
+        let swipe = match self.state.corner_swipe {
+            Some(swipe) => swipe,
+            None => return,
+        };
+        if time.elapsed_since(self.state.tap_started_at) >= self.config.hold_duration() {
+            self.state.corner_swipe = None;
+            return;
+        }
+        let distance = self.layout.corner_swipe_distance(
+            swipe.corner,
+            self.state.tap_start_pos,
+            self.state.pos,
+        );
+        if distance >= self.config.corner_swipe_distance() {
+            debug!(
+                "Swipe from {:?} corner - press {:?}",
+                swipe.corner, swipe.key
+            );
+            self.dummy_kb.keypress(swipe.key);
+            self.state.corner_swipe = None;
+            self.ignore_rest_of_touch();
+        }
+    }
+
+    /// Turn numlock off if no numpad key was pressed for the configured duration.
+    ///
+    /// The duration is counted from when numlock was turned on, or the last key press since. A
+    /// key that is held down keeps it on. Returns true if numlock was turned off.
+    fn auto_disable(&mut self, time: TimeVal) -> Result<bool> {
+        match self.auto_disable_timeout(time) {
+            Some(timeout) if timeout.is_zero() => {
+                info!("No numpad key pressed for a while, turning numlock off");
+                self.toggle_numlock(time)?;
+                self.ignore_rest_of_touch();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// How long after `time` [`Self::auto_disable`] turns numlock off, if it does.
+    fn auto_disable_timeout(&self, time: TimeVal) -> Option<Duration> {
+        let timeout = self.config.auto_disable_duration()?;
+        if !self.state.numlock || matches!(self.state.cur_key, CurKey::Numpad(_)) {
+            return None;
+        }
+        let idle = time.elapsed_since(self.state.last_key_at?);
+        Some(Duration::from(timeout).saturating_sub(idle.into()))
+    }
+
+    /// Don't let the rest of the current touch press anything or toggle numlock.
+    ///
+    /// Needs to be called before a key is pressed, since the key isn't released.
+    fn ignore_rest_of_touch(&mut self) {
+        self.state.finger_state = FingerState::TouchStart;
+        self.state.tapped_outside_numlock_bbox = true;
+        self.state.cur_key.reset();
+    }
+
+    /// Handle the end of a tap, releasing the key being pressed.
+    fn on_finger_up(&mut self) {
+        debug!("End tap");
+        if self.state.cur_key == CurKey::Calc
+            && self.config.enable_calc_key()
+            && self.state.pos.dist_sq(self.state.tap_start_pos) >= Self::CALC_DRAG_DIST
+        {
+            self.toggle_calc();
+        }
+
+        if self.state.finger_state == FingerState::Touching {
+            match self.state.cur_key {
+                CurKey::Numpad(key) => {
+                    self.numpad_keyup(key);
+                    // if we ungrab here, it causes the pointer to jump
+                    // so we only ungrab when finger is dragged
+                }
+                // a short tap in the layer corner presses its key
+                CurKey::LayerCorner if !self.state.finger_dragged_too_much => {
+                    if let Some(key) = self.key_at(self.state.tap_start_pos) {
+                        self.numpad_keydown(key);
+                        self.numpad_keyup(key);
+                    }
+                }
+                // a tap in the numlock bbox that was too short to toggle types the key under it
+                CurKey::Numlock if self.state.numlock && !self.state.finger_dragged_too_much => {
+                    match self.state.numlock_tap_key.take() {
+                        Some(key) if self.config.toggle_mode().allows_double_tap() => {
+                            debug!(
+                                "Short tap in numlock - pressing {:?} unless tapped again",
+                                key
+                            );
+                            self.state.pending_tap_key = Some((key, self.state.tap_started_at));
+                        }
+                        Some(key) => {
+                            debug!("Short tap in numlock - pressing {:?}", key);
+                            self.numpad_keydown(key);
+                            self.numpad_keyup(key);
+                        }
+                        None => (),
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.state.cur_key.reset();
+        self.state.corner_swipe = None;
+        self.state.finger_state = FingerState::Lifted;
+    }
+
+    /// Handle the finger being lifted, as reported by `BTN_TOOL_FINGER` or the tracking ID.
+    ///
+    /// Touchpads usually report both, and the second time doesn't release anything again.
+    fn on_lift(&mut self, time: TimeVal) {
+        self.state.suppress_until_lift = false;
+        self.state.pointer_pos = None;
+        if !self.state.finger_dragged_too_much {
+            // only call on_finger_up if we did not already call it as a result of
+            // finger drag
+            self.on_finger_up();
+        } else {
+            self.state.dragged_finger_lifted_at = time;
+        }
+        if let Some(pulses) = self.state.pending_notify.take() {
+            self.notify(pulses);
+        }
+    }
+
+    /// Whether a touch starting at `time` is the second tap of a double tap in the numlock bbox.
+    fn is_double_tap(&self, time: TimeVal) -> bool {
+        time.elapsed_since(self.state.last_numlock_tap_at) <= self.config.double_tap_duration()
+            && self.layout.in_numlock_activation_bbox(self.state.pos)
+    }
+
+    /// Type the key of a short tap in the numlock bbox, once it's too late for a second tap.
+    fn type_pending_tap_key(&mut self, time: TimeVal) {
+        let (key, tap_started_at) = match self.state.pending_tap_key {
+            Some(pending) => pending,
+            None => return,
+        };
+        if !self.state.numlock {
+            self.state.pending_tap_key = None;
+        } else if time.elapsed_since(tap_started_at) > self.config.double_tap_duration() {
+            debug!("No double tap - pressing {:?}", key);
+            self.state.pending_tap_key = None;
+            self.numpad_keydown(key);
+            self.numpad_keyup(key);
+        }
+    }
+
+    /// Whether touches in the calc bbox do anything, with the features enabled in the config.
+    ///
+    /// Holding it cycles the brightness while numlock is on, and the calc key works either way.
+    fn uses_calc_bbox(&self) -> bool {
+        self.config.enable_calc_key()
+            || (self.state.numlock && self.config.enable_brightness_cycle())
+    }
+
+    /// Handle a finger touching the touchpad.
+    ///
+    /// Also called while the finger is down, after it was moved too much.
+    fn on_finger_down(&mut self, time: TimeVal) -> Result<()> {
+        let tap_started = self.state.finger_state == FingerState::Lifted;
+        if tap_started {
+            // start of tap
+            debug!("Start tap");
+            self.state.finger_state = FingerState::TouchStart;
+            self.state.tap_started_at = time;
+            self.state.tap_start_pos = self.state.pos;
+            self.state.tapped_outside_numlock_bbox = false;
+            self.state.finger_dragged_too_much = false;
+            self.state.numlock_tap_key = None;
+            // the first tap of a double tap doesn't type, and other touches type after it
+            if let Some((key, _)) = self.state.pending_tap_key.take() {
+                if self.is_double_tap(time) {
+                    debug!("Double tap - not pressing the key of the first tap");
+                } else if self.state.numlock {
+                    debug!("No double tap - pressing {:?}", key);
+                    self.numpad_keydown(key);
+                    self.numpad_keyup(key);
+                }
+            }
+            // check before the touch can press a key
+            if self.auto_disable(time)? {
+                return Ok(());
+            }
+            // swipes would get in the way of the keys in the corners
+            self.state.corner_swipe = if self.state.numlock {
+                None
+            } else {
+                let pos = self.state.pos;
+                let layout = &self.layout;
+                self.config
+                    .corner_swipes()
+                    .iter()
+                    .find(|swipe| layout.in_corner(swipe.corner, pos))
+                    .copied()
+            };
+            if self.is_palm() {
+                debug!("Palm detected, ignoring touch");
+                if self.config.palm_blocks_toggle() {
+                    // treat it like a touch outside the numlock and calc bboxes
+                    self.state.tapped_outside_numlock_bbox = true;
+                    return Ok(());
+                }
+            } else if self.state.numlock
+                && self.config.brightness_slide()
+                && self.layout.in_top_edge_bbox(self.state.pos)
+            {
+                debug!("In top edge - start brightness slide");
+                if !self.grab_or_disable()? {
+                    return Ok(());
+                }
+                self.state.finger_state = FingerState::Touching;
+                self.state.cur_key = CurKey::BrightnessSlide;
+            } else if self.state.numlock && self.typing(time) {
+                debug!("Typing on the keyboard, ignoring touch");
+            } else if self.state.numlock && self.in_layer_corner() {
+                debug!("In layer corner - start");
+                if !self.grab_or_disable()? {
+                    return Ok(());
+                }
+                self.state.finger_state = FingerState::Touching;
+                self.state.cur_key = CurKey::LayerCorner;
+            } else if self.state.numlock && self.layout.in_numlock_bbox(self.state.pos) {
+                // a hold toggles numlock instead, so only type the key once we know
+                self.state.numlock_tap_key = if self.in_activation_grace(time) {
+                    debug!("Numlock was just toggled on, not typing the key under numlock");
+                    None
+                } else {
+                    self.key_at(self.state.pos)
+                };
+                if self.state.numlock_tap_key.is_some() && !self.grab_or_disable()? {
+                    return Ok(());
+                }
+            } else if self.state.numlock && self.pressed_hard_enough() {
+                self.press_key_at_pos(time)?;
+            }
+        }
+        if self.layout.in_numlock_bbox(self.state.pos) {
+            debug!("In numlock - start");
+            self.state.finger_state = FingerState::Touching;
+            self.state.cur_key = CurKey::Numlock;
+            if tap_started && self.config.toggle_mode().allows_double_tap() {
+                if self.is_double_tap(time) {
+                    debug!("Double tap - toggle numlock");
+                    self.toggle_numlock_by_gesture(time)?;
+                    // don't let a third tap count as another double tap
+                    self.state.last_numlock_tap_at = TimeVal {
+                        tv_sec: 0,
+                        tv_usec: 0,
+                    };
+                    // prevent the hold from toggling again
+                    self.state.finger_state = FingerState::TouchStart;
+                } else {
+                    self.state.last_numlock_tap_at = time;
+                }
+            }
+        } else {
+            if self.uses_calc_bbox() && self.layout.in_calc_bbox(self.state.pos) {
+                debug!("In calc - start");
+                self.state.finger_state = FingerState::Touching;
+                self.state.cur_key = CurKey::Calc;
+            }
+            self.state.tapped_outside_numlock_bbox = true
+        }
+        Ok(())
+    }
+
+    fn handle_touchpad_event(&mut self, ev: InputEvent) -> Result<()> {
+        // TODO: Double-taps when numpad is active should not be propagated.
+        //       Need to grab/ungrab the device intelligently.
+
+        // no need to trace timestamp events - too noisy
+        if !matches!(ev.event_code, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP)) {
+            trace!("TP {:?} {}", ev.event_code, ev.value);
+        }
+        match ev.event_code {
+            EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT) => {
+                self.state.contacts.set_slot(ev.value);
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID) => {
+                let holding = self.state.contacts.has_holder();
+                let was_primary = self.state.contacts.is_primary();
+                self.state.contacts.set_tracking_id(ev.value);
+                if let Some(pos) = self.state.contacts.primary_pos() {
+                    self.state.pos = pos;
+                }
+                if holding {
+                    self.on_momentary_contact(ev.value, was_primary, ev.time)?;
+                } else if was_primary && ev.value < 0 {
+                    // some touchpads only report the lift through the tracking ID
+                    self.on_lift(ev.time);
+                }
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X) => {
+                let x = self.layout.flip_x(ev.value);
+                self.state.contacts.set_x(x);
+                if self.state.contacts.is_primary() {
+                    self.state.pos.x = x;
+                }
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y) => {
+                let y = self.layout.flip_y(ev.value);
+                self.state.contacts.set_y(y);
+                if self.state.contacts.is_primary() {
+                    self.state.pos.y = y;
+                }
+            }
+            // ignore the size and pressure of the other fingers
+            EventCode::EV_ABS(
+                EV_ABS::ABS_MT_TOUCH_MAJOR | EV_ABS::ABS_MT_WIDTH_MAJOR | EV_ABS::ABS_MT_PRESSURE,
+            ) if !self.state.contacts.is_primary() => {}
+            EventCode::EV_ABS(EV_ABS::ABS_MT_TOUCH_MAJOR) => {
+                self.state.touch_major = ev.value;
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_WIDTH_MAJOR) => {
+                self.state.width_major = ev.value;
+            }
+            EventCode::EV_ABS(EV_ABS::ABS_MT_PRESSURE) => {
+                self.state.pressure = Some(ev.value);
+                // a light touch that got pressed down without moving counts as a key press
+                if self.state.numlock
+                    && self.state.finger_state == FingerState::TouchStart
+                    && self.state.cur_key == CurKey::None
+                    && self.state.tap_start_pos.dist_sq(self.state.pos) <= Self::TAP_JITTER_DIST
+                    && self.pressed_hard_enough()
+                    && !self.is_palm()
+                    && !self.typing(ev.time)
+                {
+                    debug!("Pressed hard enough");
+                    self.press_key_at_pos(ev.time)?;
+                }
+            }
+            // the taps follow the tracking IDs instead
+            EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER) if self.state.contacts.has_holder() => {}
+            EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER) if ev.value == 0 => self.on_lift(ev.time),
+            EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER)
+                if ev.value == 1
+                    && (!self.state.finger_dragged_too_much
+                        || ev.time.elapsed_since(self.state.dragged_finger_lifted_at)
+                            >= self.config.hold_duration()) =>
+            {
+                self.on_finger_down(ev.time)?;
+            }
+            EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP) => {
+                // The toggle should happen automatically after HOLD_DURATION, even if user is
+                // still touching the numpad bbox.
+                if self.state.finger_state == FingerState::TouchStart {
+                    trace!("Touch {}", self.state.pos);
+                }
+                if let CurKey::Numpad(_) = self.state.cur_key {
+                    self.state.last_key_at = Some(ev.time);
+                } else {
+                    self.auto_disable(ev.time)?;
+                }
+
+                if self.state.finger_state == FingerState::Touching
+                    && !self.state.tapped_outside_numlock_bbox
+                    && !self.state.contacts.has_holder()
+                    && self.config.toggle_mode().allows_hold()
+                {
+                    if self.moved_during_hold() {
+                        debug!("Moved during the hold - not toggling numlock");
+                        self.state.tapped_outside_numlock_bbox = true;
+                    } else if self.layout.in_numlock_activation_bbox(self.state.pos) {
+                        if ev.time.elapsed_since(self.state.tap_started_at)
+                            >= self.config.hold_duration()
+                        {
+                            debug!("Hold finish - toggle numlock");
+                            self.on_numlock_hold(ev.time)?;
+                        }
+                    } else if !self.layout.in_numlock_bbox(self.state.pos) {
+                        self.state.tapped_outside_numlock_bbox = true;
+                    }
+                    // in the margin of the numlock bbox, neither toggle nor cancel
+                }
+                if self.state.cur_key == CurKey::LayerCorner
+                    && ev.time.elapsed_since(self.state.tap_started_at)
+                        >= self.config.hold_duration()
+                {
+                    self.cycle_layer();
+                    self.ignore_rest_of_touch();
+                }
+                if self.state.numlock
+                    && self.state.cur_key == CurKey::Calc
+                    && self.config.enable_brightness_cycle()
+                    && self.layout.in_calc_bbox(self.state.pos)
+                    && ev.time.elapsed_since(self.state.tap_started_at)
+                        >= self.config.hold_duration()
+                {
+                    debug!("Hold finish - cycle brightness");
+                    self.cycle_brightness();
+                    self.state.cur_key.reset();
+                }
+                if !self.state.numlock
+                    && self.config.calc_on_hold()
+                    && self.config.enable_calc_key()
+                    && self.state.cur_key == CurKey::Calc
+                    && self.layout.in_calc_bbox(self.state.pos)
+                    && ev.time.elapsed_since(self.state.tap_started_at)
+                        >= self.config.hold_duration()
+                {
+                    debug!("Hold finish - toggle calc");
+                    self.toggle_calc();
+                    // prevent the calc from being triggered again on lift
+                    self.state.cur_key.reset();
+                }
+            }
+            EventCode::EV_KEY(tool) if Some(tool) == self.config.multi_finger_toggle_tool() => {
+                if ev.value == 1 {
+                    self.state.multi_finger_tap_started_at = ev.time;
+                    self.state.multi_finger_tap_start_pos = self.state.pos;
+                } else if ev.value == 0
+                    && ev
+                        .time
+                        .elapsed_since(self.state.multi_finger_tap_started_at)
+                        < self.config.hold_duration()
+                    && self
+                        .state
+                        .multi_finger_tap_start_pos
+                        .dist_sq(self.state.pos)
+                        <= Self::TAP_JITTER_DIST
+                {
+                    debug!("Multi finger tap - toggle numlock");
+                    self.toggle_numlock_by_gesture(ev.time)?;
+                }
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) if self.state.momentary_tap_pending => {
+                self.state.momentary_tap_pending = false;
+                self.on_finger_down(ev.time)?;
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) if self.state.corner_swipe.is_some() => {
+                self.on_corner_swipe_move(ev.time);
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) if self.left_key() => {
+                self.on_leave_key();
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) if self.dragging_on_numpad() => {
+                // only check once both X and Y of this frame have been updated
+                self.on_drag();
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT)
+                if self.state.cur_key == CurKey::BrightnessSlide =>
+            {
+                self.on_brightness_slide()?;
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT)
+                if self.state.cur_key == CurKey::BrightnessAdjust =>
+            {
+                self.on_brightness_adjust();
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) if self.state.pointer_pos.is_some() => {
+                self.on_pointer_move();
+            }
+            _ => (),
+        }
+        if ev.event_code == EventCode::EV_SYN(EV_SYN::SYN_REPORT)
+            && self.state.finger_state != FingerState::Lifted
+        {
+            let (x, y) = self.layout.normalize(self.state.pos);
+            self.emit(ProtocolEvent::Pos(x, y));
+        }
+
+        self.on_move();
+        Ok(())
+    }
+
+    /// Stop the tap if the finger dragged too much, unless it's dragging something.
+    ///
+    /// The touch then moves the pointer, and starts a new tap after `hold_duration_ms`. With
+    /// `pointer_while_numlock`, the touchpad stays grabbed and the motion is forwarded instead,
+    /// also for touches that didn't press a key.
+    fn on_move(&mut self) {
+        // TODO: Use the same logic for numlock bbox instead of `tapped_outside_numlock_bbox`
+        if self.state.numlock
+            && self.state.finger_state == FingerState::Touching
+            && self.state.cur_key != CurKey::Calc // we are fine if finger drags on calc box
+            && self.state.cur_key != CurKey::BrightnessSlide
+            && !self.dragging_on_numpad()
+            && self.state.tap_start_pos.dist_sq(self.state.pos) > Self::TAP_JITTER_DIST
+        {
+            debug!("Moved too much");
+            self.state.finger_dragged_too_much = true;
+            if self.config.pointer_while_numlock() {
+                self.on_finger_up();
+                self.state.pointer_pos = Some(self.state.pos);
+            } else {
+                self.ungrab();
+                self.on_finger_up();
+            }
+        } else if self.config.pointer_while_numlock()
+            && self.state.numlock
+            && self.state.finger_state == FingerState::TouchStart
+            && self.state.cur_key == CurKey::None
+            && self.state.pointer_pos.is_none()
+            && self.state.tap_start_pos.dist_sq(self.state.pos) > Self::TAP_JITTER_DIST
+        {
+            debug!("Moved outside the keys - move the pointer");
+            self.state.pointer_pos = Some(self.state.pos);
+        }
+    }
+
+    /// The touchpad forgets the LED brightness during suspend, so set it again.
+    fn on_resume(&mut self) {
+        info!("Resumed from suspend");
+        self.state.led = None;
+        if self.state.numlock {
+            self.light_up();
+        }
+    }
+
+    /// Handle all pending events from the touchpad.
+    ///
+    /// Returns false if the touchpad was disconnected.
+    ///
+    /// Other read errors are retried after a short backoff, so that an fd stuck in an error
+    /// state doesn't spin the CPU. After [`Self::MAX_READ_ERRORS`] in a row, the touchpad is
+    /// treated as disconnected, so that it's reopened.
+    fn read_touchpad_events(&mut self) -> Result<bool> {
+        loop {
+            match self.evdev.next_event() {
+                Ok(ev) => {
+                    self.state.read_errors = 0;
+                    self.handle_touchpad_event(ev)?;
+                }
+                // no more events
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(true),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(err) if err.raw_os_error() == Some(libc::ENODEV) => {
+                    // release the key that might be pressed
+                    self.on_finger_up();
+                    return Ok(false);
+                }
+                Err(err) => {
+                    self.state.read_errors += 1;
+                    warn!(
+                        "Failed to read touchpad events ({:?}, {} in a row): {}",
+                        err.kind(),
+                        self.state.read_errors,
+                        err
+                    );
+                    if self.state.read_errors >= Self::MAX_READ_ERRORS {
+                        self.state.read_errors = 0;
+                        self.on_finger_up();
+                        return Ok(false);
+                    }
+                    std::thread::sleep(Self::READ_ERROR_BACKOFF * self.state.read_errors);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    fn handle_control_command(&mut self, cmd: ControlCommand) -> Result<()> {
+        debug!("Control command {:?}", cmd);
+        match cmd {
+            ControlCommand::Toggle => self.toggle_numlock(now())?,
+            ControlCommand::On if !self.state.numlock => self.toggle_numlock(now())?,
+            ControlCommand::Off if self.state.numlock => self.toggle_numlock(now())?,
+            ControlCommand::Brightness(brightness) => {
+                // keep the old brightness if the LED couldn't be set
+                if self.state.numlock && !self.set_led(brightness) {
+                    return Err(anyhow!("Couldn't set the brightness to {}", brightness));
+                }
+                self.state.brightness = brightness;
+                self.state.brightness_percent = None;
+                self.state.night = false;
+            }
+            ControlCommand::Notify(pulses) => self.notify(pulses),
+            // handled by the control socket, since applying the config needs the actual devices
+            ControlCommand::Profile(_) => (),
+            ControlCommand::On
+            | ControlCommand::Off
+            | ControlCommand::Status
+            | ControlCommand::StatusJson => (),
+        }
+        Ok(())
+    }
+
+    /// Write the numlock state and brightness to the state file, if they changed.
+    fn save_state(&mut self) {
+        if !self.config.persist_state() {
+            return;
+        }
+        let state = SavedState {
+            numlock: self.state.numlock,
+            brightness: self.state.brightness,
+        };
+        if self.saved_state == Some(state) {
+            return;
+        }
+        match state.save() {
+            Ok(()) => self.saved_state = Some(state),
+            Err(err) => warn!("Failed to save state: {:#}", err),
+        }
+    }
+
+    /// Write the numlock state and brightness to the status files, if enabled and they changed.
+    fn update_status_files(&mut self) {
+        if !self.config.status_files() {
+            return;
+        }
+        let brightness = match self.state.brightness_percent {
+            Some(percent) => format!("{}%", percent),
+            None => self.state.brightness.to_string(),
         };
-        let kb_fd = libc::pollfd {
-            fd: self.keyboard_evdev.file().as_raw_fd(),
+        if let Err(err) = self.status_files.update(self.state.numlock, brightness) {
+            warn!("Failed to update status files: {:#}", err);
+        }
+    }
+
+    /// Restore the touchpad to a usable state before exiting.
+    ///
+    /// Called on drop, so that this happens however the daemon exits: on a signal, on an error,
+    /// or when unwinding after a panic.
+    fn shutdown(&mut self) {
+        self.save_state();
+        self.status_files.remove();
+        // right away, since there's no event loop to fade in anymore
+        self.state.led_steps.clear();
+        self.write_led(Brightness::Zero);
+        self.ungrab();
+    }
+}
+
+impl<S: EventSource, K: KeyboardSink, L: LedControl> Drop for Numpad<S, K, L> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Parts that need the actual hardware.
+impl Numpad {
+    /// Query the initial state of numlock led from the system.
+    fn initialize_numlock(&mut self) -> Result<()> {
+        let init_numlock = self
+            .keyboard_evdev
+            .event_value(&EventCode::EV_LED(EV_LED::LED_NUML));
+        match init_numlock {
+            Some(init_numlock) if self.saved_state.is_some() => {
+                let saved_numlock = self.saved_state.map_or(false, |saved| saved.numlock);
+                match (saved_numlock, init_numlock != 0) {
+                    (true, false) => {
+                        info!("Restoring numlock from the last run");
+                        self.toggle_numlock(now())?;
+                    }
+                    (false, true) => self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK),
+                    (true, true) => self.handle_numlock_pressed(init_numlock, now()),
+                    (false, false) => (),
+                }
+            }
+            Some(init_numlock) => {
+                if init_numlock != 0 {
+                    if self.config.disable_numlock_on_start() {
+                        self.dummy_kb.keypress(EV_KEY::KEY_NUMLOCK);
+                    } else if self.config.sync_numlock() {
+                        self.handle_numlock_pressed(init_numlock, now());
+                    }
+                }
+            }
+            None => error!(
+                "Failed to get initial numlock state. \
+                There might be something wrong with evdev keyboard detection. \
+                {}",
+                self.keyboard_evdev.name().map_or_else(
+                    || "Unknown device".to_owned(),
+                    |n| format!("Using device: {}", n)
+                )
+            ),
+        }
+        Ok(())
+    }
+
+    /// How often to look for the touchpad after it gets disconnected.
+    const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// How often to check that the touchpad is still grabbed, while numlock is active.
+    ///
+    /// The loop wakes up every [`Self::RESUME_CHECK_INTERVAL`] then, so checks happen at most
+    /// that often.
+    const GRAB_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// How often to look for an external mouse, with `disable_on_external_mouse`.
+    ///
+    /// Like [`Self::GRAB_CHECK_INTERVAL`], this only happens as often while numlock is active.
+    const MOUSE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// How often to check whether the night started or ended.
+    ///
+    /// Like [`Self::GRAB_CHECK_INTERVAL`], this depends on the loop waking up, which it does
+    /// periodically only while numlock is active.
+    const NIGHT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+    /// How often to check whether the system resumed from suspend, while numlock is active.
+    const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// How often to check whether a tap in the numlock bbox can still become a double tap.
+    const PENDING_TAP_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Try to re-open the touchpad after it was disconnected.
+    ///
+    /// Returns true if the touchpad is back.
+    fn reconnect_touchpad(&mut self) -> Result<bool> {
+        let (_, touchpad_ev_id, i2c_id) = match read_proc_input(self.config.touchpad_name()) {
+            Ok(ids) => ids,
+            Err(err) => {
+                debug!("Touchpad still missing: {:#}", err);
+                return Ok(false);
+            }
+        };
+        let evdev = match open_input_evdev(touchpad_ev_id) {
+            Ok(evdev) => evdev,
+            Err(err) => {
+                debug!("Touchpad still missing: {:#}", err);
+                return Ok(false);
+            }
+        };
+        self.evdev = evdev;
+        self.state.grabbed = false;
+        self.touchpad_i2c = if self.config.dry_run() {
+            TouchpadI2C::dry_run(i2c_id)
+        } else {
+            TouchpadI2C::new_or_unavailable(
+                i2c_id,
+                self.config.i2c_addrs(),
+                self.layout.led_command().clone(),
+            )
+        };
+        self.state.finger_state = FingerState::Lifted;
+        self.state.cur_key.reset();
+        self.state.led = None;
+        info!("Touchpad reconnected");
+        // the touchpad forgets the brightness
+        if self.state.numlock {
+            self.light_up();
+        }
+        Ok(true)
+    }
+
+    fn process(&mut self) -> Result<()> {
+        if self.config.self_test() {
+            self.self_test();
+        }
+        self.initialize_numlock()?;
+        #[cfg(feature = "dbus")]
+        if let Some(bus) = self.config.dbus() {
+            self.dbus = Some(DbusService::start(bus)?);
+        }
+
+        // indices of the sources in `fds`. More can be added after the control socket, as long
+        // as the optional ones are replaced with -1 instead of left out.
+        const TOUCHPAD: usize = 0;
+        const KEYBOARD: usize = 1;
+        const CONTROL: usize = 2;
+        #[cfg(feature = "dbus")]
+        const DBUS: usize = 3;
+        let pollfd = |fd| libc::pollfd {
+            fd,
             events: libc::POLLIN,
             revents: 0,
         };
-        let mut fds = [tp_fd, kb_fd];
+        let mut fds = vec![
+            pollfd(self.evdev.file().as_raw_fd()),
+            pollfd(self.keyboard_evdev.file().as_raw_fd()),
+            // negative fds are ignored by poll
+            pollfd(
+                self.control
+                    .as_ref()
+                    .map_or(-1, |control| control.as_raw_fd()),
+            ),
+        ];
+        #[cfg(feature = "dbus")]
+        fds.push(pollfd(
+            self.dbus.as_ref().map_or(-1, |dbus| dbus.as_raw_fd()),
+        ));
+        let wait_mask = signals::install_handlers()?;
+        let mut touchpad_connected = true;
+        let mut suspend_detector = SuspendDetector::new();
+        let mut last_grab_check = Instant::now();
+        let mut last_night_check = Instant::now();
+        let mut last_mouse_check: Option<Instant> = None;
+        let mut was_night = self.is_night();
+        systemd::notify("READY=1");
 
         loop {
-            match unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } {
-                0 => (), // timeout, TODO: disable numpad if idle (no touches) for 1 minute
-                1 | 2 => {
-                    if fds[0].revents & libc::POLLIN != 0 {
-                        // read until no more events
-                        while let Ok((_, ev)) = self.evdev.next_event(ReadFlag::NORMAL) {
-                            self.handle_touchpad_event(ev)?;
-                        }
+            if signals::shutdown_requested() {
+                info!("Received signal, shutting down");
+                break;
+            }
+            if signals::take_reload_request() {
+                self.reload_config()?;
+            }
+            if suspend_detector.resumed() {
+                self.on_resume();
+            }
+            if touchpad_connected && last_grab_check.elapsed() >= Self::GRAB_CHECK_INTERVAL {
+                last_grab_check = Instant::now();
+                self.check_grab();
+            }
+            if self.config.disable_on_external_mouse()
+                && last_mouse_check
+                    .map_or(true, |last| last.elapsed() >= Self::MOUSE_CHECK_INTERVAL)
+            {
+                last_mouse_check = Some(Instant::now());
+                match find_external_mouse() {
+                    Ok(mouse) => self.on_external_mouse(mouse)?,
+                    Err(err) => warn!("Couldn't look for an external mouse: {:#}", err),
+                }
+            }
+            if last_night_check.elapsed() >= Self::NIGHT_CHECK_INTERVAL {
+                last_night_check = Instant::now();
+                let night = self.is_night();
+                // enabling numlock picks the brightness itself, so only night changes matter
+                if night != was_night {
+                    info!("Night {}", if night { "started" } else { "ended" });
+                    was_night = night;
+                    self.state.night = night;
+                    if self.state.numlock {
+                        self.light_up();
                     }
-                    if fds[1].revents & libc::POLLIN != 0 {
+                }
+            }
+            self.run_led_steps(Instant::now());
+            self.type_pending_tap_key(now());
+            self.auto_disable(now())?;
+            self.save_state();
+            self.update_status_files();
+            #[cfg(feature = "metrics")]
+            self.update_metrics();
+            let nfds = fds.len() as libc::nfds_t;
+            let timeout = [
+                // while the touchpad is gone, wake up periodically to check if it came back
+                Some(Self::RECONNECT_INTERVAL).filter(|_| !touchpad_connected),
+                // wake up to type the key once the double tap can't happen anymore
+                self.state
+                    .pending_tap_key
+                    .map(|_| Self::PENDING_TAP_INTERVAL),
+                // wake up periodically to check if we resumed from suspend
+                Some(Self::RESUME_CHECK_INTERVAL).filter(|_| self.state.numlock),
+                // and when the next LED change is due, or the numpad was idle for too long
+                self.led_timeout(Instant::now()),
+                self.auto_disable_timeout(now()),
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|timeout| libc::timespec {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as _,
+            });
+            let timeout = timeout
+                .as_ref()
+                .map_or(std::ptr::null(), |timeout| timeout as *const libc::timespec);
+            match unsafe { libc::ppoll(fds.as_mut_ptr(), nfds, timeout, &wait_mask) } {
+                -1 => {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(err).context("Failed to poll for events");
+                    }
+                }
+                0 => {
+                    // timeout. What's due is handled at the start of the loop
+                    if !touchpad_connected && self.reconnect_touchpad()? {
+                        touchpad_connected = true;
+                        fds[TOUCHPAD].fd = self.evdev.file().as_raw_fd();
+                    }
+                }
+                _ => {
+                    if fds[TOUCHPAD].revents & (libc::POLLIN | libc::POLLERR | libc::POLLHUP) != 0
+                        && !self.read_touchpad_events()?
+                    {
+                        warn!("Touchpad disconnected, waiting for it to come back");
+                        touchpad_connected = false;
+                        fds[TOUCHPAD].fd = -1;
+                    }
+                    if fds[KEYBOARD].revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                        warn!("Keyboard disconnected, can't sync with system numlock anymore");
+                        fds[KEYBOARD].fd = -1;
+                    } else if fds[KEYBOARD].revents & libc::POLLIN != 0 {
                         while let Ok((_, ev)) = self.keyboard_evdev.next_event(ReadFlag::NORMAL) {
                             // Note: We only listen to the LED event, and not the numlock event.
                             // While most environments keep them in sync, it is technically possible
@@ -499,39 +2090,437 @@ impl Numpad {
                             // and query it to get the numlock state.
                             //
                             // So, we only listen for LED changes, hoping that it reflects numlock state
-                            if let EventCode::EV_LED(EV_LED::LED_NUML) = ev.event_code {
-                                self.handle_numlock_pressed(ev.value)?;
+                            match ev.event_code {
+                                EventCode::EV_LED(EV_LED::LED_NUML)
+                                    if self.config.sync_numlock() =>
+                                {
+                                    self.handle_numlock_pressed(ev.value, ev.time);
+                                }
+                                EventCode::EV_KEY(key) => {
+                                    self.on_keyboard_key(key, ev.value, ev.time)?;
+                                }
+                                _ => (),
                             }
                             trace!("KB {}, {}", ev.event_code, ev.value);
                         }
                     }
+                    if fds[CONTROL].revents & libc::POLLIN != 0 {
+                        self.handle_control_clients()?;
+                    }
+                    #[cfg(feature = "dbus")]
+                    if fds[DBUS].revents & libc::POLLIN != 0 {
+                        self.handle_dbus_requests();
+                    }
                 }
-                // we have only given 2 fds, so max return val of poll can be 2
-                _ => unsafe { unreachable_unchecked() },
             }
         }
+        systemd::notify("STOPPING=1");
+        Ok(())
+    }
+
+    /// Run the commands sent by all pending clients of the control socket.
+    fn handle_control_clients(&mut self) -> Result<()> {
+        while let Some(stream) = self.control.as_ref().and_then(ControlSocket::accept) {
+            for line in BufReader::new(&stream).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(err) => {
+                        debug!("Stopped reading control client: {}", err);
+                        break;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let response = match line.parse() {
+                    Ok(ControlCommand::Profile(name)) => match self.switch_profile(name.as_deref())
+                    {
+                        Ok(()) => self.status(&self.touchpad_path()).to_line(),
+                        Err(err) => format!("error: {:#}", err),
+                    },
+                    Ok(cmd) => {
+                        let json = cmd == ControlCommand::StatusJson;
+                        match self.handle_control_command(cmd) {
+                            Ok(()) => {
+                                let touchpad = self.touchpad_path();
+                                let status = self.status(&touchpad);
+                                if json {
+                                    status.to_json()
+                                } else {
+                                    status.to_line()
+                                }
+                            }
+                            Err(err) => format!("error: {:#}", err),
+                        }
+                    }
+                    Err(err) => format!("error: {}", err),
+                };
+                if let Err(err) = writeln!(&stream, "{}", response) {
+                    debug!("Unable to respond to control client: {}", err);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of the touchpad evdev node.
+    fn touchpad_path(&self) -> String {
+        self.evdev
+            .path()
+            .map_or_else(|| "unknown".to_owned(), |path| path.display().to_string())
+    }
+
+    /// Current state of the numpad, as reported to control clients.
+    fn status<'a>(&'a self, touchpad: &'a str) -> Status<'a> {
+        Status {
+            numlock: self.state.numlock,
+            brightness: self.state.brightness,
+            layout: self.layout.name(),
+            touchpad,
+            i2c_id: self.touchpad_i2c.i2c_id(),
+            i2c_errors: self.touchpad_i2c.errors(),
+            layer: self.state.active_layer,
+            toggles: self.state.toggles,
+            keys_pressed: self.state.keys_pressed,
+            profile: self.config.profile(),
+        }
+    }
+
+    /// Write the metrics file, if `metrics_file` is set and the metrics changed.
+    #[cfg(feature = "metrics")]
+    fn update_metrics(&mut self) {
+        let path = match self.config.metrics_file() {
+            Some(path) => path.to_owned(),
+            None => return,
+        };
+        let touchpad = self.touchpad_path();
+        let text = metrics::render(&self.status(&touchpad));
+        if self.metrics_written.as_ref() == Some(&text) {
+            return;
+        }
+        match status_files::write_atomic(&path, &text) {
+            Ok(()) => self.metrics_written = Some(text),
+            Err(err) => warn!("Failed to update the metrics file: {:#}", err),
+        }
+    }
+
+    /// Run the commands received by the D-Bus service, and reply to the method calls with the
+    /// results.
+    #[cfg(feature = "dbus")]
+    fn handle_dbus_requests(&mut self) {
+        let requests = self
+            .dbus
+            .as_ref()
+            .map(DbusService::requests)
+            .unwrap_or_default();
+        for request in requests {
+            let result = self
+                .handle_control_command(request.cmd)
+                .map_err(|err| format!("{:#}", err));
+            // the call might have timed out already
+            let _ = request.reply.send(result);
+        }
+    }
+
+    /// Re-read the config, and apply the settings that can be changed at runtime.
+    ///
+    /// The active profile stays active, unless the new config doesn't have it anymore.
+    fn reload_config(&mut self) -> Result<()> {
+        info!("Reloading config");
+        let mut config = match Config::load(self.config.path()) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("{:#}, keeping the current config", err);
+                return Ok(());
+            }
+        };
+        config.set_dry_run(self.config.dry_run());
+        config.set_emit_protocol(self.config.emit_protocol());
+        if let Some(name) = self.config.profile() {
+            match config.with_profile(Some(name)) {
+                Ok(with_profile) => config = with_profile,
+                Err(err) => warn!("{:#}, using the config without it", err),
+            }
+        }
+        self.apply_config(config)
+    }
+
+    /// Switch to the config's profile `name`, or back to no profile.
+    fn switch_profile(&mut self, name: Option<&str>) -> Result<()> {
+        let config = self.config.with_profile(name)?;
+        info!("Switching to profile {}", name.unwrap_or("none"));
+        self.apply_config(config)
+    }
+
+    /// Apply the settings of the new config that can be changed at runtime, and warn about the
+    /// ones that can't.
+    fn apply_config(&mut self, mut config: Config) -> Result<()> {
+        info!("Config: {:?}", config);
+        if config.layout() != self.config.layout()
+            || config.layout_file() != self.config.layout_file()
+        {
+            warn!("Layout changes will be applied only after a restart");
+        }
+        if config.key_repeat() != self.config.key_repeat() {
+            warn!("Key repeat changes will be applied only after a restart");
+        }
+        if config.dbus() != self.config.dbus() {
+            warn!("D-Bus changes will be applied only after a restart");
+        }
+        if config.uinput_identity() != self.config.uinput_identity() {
+            warn!("Virtual keyboard name and ID changes will be applied only after a restart");
+        }
+        if config.layers() != self.config.layers() {
+            warn!("Layer changes will be applied only after a restart");
+        }
+        if config.extra_keys() != self.config.extra_keys() {
+            warn!("New keys in calculator commands, corner swipes and the top row can be sent only after a restart");
+        }
+        apply_top_row(&mut self.layout, &config);
+        if config.key_remaps() != self.config.key_remaps() {
+            warn!("Key remaps will be applied only after a restart");
+        }
+        if config.multikeys() != self.config.multikeys() {
+            warn!("Multikey changes will be applied only after a restart");
+        }
+        if config.touchpad_name() != self.config.touchpad_name() {
+            warn!("Touchpad name changes will be applied only after a restart");
+        }
+        if config.touchpad_range() != self.config.touchpad_range() {
+            warn!("Touchpad range changes will be applied only after a restart");
+        }
+        if config.i2c_addrs() != self.config.i2c_addrs() {
+            warn!("I2C address changes will be applied only after a restart");
+        }
+        if config.led_command() != self.config.led_command() {
+            warn!("LED command changes will be applied only after a restart");
+        }
+        if config.invert_x() != self.config.invert_x()
+            || config.invert_y() != self.config.invert_y()
+        {
+            warn!("Axis inversion changes will be applied only after a restart");
+        }
+        if config.numlock_bbox() != self.config.numlock_bbox() {
+            warn!("Numlock bbox changes will be applied only after a restart");
+        }
+        if config.control_socket() != self.config.control_socket() {
+            warn!("Control socket changes will be applied only after a restart");
+        }
+        if config.pointer_while_numlock() != self.config.pointer_while_numlock() {
+            warn!("Pointer mode changes will be applied only after a restart");
+            // the virtual device can't move the pointer unless it was created with that
+            config.set_pointer_while_numlock(self.config.pointer_while_numlock());
+        }
+        self.layout.set_key_margin(config.key_margin());
+        self.layout.set_numlock_margin(config.numlock_margin());
+        // don't clobber the brightness if user cycled it, unless the default itself changed
+        if config.default_brightness() != self.config.default_brightness()
+            || config.brightness_percent() != self.config.brightness_percent()
+        {
+            self.state.brightness = config.default_brightness();
+            self.state.brightness_percent = config.brightness_percent();
+            if self.state.numlock {
+                self.light_up();
+            }
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// Set the layout's top row to `top_row_keys`, or back to the layout's own one without them.
+///
+/// `brightness_slide` uses the same band, so the top row is left empty with a warning then.
+fn apply_top_row(layout: &mut NumpadLayout, config: &Config) {
+    let keys = config
+        .top_row_keys()
+        .unwrap_or_else(|| layout.own_top_row())
+        .to_vec();
+    if config.brightness_slide() && !keys.is_empty() {
+        warn!("brightness_slide uses the band of the top row. Ignoring the top row");
+        layout.set_top_row(Vec::new());
+    } else {
+        layout.set_top_row(keys);
     }
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+/// Turn off the LED if the daemon panics, so that it doesn't stay lit after the daemon is gone.
+///
+/// The LED is set through a new connection, since the numpad's own one might be what panicked.
+/// The touchpad needs no cleanup: the kernel releases the grab once the device is closed, which
+/// happens at the latest when the process exits.
+fn install_panic_hook(i2c_id: u32, i2c_addrs: Vec<u16>, led_command: LedCommand) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if let Ok(mut i2c) = TouchpadI2C::new(i2c_id, &i2c_addrs, led_command.clone()) {
+            let _ = i2c.set_brightness(Brightness::Zero);
+        }
+    }));
+}
+
+fn run() -> Result<()> {
+    let args = Args::parse()?;
+    if args.help() {
+        println!("{}", Args::USAGE);
+        return Ok(());
+    }
+    if args.version() {
+        println!("{}", Args::VERSION);
+        return Ok(());
+    }
 
-    // Follows XDG Base Dir Spec
-    const CONFIG_PATH: &str = "/etc/xdg/asus_numpad.toml";
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = args.log_level() {
+        logger.filter_level(level);
+    }
+    logger.init();
 
-    let config: Config = toml::from_slice(&std::fs::read(CONFIG_PATH)?)?;
+    let mut config = match Config::load(args.config()) {
+        Ok(config) => config,
+        // the config that was asked for with --config is needed, a found one isn't
+        Err(err) if args.config().is_none() => {
+            warn!("{:#}, using defaults", err);
+            Config::default()
+        }
+        Err(err) => return Err(err),
+    };
+    config.set_dry_run(args.dry_run());
+    config.set_emit_protocol(args.emit_protocol());
+    if let Some(name) = args.profile() {
+        config = config.with_profile(Some(name))?;
+    }
+    #[cfg(not(feature = "metrics"))]
+    if config.metrics_file().is_some() {
+        warn!("Ignoring metrics_file, since asus-numpad was built without the metrics feature");
+    }
+    if args.self_test() {
+        config.set_self_test();
+    }
     info!("Config: {:?}", config);
-    let layout_name = config.layout();
+    if args.status() {
+        let path = config
+            .control_socket()
+            .ok_or_else(|| anyhow!("control_socket needs to be set in config to query status"))?;
+        println!("{}", query_status(path)?);
+        return Ok(());
+    }
+    #[cfg(not(feature = "dbus"))]
+    if config.dbus().is_some() {
+        warn!("Ignoring dbus, since asus-numpad was built without the dbus feature");
+    }
+    if args.list_devices() {
+        return print_devices(config.touchpad_name());
+    }
 
     let (keyboard_ev_id, touchpad_ev_id, i2c_id) =
-        read_proc_input().context("Couldn't get proc input devices")?;
-    let touchpad_dev = open_input_evdev(touchpad_ev_id)?;
+        read_proc_input(config.touchpad_name()).context("Couldn't get proc input devices")?;
+    let mut touchpad_dev = open_input_evdev(touchpad_ev_id)?;
     let keyboard_dev = open_input_evdev(keyboard_ev_id)?;
-    let bbox = get_touchpad_bbox(&touchpad_dev)?;
-    let layout = NumpadLayout::from_supported_layout(layout_name, bbox)?;
-    let kb = DummyKeyboard::new(&layout)?;
-    let touchpad_i2c = TouchpadI2C::new(i2c_id)?;
-    let mut numpad = Numpad::new(touchpad_dev, keyboard_dev, touchpad_i2c, kb, layout, config);
+    let bbox = get_touchpad_bbox(&touchpad_dev, config.touchpad_range())?;
+    // a layout given on the command line takes precedence over the layout file
+    let mut layout = match (args.layout(), config.layout_file()) {
+        (None, Some(path)) => NumpadLayout::from_file(path, bbox)?,
+        (layout, _) => {
+            let layout_name = match layout.or_else(|| config.layout()) {
+                Some(layout) => *layout,
+                None => SupportedLayout::detect(),
+            };
+            NumpadLayout::from_supported_layout(&layout_name, bbox)?
+        }
+    };
+    layout.set_key_margin(config.key_margin());
+    layout.set_numlock_margin(config.numlock_margin());
+    if let Some(invert) = config.invert_x() {
+        layout.set_invert_x(invert);
+    }
+    if let Some(invert) = config.invert_y() {
+        layout.set_invert_y(invert);
+    }
+    if let Some(region) = config.numlock_bbox() {
+        layout.set_numlock_bbox(region.left, region.top, region.right, region.bottom);
+    }
+    apply_top_row(&mut layout, &config);
+    if let Some(command) = config.led_command() {
+        layout.set_led_command(command.clone());
+    }
+    for keys in config.layers() {
+        if let Err(err) = layout.add_layer(keys.clone()) {
+            warn!("Ignoring layer: {}", err);
+        }
+    }
+    for remap in config.key_remaps() {
+        if let Some(key) = remap.key {
+            if let Err(err) = layout.remap_key(remap.row, remap.col, key) {
+                warn!("Ignoring key remap: {}", err);
+            }
+        }
+    }
+    for multikey in config.multikeys() {
+        if let Err(err) = layout.set_multikey(multikey.key, multikey.keys.clone()) {
+            warn!("Ignoring multikey: {}", err);
+        }
+    }
+    if args.calibrate() {
+        return calibrate(&mut touchpad_dev, &layout);
+    }
+    if let Some(device) = find_native_numpad(config.touchpad_name())? {
+        // a dry run doesn't grab the touchpad, so it can't conflict
+        if config.refuse_with_native_numpad() && !args.force() && !config.dry_run() {
+            return Err(NumpadError::NativeNumpad { device }.into());
+        }
+        warn!(
+            "The kernel already handles the numpad through \"{}\", expect double toggles",
+            device
+        );
+    }
+    let (kb, touchpad_i2c) = if config.dry_run() {
+        println!("Dry run: not sending any keys or changing the brightness");
+        (DummyKeyboard::dry_run(), TouchpadI2C::dry_run(i2c_id))
+    } else {
+        (
+            DummyKeyboard::new(
+                &layout,
+                &config.extra_keys(),
+                config.key_repeat(),
+                config.uinput_identity(),
+                config.pointer_while_numlock(),
+            )?,
+            TouchpadI2C::new_or_unavailable(
+                i2c_id,
+                config.i2c_addrs(),
+                layout.led_command().clone(),
+            ),
+        )
+    };
+    if !config.dry_run() {
+        install_panic_hook(
+            i2c_id,
+            config.i2c_addrs().to_vec(),
+            layout.led_command().clone(),
+        );
+    }
+    let control = config
+        .control_socket()
+        .map(ControlSocket::bind)
+        .transpose()?;
+    let mut numpad = Numpad::new(
+        touchpad_dev,
+        keyboard_dev,
+        touchpad_i2c,
+        kb,
+        layout,
+        config,
+        control,
+    );
     numpad.process()?;
     Ok(())
 }
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(1);
+    }
+}
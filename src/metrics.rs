@@ -0,0 +1,100 @@
+//! Metrics in the OpenMetrics text format, for monitoring with Prometheus and the like.
+//!
+//! Only built with the `metrics` feature.
+
+use std::fmt::Write;
+
+use crate::control::Status;
+use crate::touchpad_i2c::Brightness;
+
+/// Render the metrics of the status.
+///
+/// The brightness is the index of the level in [`Brightness::ALL`], `0` being off.
+pub(crate) fn render(status: &Status) -> String {
+    let brightness = Brightness::ALL
+        .iter()
+        .position(|level| *level == status.brightness)
+        .unwrap_or(0);
+    let metrics: [(&str, &str, &str, u64); 5] = [
+        (
+            "numlock",
+            "gauge",
+            "Whether numlock is on.",
+            status.numlock as u64,
+        ),
+        (
+            "brightness",
+            "gauge",
+            "Brightness level of the LED, from 0 (off) to 3 (full).",
+            brightness as u64,
+        ),
+        (
+            "toggles",
+            "counter",
+            "Numlock toggles since startup.",
+            status.toggles,
+        ),
+        (
+            "keys_pressed",
+            "counter",
+            "Numpad keys pressed since startup.",
+            status.keys_pressed,
+        ),
+        (
+            "i2c_errors",
+            "counter",
+            "Failed I2C writes since startup.",
+            status.i2c_errors,
+        ),
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        let sample = if kind == "counter" { "_total" } else { "" };
+        // writing to a String can't fail
+        let _ = write!(
+            text,
+            "# TYPE asus_numpad_{name} {kind}\n\
+            # HELP asus_numpad_{name} {help}\n\
+            asus_numpad_{name}{sample} {value}\n",
+            name = name,
+            kind = kind,
+            help = help,
+            sample = sample,
+            value = value
+        );
+    }
+    text.push_str("# EOF");
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let status = Status {
+            numlock: true,
+            brightness: Brightness::Half,
+            layout: "M433IA",
+            touchpad: "/dev/input/event14",
+            i2c_id: 2,
+            i2c_errors: 1,
+            layer: 0,
+            toggles: 3,
+            keys_pressed: 42,
+            profile: None,
+        };
+        let text = render(&status);
+        assert!(text.starts_with(
+            "# TYPE asus_numpad_numlock gauge\n\
+            # HELP asus_numpad_numlock Whether numlock is on.\n\
+            asus_numpad_numlock 1\n"
+        ));
+        assert!(text.contains("asus_numpad_brightness 2\n"));
+        assert!(text.contains("# TYPE asus_numpad_toggles counter\n"));
+        assert!(text.contains("asus_numpad_toggles_total 3\n"));
+        assert!(text.contains("asus_numpad_keys_pressed_total 42\n"));
+        assert!(text.ends_with("asus_numpad_i2c_errors_total 1\n# EOF"));
+    }
+}
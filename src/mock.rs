@@ -0,0 +1,158 @@
+//! Fake devices, for testing [`crate::Numpad`] without the actual hardware.
+
+use std::collections::VecDeque;
+use std::io;
+
+use anyhow::{anyhow, Result};
+use evdev_rs::{
+    enums::{EventCode, EV_KEY},
+    GrabMode, InputEvent, TimeVal,
+};
+
+use crate::dummy_keyboard::KeyboardSink;
+use crate::event_source::EventSource;
+use crate::touchpad_i2c::{Brightness, LedControl};
+
+/// Create an event that happened `millis` after the start of the test.
+pub(crate) fn event(millis: i64, event_code: EventCode, value: i32) -> InputEvent {
+    InputEvent::new(&time(millis), &event_code, value)
+}
+
+/// The timestamp of the events [`event`] creates for the given milliseconds.
+pub(crate) fn time(millis: i64) -> TimeVal {
+    TimeVal {
+        tv_sec: 1000 + millis / 1000,
+        tv_usec: (millis % 1000) * 1000,
+    }
+}
+
+/// Replays a scripted list of events.
+#[derive(Debug, Default)]
+pub(crate) struct MockEventSource {
+    events: VecDeque<InputEvent>,
+    pub(crate) grabbed: bool,
+    /// Fail to grab, like when another process grabbed the device already
+    pub(crate) grab_busy: bool,
+    /// Number of reads that fail with an I/O error before the events are read
+    pub(crate) failed_reads: u32,
+}
+
+impl MockEventSource {
+    /// Queue more events, to be read after the current ones.
+    pub(crate) fn extend(&mut self, events: Vec<InputEvent>) {
+        self.events.extend(events);
+    }
+}
+
+impl EventSource for MockEventSource {
+    fn next_event(&mut self) -> io::Result<InputEvent> {
+        if self.failed_reads > 0 {
+            self.failed_reads -= 1;
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        self.events
+            .pop_front()
+            .ok_or_else(|| io::ErrorKind::WouldBlock.into())
+    }
+
+    fn grab(&mut self, mode: GrabMode) -> io::Result<()> {
+        if self.grab_busy && matches!(mode, GrabMode::Grab) {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        self.grabbed = matches!(mode, GrabMode::Grab);
+        Ok(())
+    }
+
+    fn regrab(&mut self) -> io::Result<bool> {
+        let lost = !self.grabbed;
+        self.grabbed = true;
+        Ok(lost)
+    }
+}
+
+/// Lets a test keep the mock, to check what happened to it after the numpad is dropped.
+impl EventSource for &mut MockEventSource {
+    fn next_event(&mut self) -> io::Result<InputEvent> {
+        (**self).next_event()
+    }
+
+    fn grab(&mut self, mode: GrabMode) -> io::Result<()> {
+        (**self).grab(mode)
+    }
+
+    fn regrab(&mut self) -> io::Result<bool> {
+        (**self).regrab()
+    }
+}
+
+/// A call to one of the [`KeyboardSink`] methods.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum KeyEvent {
+    Down(EV_KEY),
+    Up(EV_KEY),
+    MultiDown(Vec<EV_KEY>),
+    MultiUp(Vec<EV_KEY>),
+    Pointer(i32, i32),
+}
+
+/// Records the keys instead of sending them to the system.
+#[derive(Debug, Default)]
+pub(crate) struct MockKeyboard {
+    /// The key events, in the order they were sent
+    pub(crate) events: Vec<KeyEvent>,
+}
+
+impl KeyboardSink for MockKeyboard {
+    fn keydown(&mut self, key: EV_KEY) {
+        self.events.push(KeyEvent::Down(key));
+    }
+
+    fn keyup(&mut self, key: EV_KEY) {
+        self.events.push(KeyEvent::Up(key));
+    }
+
+    fn multi_keydown(&mut self, keys: &[EV_KEY]) {
+        self.events.push(KeyEvent::MultiDown(keys.to_vec()));
+    }
+
+    fn multi_keyup(&mut self, keys: &[EV_KEY]) {
+        self.events.push(KeyEvent::MultiUp(keys.to_vec()));
+    }
+
+    fn move_pointer(&mut self, dx: i32, dy: i32) {
+        self.events.push(KeyEvent::Pointer(dx, dy));
+    }
+}
+
+/// Records the brightness levels that were set.
+#[derive(Debug, Default)]
+pub(crate) struct MockLed {
+    pub(crate) brightness: Vec<Brightness>,
+    /// Fail all the writes, like a flaky I2C bus
+    pub(crate) fail: bool,
+}
+
+impl LedControl for MockLed {
+    fn set_brightness_raw(&mut self, raw: u8) -> Result<()> {
+        self.set_brightness(Brightness::try_from(raw)?)
+    }
+
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<()> {
+        if self.fail {
+            return Err(anyhow!("Could not set brightness to {}", brightness));
+        }
+        self.brightness.push(brightness);
+        Ok(())
+    }
+}
+
+/// Like the [`EventSource`] for `&mut MockEventSource`.
+impl LedControl for &mut MockLed {
+    fn set_brightness_raw(&mut self, raw: u8) -> Result<()> {
+        (**self).set_brightness_raw(raw)
+    }
+
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<()> {
+        (**self).set_brightness(brightness)
+    }
+}
@@ -0,0 +1,130 @@
+use crate::Point;
+
+/// Max number of contacts tracked, to guard against bogus slot numbers.
+const MAX_SLOTS: usize = 16;
+
+/// A finger on the touchpad.
+#[derive(Debug, Default, Clone, Copy)]
+struct Contact {
+    active: bool,
+    /// Order in which the contacts touched the touchpad
+    order: u64,
+    pos: Point,
+}
+
+/// Tracks the contacts reported through the type B multitouch protocol.
+///
+/// Each contact gets a slot (`ABS_MT_SLOT`), and all the `ABS_MT_*` events that follow refer to
+/// that slot. The first contact that touched the touchpad is the primary one, which is used for
+/// pressing the keys.
+///
+/// In momentary mode, the contact holding numlock on is the holder, which never becomes the
+/// primary one.
+#[derive(Debug, Default)]
+pub(crate) struct Contacts {
+    slots: Vec<Contact>,
+    cur_slot: usize,
+    primary: Option<usize>,
+    holder: Option<usize>,
+    next_order: u64,
+}
+
+impl Contacts {
+    fn cur(&mut self) -> &mut Contact {
+        if self.slots.len() <= self.cur_slot {
+            self.slots.resize(self.cur_slot + 1, Contact::default());
+        }
+        &mut self.slots[self.cur_slot]
+    }
+
+    /// Handle `ABS_MT_SLOT`.
+    pub(crate) fn set_slot(&mut self, slot: i32) {
+        self.cur_slot = (slot.max(0) as usize).min(MAX_SLOTS - 1);
+    }
+
+    /// Handle `ABS_MT_TRACKING_ID`, which is `-1` when the contact is lifted.
+    pub(crate) fn set_tracking_id(&mut self, tracking_id: i32) {
+        let order = self.next_order;
+        let contact = self.cur();
+        if tracking_id >= 0 {
+            contact.active = true;
+            contact.order = order;
+            self.next_order += 1;
+            if self.primary.is_none() {
+                self.primary = Some(self.cur_slot);
+            }
+        } else {
+            contact.active = false;
+            if self.holder == Some(self.cur_slot) {
+                self.holder = None;
+            } else if self.primary == Some(self.cur_slot) {
+                // the finger that touched next becomes the primary one
+                self.primary = self.earliest_active();
+            }
+        }
+    }
+
+    /// The active contact that touched first, apart from the holder.
+    fn earliest_active(&self) -> Option<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(slot, contact)| contact.active && self.holder != Some(*slot))
+            .min_by_key(|(_, contact)| contact.order)
+            .map(|(slot, _)| slot)
+    }
+
+    /// Make the primary contact the holder, so that the next finger becomes the primary one.
+    ///
+    /// Returns false if there is no primary contact to hold.
+    pub(crate) fn hold_primary(&mut self) -> bool {
+        match self.primary {
+            Some(slot) => {
+                self.holder = Some(slot);
+                self.primary = self.earliest_active();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a holder is down. It stops being one when lifted.
+    pub(crate) fn has_holder(&self) -> bool {
+        self.holder.is_some()
+    }
+
+    pub(crate) fn set_x(&mut self, x: i32) {
+        self.cur().pos.x = x;
+    }
+
+    pub(crate) fn set_y(&mut self, y: i32) {
+        self.cur().pos.y = y;
+    }
+
+    /// Whether the events being reported are for the primary contact.
+    ///
+    /// Also true if there is no primary contact, like for touchpads without tracking IDs, unless
+    /// the events are for the holder.
+    pub(crate) fn is_primary(&self) -> bool {
+        match self.primary {
+            Some(slot) => slot == self.cur_slot,
+            None => self.holder != Some(self.cur_slot),
+        }
+    }
+
+    /// Position of the primary contact, if any.
+    pub(crate) fn primary_pos(&self) -> Option<Point> {
+        self.primary
+            .and_then(|slot| self.slots.get(slot))
+            .map(|contact| contact.pos)
+    }
+
+    /// Slots and positions of all the contacts that are down.
+    pub(crate) fn active(&self) -> impl Iterator<Item = (usize, Point)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, contact)| contact.active)
+            .map(|(slot, contact)| (slot, contact.pos))
+    }
+}
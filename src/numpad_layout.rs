@@ -1,10 +1,14 @@
-use std::fmt::Debug;
-use std::hint::unreachable_unchecked;
+use std::fmt::{Debug, Display};
+use std::path::Path;
+use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use evdev_rs::enums::EV_KEY;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 
+use crate::config::Region;
+use crate::touchpad_i2c::LedCommand;
 use crate::Point;
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -15,7 +19,18 @@ struct Margins {
     right: f32,
 }
 
-#[derive(Debug)]
+impl From<Region> for Margins {
+    fn from(region: Region) -> Self {
+        Self {
+            top: region.top,
+            bottom: 1.0 - region.bottom,
+            left: region.left,
+            right: 1.0 - region.right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct BBox {
     minx: i32,
     maxx: i32,
@@ -66,24 +81,271 @@ impl BBox {
     fn contains(&self, pos: Point) -> bool {
         (self.minx <= pos.x && pos.x <= self.maxx) && (self.miny <= pos.y && pos.y <= self.maxy)
     }
+
+    /// Return the bbox without the given fraction of its size along each edge.
+    fn shrink(&self, fraction: f32) -> Self {
+        self.apply_margins(Margins {
+            top: fraction,
+            bottom: fraction,
+            left: fraction,
+            right: fraction,
+        })
+    }
 }
 
 type Grid = Vec<Vec<EV_KEY>>;
 
+/// A corner of the touchpad that swipes can start from.
+///
+/// The top left corner is left out, since dragging from the calc bbox already toggles the calc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Corner {
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    /// Direction towards the center of the touchpad, as signs of x and y.
+    fn inward(&self) -> (i32, i32) {
+        match self {
+            Corner::TopRight => (-1, 1),
+            Corner::BottomLeft => (1, -1),
+            Corner::BottomRight => (-1, -1),
+        }
+    }
+}
+
+/// Describes the geometry of a numpad layout, relative to the touchpad bbox.
+///
+/// New models can be supported by adding a spec in [`specs`].
+struct LayoutSpec {
+    /// Name of the model
+    name: &'static str,
+    /// The matrix of keys, row by row
+    keys: &'static [&'static [EV_KEY]],
+    /// Margins around the numpad keys. Touches here are ignored.
+    numpad: Margins,
+    /// Margins defining the numlock bbox, if the model has one
+    numlock: Option<Margins>,
+    /// Margins defining the calc bbox, if the model has one
+    calc: Option<Margins>,
+}
+
+/// A layout described in a file, see [`NumpadLayout::from_file`].
+///
+/// Unlike [`LayoutSpec`], the regions are given as rectangles, which are easier to measure.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayoutFile {
+    name: String,
+    keys: Vec<Vec<EV_KEY>>,
+    numpad: Region,
+    numlock: Option<Region>,
+    calc: Option<Region>,
+    #[serde(default)]
+    top_row: Vec<EV_KEY>,
+    #[serde(default)]
+    invert_x: bool,
+    #[serde(default)]
+    invert_y: bool,
+    /// Keys that are sent along with Shift
+    #[serde(default = "default_shifted")]
+    shifted: Vec<EV_KEY>,
+    #[serde(default)]
+    led_command: LedCommand,
+}
+
+/// Send the key along with Shift.
+fn shifted(key: EV_KEY) -> (EV_KEY, Vec<EV_KEY>) {
+    (key, vec![EV_KEY::KEY_LEFTSHIFT, key])
+}
+
+/// `KEY_5` is sent with Shift to type `%`, on a US keyboard layout.
+fn default_shifted() -> Vec<EV_KEY> {
+    vec![EV_KEY::KEY_5]
+}
+
+impl LayoutFile {
+    fn validate(&self) -> Result<()> {
+        let row_len = self.keys.first().map_or(0, Vec::len);
+        if row_len == 0 || self.keys.iter().any(|row| row.len() != row_len) {
+            return Err(anyhow!("keys should be rows of the same, non-zero length"));
+        }
+        let regions = [
+            ("numpad", Some(self.numpad)),
+            ("numlock", self.numlock),
+            ("calc", self.calc),
+        ];
+        for (name, region) in regions {
+            match region {
+                Some(region) if !region.is_valid() => {
+                    return Err(anyhow!(
+                        "{} should be within 0 and 1, with left < right and top < bottom",
+                        name
+                    ))
+                }
+                _ => (),
+            }
+        }
+        self.led_command.validate()
+    }
+}
+
+/// Specs of all the supported models.
+mod specs {
+    use super::{LayoutSpec, Margins};
+    use evdev_rs::enums::EV_KEY::*;
+
+    /// Numlock bbox in the top right corner
+    const NUMLOCK_TOP_RIGHT: Margins = Margins {
+        top: 0.0,
+        bottom: 0.91,
+        left: 0.95,
+        right: 0.0,
+    };
+
+    /// Calc bbox in the top left corner
+    const CALC_TOP_LEFT: Margins = Margins {
+        top: 0.0,
+        bottom: 0.91,
+        left: 0.0,
+        right: 0.95,
+    };
+
+    pub(super) const UX433FA: LayoutSpec = LayoutSpec {
+        name: "UX433FA",
+        keys: &[
+            &[KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
+            &[KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
+            &[KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_KPENTER],
+            &[KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPPLUS, KEY_KPENTER],
+        ],
+        numpad: Margins {
+            top: 0.1,
+            bottom: 0.025,
+            left: 0.05,
+            right: 0.05,
+        },
+        numlock: Some(NUMLOCK_TOP_RIGHT),
+        calc: Some(CALC_TOP_LEFT),
+    };
+
+    pub(super) const M433IA: LayoutSpec = LayoutSpec {
+        name: "M433IA",
+        keys: &[
+            &[KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
+            &[KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
+            &[KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_5],
+            &[KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS, KEY_EQUAL],
+        ],
+        numpad: Margins {
+            top: 0.1,
+            bottom: 0.025,
+            left: 0.05,
+            right: 0.05,
+        },
+        numlock: Some(NUMLOCK_TOP_RIGHT),
+        calc: Some(CALC_TOP_LEFT),
+    };
+
+    /// Has an extra function row at the top.
+    pub(super) const UX581: LayoutSpec = LayoutSpec {
+        name: "UX581",
+        keys: &[
+            &[KEY_KPEQUAL, KEY_5, KEY_BACKSPACE, KEY_BACKSPACE],
+            &[KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH],
+            &[KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK],
+            &[KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS],
+            &[KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS],
+        ],
+        numpad: Margins {
+            top: 0.1,
+            bottom: 0.025,
+            left: 0.025,
+            right: 0.025,
+        },
+        numlock: Some(NUMLOCK_TOP_RIGHT),
+        calc: Some(CALC_TOP_LEFT),
+    };
+
+    pub(super) const GX701: LayoutSpec = LayoutSpec {
+        name: "GX701",
+        keys: &[
+            &[KEY_CALC, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
+            &[KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
+            &[KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
+            &[KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
+            &[KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
+        ],
+        numpad: Margins {
+            top: 0.025,
+            bottom: 0.025,
+            left: 0.025,
+            right: 0.025,
+        },
+        numlock: None,
+        calc: None,
+    };
+
+    pub(super) const GX531: LayoutSpec = LayoutSpec {
+        name: "GX531",
+        keys: &[
+            &[KEY_BACKSLASH, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
+            &[KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
+            &[KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
+            &[KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
+            &[KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
+        ],
+        numpad: Margins {
+            top: 0.005,
+            bottom: 0.005,
+            left: 0.005,
+            right: 0.005,
+        },
+        numlock: None,
+        calc: None,
+    };
+}
+
 #[derive(Debug)]
 pub(crate) struct NumpadLayout {
+    /// Name of the layout
+    name: String,
     /// The matrix of keys
     keys: Grid,
+    /// Alternate matrices of keys, of the same size as `keys`, which is layer 0
+    layers: Vec<Grid>,
     numpad_bbox: BBox,
     numlock_bbox: BBox,
     calc_bbox: BBox,
+    /// The band along the top edge, above the numpad keys
+    top_edge_bbox: BBox,
+    /// Keys spread along the top edge band, empty if there are none
+    top_row: Vec<EV_KEY>,
+    /// The top row of the layout file, used unless the config has other keys
+    own_top_row: Vec<EV_KEY>,
+    /// Keys that are sent as a sequence of other keys, like `%` as Shift and 5
+    multikeys: Vec<(EV_KEY, Vec<EV_KEY>)>,
+    /// The whole touchpad
+    touchpad_bbox: BBox,
     /// The width of one numpad button/key box
     key_width: i32,
     /// The height of one numpad button/key box
     key_height: i32,
+    /// Fraction of key size near the edges of a key where touches are ignored
+    key_margin: f32,
+    /// Fraction of the numlock bbox size near its edges where numlock can't be toggled
+    numlock_margin: f32,
+    /// Whether the touchpad reports the axes in the opposite direction than the layout expects
+    invert_x: bool,
+    invert_y: bool,
+    /// The I2C messages that control the model's LED
+    led_command: LedCommand,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub(crate) enum SupportedLayout {
     UX433FA,
     M433IA,
@@ -92,42 +354,350 @@ pub(crate) enum SupportedLayout {
     GX531,
 }
 
+impl SupportedLayout {
+    const ALL: [Self; 5] = [
+        Self::UX433FA,
+        Self::M433IA,
+        Self::UX581,
+        Self::GX701,
+        Self::GX531,
+    ];
+
+    fn name(&self) -> &'static str {
+        use SupportedLayout::*;
+        match self {
+            UX433FA => "UX433FA",
+            M433IA => "M433IA",
+            UX581 => "UX581",
+            GX701 => "GX701",
+            GX531 => "GX531",
+        }
+    }
+
+    /// Find the layout whose name appears in the laptop model string.
+    fn from_model(model: &str) -> Option<Self> {
+        let model = model.to_ascii_uppercase();
+        Self::ALL
+            .iter()
+            .find(|layout| model.contains(layout.name()))
+            .copied()
+    }
+
+    /// Detect the layout from the laptop model, as reported by DMI.
+    ///
+    /// Falls back to `M433IA` if the model is not recognized.
+    pub(crate) fn detect() -> Self {
+        const DMI_PATHS: [&str; 2] = [
+            "/sys/class/dmi/id/product_name",
+            "/sys/class/dmi/id/board_name",
+        ];
+        for path in DMI_PATHS {
+            match std::fs::read_to_string(path) {
+                Ok(model) => {
+                    if let Some(layout) = Self::from_model(model.trim()) {
+                        info!("Detected layout {} from model {}", layout, model.trim());
+                        return layout;
+                    }
+                    debug!("Unrecognized model {} in {}", model.trim(), path);
+                }
+                Err(err) => debug!("Couldn't read {}: {}", path, err),
+            }
+        }
+        let fallback = Self::M433IA;
+        warn!(
+            "Couldn't detect layout from laptop model, falling back to {}. \
+            Specify the layout in config if this is wrong.",
+            fallback
+        );
+        fallback
+    }
+}
+
+impl Display for SupportedLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SupportedLayout {
+    type Err = anyhow::Error;
+
+    /// Parse the layout name, ignoring case.
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|layout| layout.name().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(Self::name).collect();
+                anyhow!(
+                    "Unknown layout \"{}\". Valid layouts are: {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
 impl NumpadLayout {
+    /// Get the name of the layout.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     /// Get a reference to the numpad layout's keys.
     pub fn keys(&self) -> &Grid {
         self.keys.as_ref()
     }
 
-    pub fn needs_multikey(&self, key: EV_KEY) -> bool {
-        key == EV_KEY::KEY_5
+    /// Get the I2C messages that control the model's LED.
+    pub fn led_command(&self) -> &LedCommand {
+        &self.led_command
+    }
+
+    /// Use other I2C messages to control the LED, for models that need them.
+    pub fn set_led_command(&mut self, command: LedCommand) {
+        self.led_command = command;
+    }
+
+    /// The keys to press all at once for `key`, if it's sent as a sequence of other keys.
+    pub fn multikeys(&self, key: EV_KEY) -> Option<&[EV_KEY]> {
+        self.multikeys
+            .iter()
+            .find(|(multikey, _)| *multikey == key)
+            .map(|(_, keys)| keys.as_slice())
     }
 
-    pub fn multikeys(&self, key: EV_KEY) -> [EV_KEY; 2] {
-        match key {
-            EV_KEY::KEY_5 => [EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_5],
-            // Safety: We know this method will only be called after
-            // needs_multikey returns true
-            _ => unsafe { unreachable_unchecked() },
+    /// Send `key` as `keys`, pressed all at once, instead of the defaults.
+    ///
+    /// This allows typing symbols like `%` on keyboard layouts where they aren't Shift and 5.
+    /// Fails if `keys` is empty, or if the layout doesn't have `key`.
+    pub fn set_multikey(&mut self, key: EV_KEY, keys: Vec<EV_KEY>) -> Result<()> {
+        if keys.is_empty() {
+            bail!("No keys to send for {:?}", key);
         }
+        let in_layout = std::iter::once(&self.keys)
+            .chain(self.layers.iter())
+            .flat_map(|keys| keys.iter().flatten())
+            .chain(self.top_row.iter())
+            .any(|&layout_key| layout_key == key);
+        if !in_layout {
+            bail!("Layout {} has no {:?} key", self.name, key);
+        }
+        self.multikeys.retain(|(multikey, _)| *multikey != key);
+        self.multikeys.push((key, keys));
+        Ok(())
+    }
+
+    /// Get all the keys that the layout can emit, including the modifiers of [`Self::multikeys`].
+    pub fn emitted_keys(&self) -> Vec<EV_KEY> {
+        let mut emitted = Vec::new();
+        let grid_keys = std::iter::once(self.keys())
+            .chain(self.layers.iter())
+            .flat_map(|keys| keys.iter().flat_map(|row| row.iter()));
+        for &key in grid_keys.chain(self.top_row.iter()) {
+            let keys = self
+                .multikeys(key)
+                .unwrap_or_else(|| std::slice::from_ref(&key));
+            for &key in keys {
+                if !emitted.contains(&key) {
+                    emitted.push(key);
+                }
+            }
+        }
+        emitted
+    }
+
+    /// Get the (row, col) of the grid cell at the position, if it's in the numpad bbox.
+    pub fn cell(&self, pos: Point) -> Option<(usize, usize)> {
+        let bbox = &self.numpad_bbox;
+        if !bbox.contains(pos) {
+            return None;
+        }
+        let num_rows = self.keys.len();
+        let num_cols = self.keys[0].len();
+        // clamp, since the bbox range might not be an exact multiple of key size
+        let col = (((pos.x - bbox.minx) / self.key_width) as usize).min(num_cols - 1);
+        let row = (((pos.y - bbox.miny) / self.key_height) as usize).min(num_rows - 1);
+        Some((row, col))
+    }
+
+    /// Get the position as fractions of the touchpad size, with `(0, 0)` at the top left.
+    pub fn normalize(&self, pos: Point) -> (f32, f32) {
+        let bbox = &self.touchpad_bbox;
+        (
+            (pos.x - bbox.minx) as f32 / bbox.xrange().max(1) as f32,
+            (pos.y - bbox.miny) as f32 / bbox.yrange().max(1) as f32,
+        )
     }
 
     /// Get the key at (posx, posy), if it exists
     pub fn get_key(&self, pos: Point) -> Option<EV_KEY> {
+        self.get_key_in_layer(pos, 0)
+    }
+
+    /// Get the key at the position in the given layer, see [`Self::add_layer`].
+    ///
+    /// The top row is the same in all layers. Layers that don't exist fall back to layer 0.
+    pub fn get_key_in_layer(&self, pos: Point, layer: usize) -> Option<EV_KEY> {
+        let (row, col) = match self.cell(pos) {
+            Some(cell) => cell,
+            None => return self.get_top_row_key(pos),
+        };
+        let keys = match layer.checked_sub(1).and_then(|idx| self.layers.get(idx)) {
+            Some(keys) => keys,
+            None => &self.keys,
+        };
         let bbox = &self.numpad_bbox;
-        if !bbox.contains(pos) {
+        let key = keys[row][col];
+
+        // offset of the point inside the key's box
+        let offset_x = pos.x - bbox.minx - col as i32 * self.key_width;
+        let offset_y = pos.y - bbox.miny - row as i32 * self.key_height;
+        let margin_x = (self.key_margin * self.key_width as f32) as i32;
+        let margin_y = (self.key_margin * self.key_height as f32) as i32;
+
+        // The margin only applies to edges shared with a different key.
+        // Keys spanning multiple cells (like a tall Enter key) shouldn't have gaps in them.
+        let differs = |row: Option<usize>, col: Option<usize>| match (row, col) {
+            (Some(row), Some(col)) => keys
+                .get(row)
+                .and_then(|keys| keys.get(col))
+                .map_or(false, |other| *other != key),
+            _ => false,
+        };
+        let in_margin = (offset_x < margin_x && differs(Some(row), col.checked_sub(1)))
+            || (offset_x > self.key_width - margin_x && differs(Some(row), Some(col + 1)))
+            || (offset_y < margin_y && differs(row.checked_sub(1), Some(col)))
+            || (offset_y > self.key_height - margin_y && differs(Some(row + 1), Some(col)));
+        if in_margin {
+            None
+        } else {
+            Some(key)
+        }
+    }
+
+    /// Get the key of the top row at the position, if it's in the top edge band.
+    fn get_top_row_key(&self, pos: Point) -> Option<EV_KEY> {
+        if self.top_row.is_empty() || !self.in_top_edge_bbox(pos) {
             return None;
         }
-        let col = ((pos.x - bbox.minx) / self.key_width) as usize;
-        let row = ((pos.y - bbox.miny) / self.key_height) as usize;
-        // Safety: We have already checked that bbox contains the point
-        let key = unsafe { self.keys().get_unchecked(row).get_unchecked(col) };
-        Some(*key)
+        let bbox = &self.top_edge_bbox;
+        let count = self.top_row.len() as i32;
+        let col = ((pos.x - bbox.minx) * count / bbox.xrange().max(1)).min(count - 1);
+        Some(self.top_row[col as usize])
+    }
+
+    /// Replace the keys in the band along the top edge, between the numlock and calc bboxes.
+    ///
+    /// The keys are spread evenly across the band. An empty list removes the top row.
+    pub fn set_top_row(&mut self, keys: Vec<EV_KEY>) {
+        self.top_row = keys;
+    }
+
+    /// Get the top row that the layout itself has, whatever it was replaced with.
+    pub fn own_top_row(&self) -> &[EV_KEY] {
+        &self.own_top_row
+    }
+
+    /// Add an alternate matrix of keys, which needs to have the same size as the layout's keys.
+    pub fn add_layer(&mut self, keys: Grid) -> Result<()> {
+        let same_size =
+            keys.len() == self.keys.len() && keys.iter().all(|row| row.len() == self.keys[0].len());
+        if !same_size {
+            return Err(anyhow!(
+                "Layer should have {} rows of {} keys, like layout {}",
+                self.keys.len(),
+                self.keys[0].len(),
+                self.name
+            ));
+        }
+        self.layers.push(keys);
+        Ok(())
+    }
+
+    /// Number of layers, including the layout's own keys.
+    pub fn num_layers(&self) -> usize {
+        1 + self.layers.len()
+    }
+
+    /// Replace the key at the given position of the grid.
+    pub fn remap_key(&mut self, row: usize, col: usize, key: EV_KEY) -> Result<()> {
+        let name = &self.name;
+        let cell = self
+            .keys
+            .get_mut(row)
+            .and_then(|keys| keys.get_mut(col))
+            .ok_or_else(|| anyhow!("No key at row {}, col {} in layout {}", row, col, name))?;
+        *cell = key;
+        Ok(())
+    }
+
+    /// Set the fraction of key size near the edges of a key where touches are ignored.
+    ///
+    /// Clamped to [0, 0.5).
+    pub fn set_key_margin(&mut self, margin: f32) {
+        self.key_margin = margin.clamp(0.0, 0.49);
+    }
+
+    /// Set the fraction of the numlock bbox size near its edges where numlock can't be toggled.
+    ///
+    /// Clamped to [0, 0.5).
+    pub fn set_numlock_margin(&mut self, margin: f32) {
+        self.numlock_margin = margin.clamp(0.0, 0.49);
+    }
+
+    /// Flip the x axis, so that the left edge of the touchpad becomes the right one.
+    pub fn set_invert_x(&mut self, invert: bool) {
+        self.invert_x = invert;
+    }
+
+    /// Flip the y axis, so that the top edge of the touchpad becomes the bottom one.
+    pub fn set_invert_y(&mut self, invert: bool) {
+        self.invert_y = invert;
+    }
+
+    /// Get the x coordinate reported by the touchpad in the direction the layout expects.
+    pub fn flip_x(&self, x: i32) -> i32 {
+        let bbox = &self.touchpad_bbox;
+        if self.invert_x {
+            bbox.minx + bbox.maxx - x
+        } else {
+            x
+        }
+    }
+
+    /// Get the y coordinate reported by the touchpad in the direction the layout expects.
+    pub fn flip_y(&self, y: i32) -> i32 {
+        let bbox = &self.touchpad_bbox;
+        if self.invert_y {
+            bbox.miny + bbox.maxy - y
+        } else {
+            y
+        }
+    }
+
+    /// Whether the point is well within the numlock bbox, outside of its margin.
+    pub fn in_numlock_activation_bbox(&self, pos: Point) -> bool {
+        self.numlock_bbox.shrink(self.numlock_margin).contains(pos)
     }
 
     pub fn _in_margins(&self, pos: Point) -> bool {
         !self.numpad_bbox.contains(pos)
     }
 
+    /// Move the numlock bbox to the given rectangle.
+    ///
+    /// The coordinates are fractions of the touchpad size, with `(0, 0)` at the top left.
+    pub fn set_numlock_bbox(&mut self, left: f32, top: f32, right: f32, bottom: f32) {
+        self.numlock_bbox = self.touchpad_bbox.apply_margins(Margins {
+            top,
+            bottom: 1.0 - bottom,
+            left,
+            right: 1.0 - right,
+        });
+    }
+
     pub fn in_numlock_bbox(&self, pos: Point) -> bool {
         self.numlock_bbox.contains(pos)
     }
@@ -136,156 +706,186 @@ impl NumpadLayout {
         self.calc_bbox.contains(pos)
     }
 
-    fn create(keys: Grid, numpad_bbox: BBox, numlock_bbox: BBox, calc_bbox: BBox) -> Self {
+    /// Whether the point is in the band along the top edge, but not in the numlock or calc bbox.
+    pub fn in_top_edge_bbox(&self, pos: Point) -> bool {
+        self.top_edge_bbox.contains(pos) && !self.in_numlock_bbox(pos) && !self.in_calc_bbox(pos)
+    }
+
+    /// Whether the point is in the given corner of the touchpad.
+    pub fn in_corner(&self, corner: Corner, pos: Point) -> bool {
+        // fraction of the touchpad size covered by a corner, along each axis
+        const CORNER_SIZE: f32 = 0.1;
+        let bbox = &self.touchpad_bbox;
+        let near_edge = |val: i32, min: i32, range: i32, sign: i32| {
+            let dist = if sign > 0 {
+                val - min
+            } else {
+                min + range - val
+            };
+            dist as f32 <= CORNER_SIZE * range as f32
+        };
+        let (sx, sy) = corner.inward();
+        near_edge(pos.x, bbox.minx, bbox.xrange(), sx)
+            && near_edge(pos.y, bbox.miny, bbox.yrange(), sy)
+    }
+
+    /// How far the finger moved from `start` towards the center, away from the corner.
+    ///
+    /// The distance is the sum of the inward movement along each axis, as fractions of the
+    /// touchpad size. Moving back towards the corner makes it smaller.
+    pub fn corner_swipe_distance(&self, corner: Corner, start: Point, pos: Point) -> f32 {
+        let bbox = &self.touchpad_bbox;
+        let (sx, sy) = corner.inward();
+        let dx = ((pos.x - start.x) * sx) as f32 / bbox.xrange().max(1) as f32;
+        let dy = ((pos.y - start.y) * sy) as f32 / bbox.yrange().max(1) as f32;
+        dx + dy
+    }
+
+    /// Get the given fraction of the touchpad height, in touchpad units.
+    pub fn height_fraction(&self, fraction: f32) -> i32 {
+        (fraction * self.touchpad_bbox.yrange() as f32) as i32
+    }
+
+    /// Horizontal position of the point in the top edge band, from `0.0` (left) to `1.0` (right).
+    pub fn top_edge_fraction(&self, pos: Point) -> f32 {
+        let bbox = &self.top_edge_bbox;
+        ((pos.x - bbox.minx) as f32 / bbox.xrange().max(1) as f32).clamp(0.0, 1.0)
+    }
+
+    fn create(
+        name: String,
+        keys: Grid,
+        numpad_bbox: BBox,
+        numlock_bbox: BBox,
+        calc_bbox: BBox,
+        top_edge_bbox: BBox,
+        touchpad_bbox: BBox,
+    ) -> Self {
         let key_width = numpad_bbox.xrange() / keys[0].len() as i32;
         let key_height = numpad_bbox.yrange() / keys.len() as i32;
         Self {
+            name,
             keys,
             numpad_bbox,
             numlock_bbox,
             calc_bbox,
+            top_edge_bbox,
+            layers: Vec::new(),
+            top_row: Vec::new(),
+            own_top_row: Vec::new(),
+            multikeys: default_shifted().into_iter().map(shifted).collect(),
+            touchpad_bbox,
             key_width,
             key_height,
+            key_margin: 0.0,
+            numlock_margin: 0.0,
+            invert_x: false,
+            invert_y: false,
+            led_command: LedCommand::default(),
         }
     }
 
-    pub fn ux433fa(bbox: BBox) -> Self {
-        use EV_KEY::*;
+    /// Build the layout from the margins of its regions, scaled to the given touchpad bbox.
+    fn from_margins(
+        name: String,
+        keys: Grid,
+        numpad: Margins,
+        numlock: Option<Margins>,
+        calc: Option<Margins>,
+        bbox: BBox,
+    ) -> Self {
+        // Regions that aren't present on a model are set to values outside the
+        // actual touchpad bbox. This way, they will never be activated.
+        let region = |margins: Option<Margins>| {
+            margins.map_or_else(|| bbox.disjoint_dummy(), |m| bbox.apply_margins(m))
+        };
+        let top_edge = Margins {
+            bottom: 1.0 - numpad.top,
+            top: 0.0,
+            ..numpad
+        };
         Self::create(
-            vec![
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_KPENTER],
-                vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPPLUS, KEY_KPENTER],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.1,
-                bottom: 0.025,
-                left: 0.05,
-                right: 0.05,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.95,
-                right: 0.0,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.0,
-                right: 0.95,
-            }),
+            name,
+            keys,
+            bbox.apply_margins(numpad),
+            region(numlock),
+            region(calc),
+            bbox.apply_margins(top_edge),
+            bbox,
         )
     }
 
-    pub fn m433ia(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH, KEY_BACKSPACE],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK, KEY_BACKSPACE],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS, KEY_5],
-                vec![KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS, KEY_EQUAL],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.1,
-                bottom: 0.025,
-                left: 0.05,
-                right: 0.05,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.95,
-                right: 0.0,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.0,
-                right: 0.95,
-            }),
+    /// Build the layout from its spec, scaled to the given touchpad bbox.
+    fn from_spec(spec: &LayoutSpec, bbox: BBox) -> Self {
+        Self::from_margins(
+            spec.name.to_owned(),
+            spec.keys.iter().map(|row| row.to_vec()).collect(),
+            spec.numpad,
+            spec.numlock,
+            spec.calc,
+            bbox,
         )
     }
 
+    /// Load a layout from a TOML file, scaled to the given touchpad bbox.
+    ///
+    /// This allows using models that aren't built in. The regions are rectangles, given as
+    /// fractions of the touchpad size:
+    ///
+    /// ```toml
+    /// name = "M433IA"
+    /// keys = [
+    ///     ["KEY_KP7", "KEY_KP8", "KEY_KP9", "KEY_KPSLASH", "KEY_BACKSPACE"],
+    ///     # ...
+    /// ]
+    /// numpad = { left = 0.05, top = 0.1, right = 0.95, bottom = 0.975 }
+    /// numlock = { left = 0.95, top = 0.0, right = 1.0, bottom = 0.09 }
+    /// ```
+    pub fn from_file(path: &Path, bbox: BBox) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read layout file {}", path.display()))?;
+        Self::from_toml(&data, bbox)
+            .with_context(|| format!("Invalid layout file {}", path.display()))
+    }
+
+    fn from_toml(data: &str, bbox: BBox) -> Result<Self> {
+        let file: LayoutFile = toml::from_str(data)?;
+        file.validate()?;
+        let mut layout = Self::from_margins(
+            file.name,
+            file.keys,
+            file.numpad.into(),
+            file.numlock.map(Margins::from),
+            file.calc.map(Margins::from),
+            bbox,
+        );
+        layout.own_top_row = file.top_row.clone();
+        layout.set_top_row(file.top_row);
+        layout.set_invert_x(file.invert_x);
+        layout.set_invert_y(file.invert_y);
+        layout.multikeys = file.shifted.into_iter().map(shifted).collect();
+        layout.set_led_command(file.led_command);
+        Ok(layout)
+    }
+
+    pub fn ux433fa(bbox: BBox) -> Self {
+        Self::from_spec(&specs::UX433FA, bbox)
+    }
+
+    pub fn m433ia(bbox: BBox) -> Self {
+        Self::from_spec(&specs::M433IA, bbox)
+    }
+
     pub fn ux581(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_KPEQUAL, KEY_5, KEY_BACKSPACE, KEY_BACKSPACE],
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPSLASH],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPASTERISK],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPMINUS],
-                vec![KEY_KP0, KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.1,
-                bottom: 0.025,
-                left: 0.025,
-                right: 0.025,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.95,
-                right: 0.0,
-            }),
-            bbox.apply_margins(Margins {
-                top: 0.0,
-                bottom: 0.91,
-                left: 0.0,
-                right: 0.95,
-            }),
-        )
+        Self::from_spec(&specs::UX581, bbox)
     }
 
     pub fn gx701(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_CALC, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
-                vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.025,
-                bottom: 0.025,
-                left: 0.025,
-                right: 0.025,
-            }),
-            // these bboxes aren't present on this model.
-            // set to values outside the actual touchpad bbox.
-            // this way, they will never be activated.
-            bbox.disjoint_dummy(),
-            bbox.disjoint_dummy(),
-        )
+        Self::from_spec(&specs::GX701, bbox)
     }
 
     pub fn gx531(bbox: BBox) -> Self {
-        use EV_KEY::*;
-        Self::create(
-            vec![
-                vec![KEY_BACKSLASH, KEY_KPSLASH, KEY_KPASTERISK, KEY_KPMINUS],
-                vec![KEY_KP7, KEY_KP8, KEY_KP9, KEY_KPPLUS],
-                vec![KEY_KP4, KEY_KP5, KEY_KP6, KEY_KPPLUS],
-                vec![KEY_KP1, KEY_KP2, KEY_KP3, KEY_KPENTER],
-                vec![KEY_KP0, KEY_KP0, KEY_KPDOT, KEY_KPENTER],
-            ],
-            bbox.apply_margins(Margins {
-                top: 0.005,
-                bottom: 0.005,
-                left: 0.005,
-                right: 0.005,
-            }),
-            // these bboxes aren't present on this model.
-            // set to values outside the actual touchpad bbox.
-            // this way, they will never be activated.
-            bbox.disjoint_dummy(),
-            bbox.disjoint_dummy(),
-        )
+        Self::from_spec(&specs::GX531, bbox)
     }
 
     pub(crate) fn from_supported_layout(layout: &SupportedLayout, bbox: BBox) -> Result<Self> {
@@ -300,3 +900,238 @@ impl NumpadLayout {
         Ok(layout)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::EV_KEY::*;
+
+    /// M433IA on a 1000x1000 touchpad.
+    ///
+    /// The numpad bbox is `x: 50..=950, y: 100..=975`, with keys of size 180x218.
+    fn m433ia() -> NumpadLayout {
+        NumpadLayout::m433ia(BBox::new(0, 1000, 0, 1000))
+    }
+
+    /// Center of the key at the given row and column.
+    fn key_center(row: i32, col: i32) -> Point {
+        Point {
+            x: 50 + col * 180 + 90,
+            y: 100 + row * 218 + 109,
+        }
+    }
+
+    #[test]
+    fn test_get_key_centers() {
+        let layout = m433ia();
+        for (row, keys) in specs::M433IA.keys.iter().enumerate() {
+            for (col, key) in keys.iter().enumerate() {
+                let pos = key_center(row as i32, col as i32);
+                assert_eq!(layout.get_key(pos), Some(*key), "at {}", pos);
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_key_outside_numpad() {
+        let layout = m433ia();
+        for (x, y) in [(10, 500), (990, 500), (500, 50), (500, 990), (0, 0)] {
+            assert_eq!(layout.get_key(Point { x, y }), None, "at <{}, {}>", x, y);
+        }
+    }
+
+    #[test]
+    fn test_get_key_margins() {
+        let mut layout = m433ia();
+        layout.set_key_margin(0.1);
+        // between KP7 and KP8
+        assert_eq!(layout.get_key(Point { x: 232, y: 209 }), None);
+        // between KP7 and KP4
+        assert_eq!(layout.get_key(Point { x: 140, y: 320 }), None);
+        // inside the margin of KP7, but on the edge of the numpad
+        assert_eq!(layout.get_key(Point { x: 55, y: 209 }), Some(KEY_KP7));
+        // both cells of the tall backspace key
+        assert_eq!(
+            layout.get_key(Point { x: 860, y: 318 }),
+            Some(KEY_BACKSPACE)
+        );
+    }
+
+    #[test]
+    fn test_numpad_bbox_corners() {
+        let layout = m433ia();
+        for (x, y) in [(50, 100), (950, 100), (50, 975), (950, 975)] {
+            assert!(!layout._in_margins(Point { x, y }), "at <{}, {}>", x, y);
+        }
+        for (x, y) in [(49, 100), (951, 100), (50, 99), (950, 976)] {
+            assert!(layout._in_margins(Point { x, y }), "at <{}, {}>", x, y);
+        }
+    }
+
+    #[test]
+    fn test_set_numlock_bbox() {
+        let mut layout = m433ia();
+        assert!(layout.in_numlock_bbox(Point { x: 975, y: 45 }));
+        layout.set_numlock_bbox(0.0, 0.9, 0.1, 1.0);
+        assert!(!layout.in_numlock_bbox(Point { x: 975, y: 45 }));
+        for (x, y) in [(0, 900), (100, 1000), (50, 950)] {
+            assert!(layout.in_numlock_bbox(Point { x, y }), "at <{}, {}>", x, y);
+        }
+    }
+
+    #[test]
+    fn test_emitted_keys_cover_all_keys() {
+        let bbox = BBox::new(0, 1000, 0, 1000);
+        for supported in SupportedLayout::ALL {
+            let layout = NumpadLayout::from_supported_layout(&supported, bbox).unwrap();
+            let emitted = layout.emitted_keys();
+            for x in (0..=1000).step_by(10) {
+                for y in (0..=1000).step_by(10) {
+                    let key = match layout.get_key(Point { x, y }) {
+                        Some(key) => key,
+                        None => continue,
+                    };
+                    let keys = layout
+                        .multikeys(key)
+                        .unwrap_or_else(|| std::slice::from_ref(&key));
+                    for key in keys {
+                        assert!(
+                            emitted.contains(key),
+                            "{:?} missing in {}",
+                            key,
+                            layout.name()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_top_row() {
+        let mut layout = m433ia();
+        assert_eq!(layout.get_key(Point { x: 500, y: 50 }), None);
+
+        // the band is `x: 50..=950, y: 0..=100`
+        layout.set_top_row(vec![KEY_CALC, KEY_EQUAL, KEY_BACKSPACE]);
+        assert_eq!(layout.get_key(Point { x: 200, y: 50 }), Some(KEY_CALC));
+        assert_eq!(layout.get_key(Point { x: 500, y: 50 }), Some(KEY_EQUAL));
+        assert_eq!(layout.get_key(Point { x: 940, y: 95 }), Some(KEY_BACKSPACE));
+        // not in the numlock bbox
+        assert_eq!(layout.get_key(Point { x: 975, y: 45 }), None);
+        assert!(layout.emitted_keys().contains(&KEY_CALC));
+    }
+
+    #[test]
+    fn test_corners() {
+        let layout = m433ia();
+        assert!(layout.in_corner(Corner::BottomLeft, Point { x: 50, y: 950 }));
+        assert!(!layout.in_corner(Corner::BottomLeft, Point { x: 150, y: 950 }));
+        assert!(layout.in_corner(Corner::TopRight, Point { x: 950, y: 50 }));
+        assert!(!layout.in_corner(Corner::BottomRight, Point { x: 950, y: 50 }));
+
+        let start = Point { x: 950, y: 950 };
+        let towards_center = Point { x: 850, y: 850 };
+        let distance = layout.corner_swipe_distance(Corner::BottomRight, start, towards_center);
+        assert!((distance - 0.2).abs() < 1e-6, "{}", distance);
+        let outwards = Point { x: 1000, y: 950 };
+        assert!(layout.corner_swipe_distance(Corner::BottomRight, start, outwards) < 0.0);
+    }
+
+    #[test]
+    fn test_gx701_key_centers() {
+        // numpad bbox is `x: 25..=975, y: 25..=975`, with keys of size 237x190
+        let layout = NumpadLayout::gx701(BBox::new(0, 1000, 0, 1000));
+        let center = |row: i32, col: i32| Point {
+            x: 25 + col * 237 + 118,
+            y: 25 + row * 190 + 95,
+        };
+        assert_eq!(layout.get_key(center(0, 0)), Some(KEY_CALC));
+        assert_eq!(layout.get_key(center(2, 1)), Some(KEY_KP5));
+        assert_eq!(layout.get_key(center(4, 3)), Some(KEY_KPENTER));
+        // the plus key spans two rows
+        assert_eq!(layout.get_key(center(1, 3)), Some(KEY_KPPLUS));
+        assert_eq!(layout.get_key(center(2, 3)), Some(KEY_KPPLUS));
+        // no numlock bbox on this model
+        assert!(!layout.in_numlock_bbox(Point { x: 990, y: 10 }));
+    }
+
+    /// M433IA, as it would be described in a layout file.
+    const M433IA_FILE: &str = r#"
+        name = "M433IA"
+        keys = [
+            ["KEY_KP7", "KEY_KP8", "KEY_KP9", "KEY_KPSLASH", "KEY_BACKSPACE"],
+            ["KEY_KP4", "KEY_KP5", "KEY_KP6", "KEY_KPASTERISK", "KEY_BACKSPACE"],
+            ["KEY_KP1", "KEY_KP2", "KEY_KP3", "KEY_KPMINUS", "KEY_5"],
+            ["KEY_KP0", "KEY_KPDOT", "KEY_KPENTER", "KEY_KPPLUS", "KEY_EQUAL"],
+        ]
+        numpad = { left = 0.05, top = 0.1, right = 0.95, bottom = 0.975 }
+        numlock = { left = 0.95, top = 0.0, right = 1.0, bottom = 0.09 }
+        calc = { left = 0.0, top = 0.0, right = 0.05, bottom = 0.09 }
+    "#;
+
+    #[test]
+    fn test_from_toml() {
+        let bbox = BBox::new(0, 1000, 0, 1000);
+        let layout = NumpadLayout::from_toml(M433IA_FILE, bbox).unwrap();
+        let builtin = m433ia();
+        assert_eq!(layout.name(), "M433IA");
+        for x in (0..=1000).step_by(25) {
+            for y in (0..=1000).step_by(25) {
+                let pos = Point { x, y };
+                assert_eq!(layout.get_key(pos), builtin.get_key(pos), "at {}", pos);
+                assert_eq!(layout.in_numlock_bbox(pos), builtin.in_numlock_bbox(pos));
+                assert_eq!(layout.in_calc_bbox(pos), builtin.in_calc_bbox(pos));
+            }
+        }
+        assert_eq!(
+            layout.multikeys(KEY_5),
+            Some([KEY_LEFTSHIFT, KEY_5].as_slice())
+        );
+    }
+
+    #[test]
+    fn test_from_toml_invalid() {
+        let bbox = BBox::new(0, 1000, 0, 1000);
+        let unknown_key = M433IA_FILE.replace("KEY_KP7", "KEY_SEVEN");
+        assert!(NumpadLayout::from_toml(&unknown_key, bbox).is_err());
+        let ragged = M433IA_FILE.replace(", \"KEY_EQUAL\"", "");
+        assert!(NumpadLayout::from_toml(&ragged, bbox).is_err());
+        let inverted = M433IA_FILE.replace("right = 0.95", "right = 0.01");
+        assert!(NumpadLayout::from_toml(&inverted, bbox).is_err());
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut layout = m433ia();
+        assert!(layout.add_layer(vec![vec![KEY_5]]).is_err());
+        let mut keys = layout.keys().clone();
+        keys[0][0] = KEY_HOME;
+        layout.add_layer(keys).unwrap();
+        assert_eq!(layout.num_layers(), 2);
+        assert_eq!(layout.get_key_in_layer(key_center(0, 0), 0), Some(KEY_KP7));
+        assert_eq!(layout.get_key_in_layer(key_center(0, 0), 1), Some(KEY_HOME));
+        assert_eq!(layout.get_key_in_layer(key_center(0, 1), 1), Some(KEY_KP8));
+        assert!(layout.emitted_keys().contains(&KEY_HOME));
+    }
+
+    #[test]
+    fn test_set_multikey() {
+        let mut layout = m433ia();
+        layout.set_multikey(KEY_KPDOT, vec![KEY_COMMA]).unwrap();
+        layout
+            .set_multikey(KEY_5, vec![KEY_LEFTSHIFT, KEY_APOSTROPHE])
+            .unwrap();
+        assert_eq!(layout.multikeys(KEY_KPDOT), Some([KEY_COMMA].as_slice()));
+        assert_eq!(
+            layout.multikeys(KEY_5),
+            Some([KEY_LEFTSHIFT, KEY_APOSTROPHE].as_slice())
+        );
+        assert_eq!(layout.multikeys(KEY_KP7), None);
+        let emitted = layout.emitted_keys();
+        assert!(emitted.contains(&KEY_COMMA) && emitted.contains(&KEY_APOSTROPHE));
+
+        assert!(layout.set_multikey(KEY_HOME, vec![KEY_LEFTSHIFT]).is_err());
+        assert!(layout.set_multikey(KEY_KP7, Vec::new()).is_err());
+    }
+}
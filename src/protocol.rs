@@ -0,0 +1,72 @@
+//! A line protocol on stdout, for overlays that show the numpad and which key is pressed.
+//!
+//! Enabled through the `--emit-protocol` flag. Each event is printed as one line of
+//! space-separated fields, and the format is kept stable:
+//!
+//! - `KEY <KEY> down` and `KEY <KEY> up`: a numpad key was pressed or released. `<KEY>` is the
+//!   key's name without the `KEY_` prefix, like `KP7`. Keys that send several keys are reported
+//!   by the key in the layout.
+//! - `TOGGLE on` and `TOGGLE off`: numlock was turned on or off, by the touchpad or the system.
+//! - `POS <X> <Y>`: a finger moved to this position while touching, normalized to `0.00`..`1.00`
+//!   from the top left corner of the touchpad.
+//!
+//! Overlays should ignore lines they don't recognize, so that new events can be added.
+
+use std::fmt;
+use std::io::Write;
+
+use evdev_rs::enums::EV_KEY;
+
+/// An event of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ProtocolEvent {
+    /// A key was pressed, `true`, or released, `false`
+    Key(EV_KEY, bool),
+    /// Numlock was turned on, `true`, or off, `false`
+    Toggle(bool),
+    /// Normalized position of the finger
+    Pos(f32, f32),
+}
+
+impl fmt::Display for ProtocolEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key(key, down) => {
+                let name = format!("{:?}", key);
+                let name = name.strip_prefix("KEY_").unwrap_or(&name);
+                write!(f, "KEY {} {}", name, if *down { "down" } else { "up" })
+            }
+            Self::Toggle(on) => write!(f, "TOGGLE {}", if *on { "on" } else { "off" }),
+            Self::Pos(x, y) => write!(f, "POS {:.2} {:.2}", x, y),
+        }
+    }
+}
+
+impl ProtocolEvent {
+    /// Print the event as a line on stdout.
+    ///
+    /// Errors are ignored, so that the numpad keeps working if the overlay goes away.
+    pub(crate) fn emit(&self) {
+        let _ = writeln!(std::io::stdout().lock(), "{}", self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            ProtocolEvent::Key(EV_KEY::KEY_KP7, true).to_string(),
+            "KEY KP7 down"
+        );
+        assert_eq!(
+            ProtocolEvent::Key(EV_KEY::KEY_BACKSPACE, false).to_string(),
+            "KEY BACKSPACE up"
+        );
+        assert_eq!(ProtocolEvent::Toggle(true).to_string(), "TOGGLE on");
+        assert_eq!(ProtocolEvent::Toggle(false).to_string(), "TOGGLE off");
+        assert_eq!(ProtocolEvent::Pos(0.42, 0.314).to_string(), "POS 0.42 0.31");
+    }
+}
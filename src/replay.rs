@@ -0,0 +1,105 @@
+//! Replay of touchpad captures, for reproducing bug reports in tests.
+//!
+//! Captures are the text output of `evtest`, like:
+//!
+//! ```text
+//! Event: time 1652614505.524260, type 3 (EV_ABS), code 53 (ABS_MT_POSITION_X), value 975
+//! Event: time 1652614505.524260, -------------- SYN_REPORT ------------
+//! ```
+//!
+//! Lines that aren't events, like the description of the device that `evtest` prints first, are
+//! skipped, so a capture can be pasted from a bug report without trimming it.
+
+use anyhow::{anyhow, bail, Context, Result};
+use evdev_rs::enums::{int_to_event_type, EventCode, EventType};
+use evdev_rs::util::int_to_event_code;
+use evdev_rs::{InputEvent, TimeVal};
+
+/// Parse the events of an `evtest` capture, to be fed to a [`crate::mock::MockEventSource`].
+pub(crate) fn parse_evtest(capture: &str) -> Result<Vec<InputEvent>> {
+    capture
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| Some((i, line.trim().strip_prefix("Event: time ")?)))
+        .map(|(i, line)| {
+            parse_event(line).with_context(|| format!("Invalid event on line {}", i + 1))
+        })
+        .collect()
+}
+
+/// Parse an event line, after the `Event: time ` prefix.
+fn parse_event(line: &str) -> Result<InputEvent> {
+    let (time, rest) = line
+        .split_once(", ")
+        .ok_or_else(|| anyhow!("Missing event after the time"))?;
+    let (secs, micros) = time
+        .split_once('.')
+        .ok_or_else(|| anyhow!("Invalid time {}", time))?;
+    let time = TimeVal {
+        tv_sec: secs.parse().context("Invalid seconds")?,
+        tv_usec: micros.parse().context("Invalid microseconds")?,
+    };
+
+    // SYN events are printed as separators, like `-------------- SYN_REPORT ------------`
+    if !rest.starts_with("type ") {
+        let name = rest.trim_matches(|c: char| c == '-' || c == '+' || c.is_whitespace());
+        let event_code = EventCode::from_str(&EventType::EV_SYN, name)
+            .ok_or_else(|| anyhow!("Unknown event {}", rest))?;
+        return Ok(InputEvent::new(&time, &event_code, 0));
+    }
+
+    let mut fields = rest.split(", ");
+    let mut number = |prefix: &str| -> Result<u32> {
+        let field = fields
+            .next()
+            .and_then(|field| field.strip_prefix(prefix))
+            .ok_or_else(|| anyhow!("Missing {}", prefix.trim()))?;
+        // the number is followed by its name, like `3 (EV_ABS)`
+        let number = field.split(' ').next().unwrap_or_default();
+        number
+            .parse()
+            .with_context(|| format!("Invalid {}{}", prefix, number))
+    };
+    let event_type = number("type ")?;
+    let code = number("code ")?;
+    let value = fields
+        .next()
+        .and_then(|field| field.strip_prefix("value "))
+        .ok_or_else(|| anyhow!("Missing value"))?
+        .parse()
+        .context("Invalid value")?;
+    if int_to_event_type(event_type).is_none() {
+        bail!("Unknown event type {}", event_type);
+    }
+    let event_code = int_to_event_code(event_type, code);
+    Ok(InputEvent::new(&time, &event_code, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev_rs::enums::{EV_ABS, EV_SYN};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_evtest() {
+        let events = parse_evtest(
+            "Input driver version is 1.0.1
+Event: time 1652614505.524260, type 3 (EV_ABS), code 53 (ABS_MT_POSITION_X), value 975
+Event: time 1652614505.524260, -------------- SYN_REPORT ------------",
+        )
+        .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].event_code,
+            EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X)
+        );
+        assert_eq!(events[0].value, 975);
+        assert_eq!(events[0].time.tv_sec, 1652614505);
+        assert_eq!(events[0].time.tv_usec, 524260);
+        assert_eq!(events[1].event_code, EventCode::EV_SYN(EV_SYN::SYN_REPORT));
+
+        assert!(parse_evtest("Event: time 1.000000, type 3 (EV_ABS), code 53").is_err());
+        assert!(parse_evtest("Event: time 1.000000, type 99 (?), code 0 (?), value 0").is_err());
+    }
+}
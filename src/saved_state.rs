@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::touchpad_i2c::Brightness;
+
+/// The part of the numpad state that is kept across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SavedState {
+    pub(crate) numlock: bool,
+    pub(crate) brightness: Brightness,
+}
+
+impl SavedState {
+    /// Path of the state file. Follows XDG Base Dir Spec.
+    fn path() -> Option<PathBuf> {
+        let state_home = match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+        };
+        Some(state_home.join("asus-numpad/state"))
+    }
+
+    /// Read the state saved by a previous run.
+    ///
+    /// Returns `None` if there is no saved state, or if it is corrupt.
+    pub(crate) fn load() -> Option<Self> {
+        let path = Self::path()?;
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(err) => {
+                debug!("Couldn't read state from {}: {}", path.display(), err);
+                return None;
+            }
+        };
+        match toml::from_str(&data) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                warn!("Ignoring corrupt state file {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Write the state, so that it can be restored by the next run.
+    pub(crate) fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("Neither XDG_STATE_HOME nor HOME is set"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Couldn't create state dir {}", dir.display()))?;
+        }
+        let data = toml::to_string(self).context("Couldn't serialize state")?;
+        // write to a temporary file first, so that the state isn't corrupted if we get killed
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)
+            .with_context(|| format!("Couldn't write state to {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Couldn't write state to {}", path.display()))?;
+        debug!("Saved state {:?} to {}", self, path.display());
+        Ok(())
+    }
+}
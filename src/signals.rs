@@ -0,0 +1,71 @@
+use std::io;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+
+/// Set when we receive SIGTERM or SIGINT.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set when we receive SIGHUP.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_shutdown(_signal: c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_reload(_signal: c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Whether the process was asked to exit.
+pub(crate) fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Whether the process was asked to reload its config.
+///
+/// Clears the request, so that we reload only once per signal.
+pub(crate) fn take_reload_request() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+fn set_handler(signal: c_int, handler: extern "C" fn(c_int)) -> io::Result<()> {
+    // Safety: The handler only touches an atomic, which is async-signal-safe.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        // no SA_RESTART, so that ppoll is interrupted
+        action.sa_flags = 0;
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Install the signal handlers, and block the handled signals.
+///
+/// The signals get delivered only while waiting in `ppoll` with the returned
+/// mask. That way, we can't miss a signal that arrives just before we start waiting.
+pub(crate) fn install_handlers() -> Result<libc::sigset_t> {
+    const SIGNALS: [c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        set_handler(signal, handle_shutdown).context("Unable to install signal handler")?;
+    }
+    set_handler(libc::SIGHUP, handle_reload).context("Unable to install signal handler")?;
+    // Safety: The sigsets are initialized by sigemptyset before use.
+    unsafe {
+        let mut blocked: libc::sigset_t = std::mem::zeroed();
+        let mut orig_mask: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut blocked);
+        for signal in SIGNALS {
+            libc::sigaddset(&mut blocked, signal);
+        }
+        if libc::sigprocmask(libc::SIG_BLOCK, &blocked, &mut orig_mask) != 0 {
+            return Err(io::Error::last_os_error()).context("Unable to block signals");
+        }
+        Ok(orig_mask)
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::debug;
+
+/// Small files that reflect the numpad state, for status bars like polybar and waybar.
+///
+/// `numlock` contains `1` or `0`, and `brightness` the brightness level, like sysfs LEDs do.
+#[derive(Debug)]
+pub(crate) struct StatusFiles {
+    dir: PathBuf,
+    /// The state that was last written
+    written: Option<(bool, String)>,
+    /// The last write failed, and the failure was reported already
+    failed: bool,
+}
+
+impl StatusFiles {
+    /// Use the runtime dir: `$RUNTIME_DIRECTORY` (set by systemd's `RuntimeDirectory=`), or
+    /// `$XDG_RUNTIME_DIR/asus-numpad`, falling back to `/run/asus-numpad`.
+    pub(crate) fn new() -> Self {
+        let dir = match (
+            std::env::var_os("RUNTIME_DIRECTORY"),
+            std::env::var_os("XDG_RUNTIME_DIR"),
+        ) {
+            (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+            (_, Some(dir)) if !dir.is_empty() => PathBuf::from(dir).join("asus-numpad"),
+            _ => PathBuf::from("/run/asus-numpad"),
+        };
+        Self::in_dir(dir)
+    }
+
+    fn in_dir(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            written: None,
+            failed: false,
+        }
+    }
+
+    /// Write the files, if the state changed since the last write.
+    ///
+    /// Failed writes are retried on the next update, but only the first of the failures in a row
+    /// is returned, so that a dir that can't be written isn't reported over and over.
+    pub(crate) fn update(&mut self, numlock: bool, brightness: String) -> Result<()> {
+        let state = (numlock, brightness);
+        if self.written.as_ref() == Some(&state) {
+            return Ok(());
+        }
+        match self.write(&state) {
+            Ok(()) => {
+                debug!("Updated status files in {}", self.dir.display());
+                self.written = Some(state);
+                self.failed = false;
+                Ok(())
+            }
+            Err(err) if self.failed => {
+                debug!("Still failing to update status files: {:#}", err);
+                Ok(())
+            }
+            Err(err) => {
+                self.failed = true;
+                Err(err)
+            }
+        }
+    }
+
+    fn write(&self, (numlock, brightness): &(bool, String)) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Couldn't create status dir {}", self.dir.display()))?;
+        write_atomic(&self.dir.join("numlock"), if *numlock { "1" } else { "0" })?;
+        write_atomic(&self.dir.join("brightness"), brightness)
+    }
+
+    /// Remove the files, so that they don't show a stale state after exiting.
+    pub(crate) fn remove(&mut self) {
+        if self.written.take().is_some() {
+            for name in ["numlock", "brightness"] {
+                // the dir itself might be removed by systemd already
+                let _ = fs::remove_file(self.dir.join(name));
+            }
+        }
+    }
+}
+
+/// Write to a temporary file first, so that readers never see a partially written file.
+pub(crate) fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, format!("{}\n", contents))
+        .and_then(|()| fs::rename(&tmp_path, path))
+        .with_context(|| format!("Couldn't write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_and_remove() {
+        let dir = std::env::temp_dir().join(format!("asus-numpad-test-{}", std::process::id()));
+        let mut files = StatusFiles::in_dir(dir.clone());
+        files.update(true, "Full".to_string()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("numlock")).unwrap(), "1\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("brightness")).unwrap(),
+            "Full\n"
+        );
+
+        files.update(false, "Full".to_string()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("numlock")).unwrap(), "0\n");
+
+        files.remove();
+        assert!(!dir.join("numlock").exists());
+        assert!(!dir.join("brightness").exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failure_reported_once() {
+        // a file where the dir should be makes the writes fail
+        let dir = std::env::temp_dir().join(format!("asus-numpad-fail-{}", std::process::id()));
+        fs::write(&dir, "").unwrap();
+        let mut files = StatusFiles::in_dir(dir.clone());
+        assert!(files.update(true, "Full".to_string()).is_err());
+        assert!(files.update(false, "Full".to_string()).is_ok());
+        assert!(files.written.is_none());
+
+        fs::remove_file(&dir).unwrap();
+        files.update(false, "Full".to_string()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("numlock")).unwrap(), "0\n");
+        files.remove();
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+
+use log::{debug, warn};
+
+/// Tell systemd about a change in the state of the service, like `READY=1`.
+///
+/// Does nothing if we aren't running as a `Type=notify` service.
+pub(crate) fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    match send(&path, state) {
+        Ok(()) => debug!("Notified systemd: {}", state),
+        Err(err) => warn!("Failed to notify systemd: {}", err),
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    let path = path.as_bytes();
+    if path.first() != Some(&b'@') {
+        socket.send_to(state.as_bytes(), OsStr::from_bytes(path))?;
+        return Ok(());
+    }
+    // Socket in the abstract namespace, which std doesn't support.
+    // Its address starts with a null byte instead of the `@`.
+    let mut addr = libc::sockaddr_un {
+        sun_family: libc::AF_UNIX as libc::sa_family_t,
+        sun_path: [0; 108],
+    };
+    if path.len() > addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "NOTIFY_SOCKET path is too long",
+        ));
+    }
+    for (dst, src) in addr.sun_path[1..].iter_mut().zip(&path[1..]) {
+        *dst = *src as c_char;
+    }
+    let addr_len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+    // Safety: addr is a valid sockaddr_un, and addr_len doesn't exceed it
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            addr_len as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,1233 @@
+//! Tests of the gesture handling, using the fake devices from [`crate::mock`].
+
+use std::time::{Duration, Instant};
+
+use evdev_rs::enums::{EventCode, EV_ABS, EV_KEY, EV_MSC, EV_SYN};
+use evdev_rs::InputEvent;
+
+use crate::config::Config;
+use crate::control::ControlCommand;
+use crate::mock::{event, time, KeyEvent, MockEventSource, MockKeyboard, MockLed};
+use crate::numpad_layout::{BBox, NumpadLayout};
+use crate::replay::parse_evtest;
+use crate::touchpad_i2c::Brightness;
+use crate::{apply_top_row, CurKey, FingerState, Numpad};
+
+type MockNumpad = Numpad<MockEventSource, MockKeyboard, MockLed>;
+
+/// M433IA layout on a 1000x1000 touchpad, with the numlock bbox at `x: 950.., y: ..90`.
+fn numpad(config: Config) -> MockNumpad {
+    Numpad::new(
+        MockEventSource::default(),
+        MockEventSource::default(),
+        MockLed::default(),
+        MockKeyboard::default(),
+        NumpadLayout::m433ia(BBox::new(0, 1000, 0, 1000)),
+        config,
+        None,
+    )
+}
+
+/// Handle the events as if they were read from the touchpad.
+fn feed(numpad: &mut MockNumpad, events: Vec<InputEvent>) {
+    numpad.evdev.extend(events);
+    assert!(numpad.read_touchpad_events().unwrap());
+}
+
+/// Events of a finger touching the given position.
+fn finger_down(millis: i64, x: i32, y: i32) -> Vec<InputEvent> {
+    vec![
+        event(millis, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), x),
+        event(millis, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), y),
+        event(millis, EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER), 1),
+        event(millis, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), 0),
+        event(millis, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+    ]
+}
+
+/// Events of the touchpad reporting that the finger is still there.
+fn finger_held(millis: i64) -> Vec<InputEvent> {
+    vec![
+        event(millis, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), 0),
+        event(millis, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+    ]
+}
+
+/// Events of the finger moving to the given position.
+fn finger_moved(millis: i64, x: i32, y: i32) -> Vec<InputEvent> {
+    vec![
+        event(millis, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), x),
+        event(millis, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), y),
+        event(millis, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), 0),
+        event(millis, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+    ]
+}
+
+fn finger_up(millis: i64) -> Vec<InputEvent> {
+    vec![
+        event(millis, EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER), 0),
+        event(millis, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+    ]
+}
+
+#[test]
+fn test_hold_numlock_bbox_toggles() {
+    let mut numpad = numpad(Config::default());
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(100));
+    assert!(!numpad.state.numlock);
+
+    feed(&mut numpad, finger_held(300));
+    feed(&mut numpad, finger_up(350));
+    assert!(numpad.state.numlock);
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK)
+        ]
+    );
+}
+
+#[test]
+fn test_hold_toggles_once_at_hold_duration() {
+    let mut numpad = numpad(Config::default());
+    feed(&mut numpad, finger_down(0, 975, 45));
+    // the touchpad reports the finger every few milliseconds
+    for millis in (10..250).step_by(10) {
+        feed(&mut numpad, finger_held(millis));
+        assert!(!numpad.state.numlock, "toggled after {}ms", millis);
+    }
+    // toggles while the finger is still down
+    feed(&mut numpad, finger_held(250));
+    assert!(numpad.state.numlock);
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+
+    // holding longer doesn't toggle it back
+    for millis in (260..1000).step_by(10) {
+        feed(&mut numpad, finger_held(millis));
+    }
+    feed(&mut numpad, finger_up(1000));
+    assert!(numpad.state.numlock);
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+}
+
+#[test]
+fn test_short_hold_numlock_bbox_does_not_toggle() {
+    let mut numpad = numpad(Config::default());
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(200));
+    feed(&mut numpad, finger_up(220));
+    assert!(!numpad.state.numlock);
+    assert!(numpad.touchpad_i2c.brightness.is_empty());
+    assert!(numpad.dummy_kb.events.is_empty());
+}
+
+#[test]
+fn test_drag_to_new_key() {
+    let config: Config = toml::from_str("drag_to_new_key = true").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.numlock = true;
+    // centers of the 7 and 8 keys
+    feed(&mut numpad, finger_down(0, 140, 209));
+    feed(&mut numpad, finger_moved(50, 320, 209));
+    feed(&mut numpad, finger_up(100));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+            KeyEvent::Down(EV_KEY::KEY_KP8),
+            KeyEvent::Up(EV_KEY::KEY_KP8),
+        ]
+    );
+}
+
+#[test]
+fn test_drag_across_keys() {
+    let config: Config = toml::from_str("drag_to_new_key = true").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.numlock = true;
+    // centers of the 7, 8 and 9 keys, checking each transition as it happens
+    feed(&mut numpad, finger_down(0, 140, 209));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![KeyEvent::Down(EV_KEY::KEY_KP7)]
+    );
+    numpad.dummy_kb.events.clear();
+
+    feed(&mut numpad, finger_moved(50, 320, 209));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+            KeyEvent::Down(EV_KEY::KEY_KP8),
+        ]
+    );
+    numpad.dummy_kb.events.clear();
+
+    feed(&mut numpad, finger_moved(100, 500, 209));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Up(EV_KEY::KEY_KP8),
+            KeyEvent::Down(EV_KEY::KEY_KP9),
+        ]
+    );
+    numpad.dummy_kb.events.clear();
+
+    feed(&mut numpad, finger_up(150));
+    assert_eq!(numpad.dummy_kb.events, vec![KeyEvent::Up(EV_KEY::KEY_KP9)]);
+    assert_eq!(numpad.state.finger_state, FingerState::Lifted);
+}
+
+#[test]
+fn test_auto_disable() {
+    let config: Config = toml::from_str("auto_disable_secs = 10").unwrap();
+    let mut numpad = numpad(config);
+    // starts counting when numlock is turned on
+    numpad.toggle_numlock(time(0)).unwrap();
+    assert_eq!(
+        numpad.auto_disable_timeout(time(4000)),
+        Some(Duration::from_secs(6))
+    );
+    feed(&mut numpad, finger_down(5000, 140, 209));
+    feed(&mut numpad, finger_up(5050));
+    assert!(numpad.state.numlock);
+
+    // the touch doesn't press a key after the timeout
+    feed(&mut numpad, finger_down(15100, 140, 209));
+    feed(&mut numpad, finger_up(15150));
+    assert!(!numpad.state.numlock);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK),
+        ]
+    );
+
+    // and without a touch, once the event loop wakes up for it
+    numpad.toggle_numlock(time(20000)).unwrap();
+    assert!(!numpad.auto_disable(time(29000)).unwrap());
+    assert!(numpad.auto_disable(time(30000)).unwrap());
+    assert!(!numpad.state.numlock);
+    assert_eq!(numpad.auto_disable_timeout(time(30000)), None);
+}
+
+#[test]
+fn test_second_finger_ignored() {
+    let config: Config = toml::from_str("drag_to_new_key = true").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.numlock = true;
+    let mut events = vec![
+        event(0, EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 0),
+        event(0, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 10),
+    ];
+    events.extend(finger_down(0, 140, 209));
+    feed(&mut numpad, events);
+    // second finger over the 9 key
+    feed(
+        &mut numpad,
+        vec![
+            event(50, EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 1),
+            event(50, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 11),
+            event(50, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), 500),
+            event(50, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), 209),
+            event(50, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ],
+    );
+    assert_eq!(numpad.state.pos.x, 140);
+    // first finger is lifted, the second one becomes the primary
+    let mut events = vec![
+        event(100, EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 0),
+        event(100, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1),
+    ];
+    events.extend(finger_up(100));
+    feed(&mut numpad, events);
+    assert_eq!(numpad.state.pos.x, 500);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+        ]
+    );
+}
+
+#[test]
+fn test_toggle_blink() {
+    let config: Config = toml::from_str("toggle_blink = true").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.brightness = Brightness::Low;
+    numpad.toggle_numlock(time(0)).unwrap();
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+
+    // the brightness is restored once the blink is over, without blocking until then
+    numpad.run_led_steps(Instant::now());
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+    assert!(numpad.led_timeout(Instant::now()).is_some());
+    numpad.run_led_steps(Instant::now() + Duration::from_secs(1));
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Full, Brightness::Low]
+    );
+    assert_eq!(numpad.led_timeout(Instant::now()), None);
+}
+
+#[test]
+fn test_brightness_percent_used_on_toggle() {
+    let config: Config = toml::from_str("brightness_percent = 50").unwrap();
+    let mut numpad = numpad(config);
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(300));
+    feed(&mut numpad, finger_up(350));
+    assert!(numpad.state.numlock);
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Half]);
+}
+
+#[test]
+fn test_quick_retoggle_ignored() {
+    let config: Config =
+        toml::from_str("hold_duration_ms = 100\nmin_toggle_interval_ms = 500").unwrap();
+    let mut numpad = numpad(config);
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(100));
+    feed(&mut numpad, finger_up(120));
+    assert!(numpad.state.numlock);
+
+    // too soon after the first toggle
+    feed(&mut numpad, finger_down(150, 975, 45));
+    feed(&mut numpad, finger_held(260));
+    feed(&mut numpad, finger_up(280));
+    assert!(numpad.state.numlock);
+
+    feed(&mut numpad, finger_down(700, 975, 45));
+    feed(&mut numpad, finger_held(810));
+    feed(&mut numpad, finger_up(830));
+    assert!(!numpad.state.numlock);
+}
+
+#[test]
+fn test_momentary_mode() {
+    let config: Config = toml::from_str("mode = \"momentary\"").unwrap();
+    let mut numpad = numpad(config);
+    let mut events = vec![
+        event(0, EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 0),
+        event(0, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 10),
+    ];
+    events.extend(finger_down(0, 975, 45));
+    feed(&mut numpad, events);
+    feed(&mut numpad, finger_held(300));
+    assert!(numpad.state.numlock);
+    assert!(numpad.evdev.grabbed);
+
+    // second finger presses the 7 key
+    feed(
+        &mut numpad,
+        vec![
+            event(400, EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 1),
+            event(400, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 11),
+            event(400, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_X), 140),
+            event(400, EventCode::EV_ABS(EV_ABS::ABS_MT_POSITION_Y), 209),
+            event(400, EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER), 0),
+            event(400, EventCode::EV_KEY(EV_KEY::BTN_TOOL_DOUBLETAP), 1),
+            event(400, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ],
+    );
+    feed(
+        &mut numpad,
+        vec![
+            event(450, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1),
+            event(450, EventCode::EV_KEY(EV_KEY::BTN_TOOL_DOUBLETAP), 0),
+            event(450, EventCode::EV_KEY(EV_KEY::BTN_TOOL_FINGER), 1),
+            event(450, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ],
+    );
+    assert!(numpad.state.numlock);
+
+    // lifting the holding finger turns numlock off
+    let mut events = vec![
+        event(500, EventCode::EV_ABS(EV_ABS::ABS_MT_SLOT), 0),
+        event(500, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1),
+    ];
+    events.extend(finger_up(500));
+    feed(&mut numpad, events);
+    assert!(!numpad.state.numlock);
+    assert!(!numpad.evdev.grabbed);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK),
+        ]
+    );
+}
+
+#[test]
+fn test_notify() {
+    let mut numpad = numpad(Config::default());
+    numpad.state.numlock = true;
+    numpad.state.brightness = Brightness::Low;
+    numpad
+        .handle_control_command("notify 1".parse().unwrap())
+        .unwrap();
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+    numpad.run_led_steps(Instant::now() + Duration::from_millis(200));
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Full, Brightness::Zero]
+    );
+    numpad.run_led_steps(Instant::now() + Duration::from_secs(1));
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Full, Brightness::Zero, Brightness::Low]
+    );
+
+    // waits for the key press to finish
+    numpad.touchpad_i2c.brightness.clear();
+    feed(&mut numpad, finger_down(0, 140, 209));
+    numpad
+        .handle_control_command(ControlCommand::Notify(1))
+        .unwrap();
+    assert!(numpad.touchpad_i2c.brightness.is_empty());
+    feed(&mut numpad, finger_up(50));
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+    numpad.run_led_steps(Instant::now() + Duration::from_secs(1));
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Full, Brightness::Zero, Brightness::Low]
+    );
+    assert!("notify 0".parse::<ControlCommand>().is_err());
+    assert_eq!(
+        "notify".parse::<ControlCommand>().unwrap(),
+        ControlCommand::Notify(2)
+    );
+}
+
+#[test]
+fn test_top_row_with_brightness_slide() {
+    let mut layout = NumpadLayout::m433ia(BBox::new(0, 1000, 0, 1000));
+    let config: Config = toml::from_str(r#"top_row_keys = ["KEY_CALC"]"#).unwrap();
+    apply_top_row(&mut layout, &config);
+    assert!(layout.emitted_keys().contains(&EV_KEY::KEY_CALC));
+
+    // the slide takes the band, until it's turned off again
+    let with_slide: Config =
+        toml::from_str("top_row_keys = [\"KEY_CALC\"]\nbrightness_slide = true").unwrap();
+    apply_top_row(&mut layout, &with_slide);
+    assert!(!layout.emitted_keys().contains(&EV_KEY::KEY_CALC));
+    apply_top_row(&mut layout, &config);
+    assert!(layout.emitted_keys().contains(&EV_KEY::KEY_CALC));
+
+    // and the layout has none of its own
+    apply_top_row(&mut layout, &Config::default());
+    assert!(!layout.emitted_keys().contains(&EV_KEY::KEY_CALC));
+}
+
+#[test]
+fn test_brightness_fade() {
+    let config: Config = toml::from_str("brightness_fade_ms = 300").unwrap();
+    let mut numpad = numpad(config);
+    numpad.toggle_numlock(time(0)).unwrap();
+    // the brightness wasn't known yet, so there's nothing to fade from
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+
+    // through the level in between, without blocking until the end
+    numpad
+        .handle_control_command(ControlCommand::Brightness(Brightness::Low))
+        .unwrap();
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Full, Brightness::Half]
+    );
+    numpad.run_led_steps(Instant::now() + Duration::from_secs(1));
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Full, Brightness::Half, Brightness::Low]
+    );
+}
+
+#[test]
+fn test_led_failure_keeps_brightness() {
+    let mut numpad = numpad(Config::default());
+    numpad.state.numlock = true;
+    numpad.touchpad_i2c.fail = true;
+    numpad
+        .handle_control_command(ControlCommand::Brightness(Brightness::Half))
+        .unwrap_err();
+    assert_eq!(numpad.state.brightness, Brightness::Full);
+
+    // the write is retried once, with the brightness that was kept
+    numpad.touchpad_i2c.fail = false;
+    numpad.run_led_steps(Instant::now() + Duration::from_secs(1));
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+    numpad.touchpad_i2c.fail = true;
+
+    // the write is retried once, with the brightness that was kept
+    numpad.touchpad_i2c.fail = false;
+    numpad.run_led_steps(Instant::now() + Duration::from_secs(1));
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Full]);
+    numpad.touchpad_i2c.fail = true;
+
+    // toggling still works without the LED
+    numpad.toggle_numlock(time(0)).unwrap();
+    assert!(!numpad.state.numlock);
+}
+
+#[test]
+fn test_disable_while_typing() {
+    let config: Config = toml::from_str("disable_while_typing_ms = 500").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.numlock = true;
+    numpad.state.last_kbd_key_at = Some(event(0, EventCode::EV_KEY(EV_KEY::KEY_A), 1).time);
+    feed(&mut numpad, finger_down(100, 140, 209));
+    feed(&mut numpad, finger_up(150));
+    assert!(numpad.dummy_kb.events.is_empty());
+
+    feed(&mut numpad, finger_down(700, 140, 209));
+    feed(&mut numpad, finger_up(750));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+        ]
+    );
+}
+
+#[test]
+fn test_corner_swipe() {
+    let config: Config =
+        toml::from_str("corner_swipes = [{corner = \"bottom_left\", key = \"KEY_ESC\"}]").unwrap();
+    let mut numpad = numpad(config);
+    feed(&mut numpad, finger_down(0, 20, 980));
+    feed(&mut numpad, finger_moved(50, 150, 900));
+    feed(&mut numpad, finger_moved(80, 200, 850));
+    feed(&mut numpad, finger_up(100));
+    assert!(!numpad.state.numlock);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_ESC),
+            KeyEvent::Up(EV_KEY::KEY_ESC)
+        ]
+    );
+
+    // a slow swipe doesn't count
+    feed(&mut numpad, finger_down(1000, 20, 980));
+    feed(&mut numpad, finger_held(1300));
+    feed(&mut numpad, finger_moved(1350, 200, 850));
+    feed(&mut numpad, finger_up(1400));
+    assert_eq!(numpad.dummy_kb.events.len(), 2);
+}
+
+#[test]
+fn test_hold_toggle_does_not_press_key() {
+    let mut numpad = numpad(Config::default());
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(300));
+    assert!(numpad.state.numlock);
+    // the same touch moves to 7 and presses down
+    feed(&mut numpad, finger_moved(350, 140, 209));
+    feed(
+        &mut numpad,
+        vec![
+            event(400, EventCode::EV_ABS(EV_ABS::ABS_MT_PRESSURE), 100),
+            event(400, EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), 0),
+            event(400, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ],
+    );
+    feed(&mut numpad, finger_up(450));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK)
+        ]
+    );
+
+    // the next touch presses the key
+    feed(&mut numpad, finger_down(1000, 140, 209));
+    feed(&mut numpad, finger_up(1050));
+    assert_eq!(
+        numpad.dummy_kb.events[2..],
+        [
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7)
+        ]
+    );
+}
+
+#[test]
+fn test_toggle_hotkey() {
+    let config: Config = toml::from_str(r#"toggle_hotkey = "KEY_LEFTMETA+KEY_N""#).unwrap();
+    let mut numpad = numpad(config);
+    let time = event(0, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0).time;
+    numpad.on_keyboard_key(EV_KEY::KEY_N, 1, time).unwrap();
+    assert!(!numpad.state.numlock);
+
+    numpad
+        .on_keyboard_key(EV_KEY::KEY_LEFTMETA, 1, time)
+        .unwrap();
+    numpad.on_keyboard_key(EV_KEY::KEY_N, 1, time).unwrap();
+    assert!(numpad.state.numlock);
+    // autorepeats don't toggle again
+    numpad.on_keyboard_key(EV_KEY::KEY_N, 2, time).unwrap();
+    numpad.on_keyboard_key(EV_KEY::KEY_N, 0, time).unwrap();
+    assert!(numpad.state.numlock);
+
+    numpad
+        .on_keyboard_key(EV_KEY::KEY_LEFTMETA, 0, time)
+        .unwrap();
+    numpad.on_keyboard_key(EV_KEY::KEY_N, 1, time).unwrap();
+    assert!(numpad.state.numlock);
+}
+
+#[test]
+fn test_finger_state_transitions() {
+    let mut numpad = numpad(Config::default());
+    numpad.state.numlock = true;
+    assert_eq!(numpad.state.finger_state, FingerState::Lifted);
+
+    // touching a key presses it
+    feed(&mut numpad, finger_down(0, 140, 209));
+    assert_eq!(numpad.state.finger_state, FingerState::Touching);
+    assert_eq!(numpad.state.cur_key, CurKey::Numpad(EV_KEY::KEY_KP7));
+    feed(&mut numpad, finger_up(50));
+    assert_eq!(numpad.state.finger_state, FingerState::Lifted);
+    assert_eq!(numpad.state.cur_key, CurKey::None);
+
+    // touching the margins below the keys doesn't press anything
+    feed(&mut numpad, finger_down(100, 500, 990));
+    assert_eq!(numpad.state.finger_state, FingerState::TouchStart);
+    assert_eq!(numpad.state.cur_key, CurKey::None);
+    feed(&mut numpad, finger_up(150));
+
+    // moving too much releases the key, even though the finger is still down
+    feed(&mut numpad, finger_down(200, 140, 209));
+    feed(&mut numpad, finger_moved(250, 140, 600));
+    assert_eq!(numpad.state.finger_state, FingerState::Lifted);
+    assert!(numpad.state.finger_dragged_too_much);
+    feed(&mut numpad, finger_up(300));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+        ]
+    );
+}
+
+#[test]
+fn test_finger_state_after_hold() {
+    let mut numpad = numpad(Config::default());
+    feed(&mut numpad, finger_down(0, 975, 45));
+    assert_eq!(numpad.state.finger_state, FingerState::Touching);
+    assert_eq!(numpad.state.cur_key, CurKey::Numlock);
+
+    // the hold finished, so the rest of the touch doesn't toggle again
+    feed(&mut numpad, finger_held(300));
+    assert!(numpad.state.numlock);
+    assert_eq!(numpad.state.finger_state, FingerState::TouchStart);
+    feed(&mut numpad, finger_held(600));
+    assert!(numpad.state.numlock);
+    feed(&mut numpad, finger_up(650));
+    assert_eq!(numpad.state.finger_state, FingerState::Lifted);
+}
+
+#[test]
+fn test_layer_corner() {
+    let config: Config = toml::from_str(
+        r#"
+        layer_corner = "bottom_left"
+        layers = [[
+            ["KEY_HOME", "KEY_UP", "KEY_PAGEUP", "KEY_MUTE", "KEY_BACKSPACE"],
+            ["KEY_LEFT", "KEY_ENTER", "KEY_RIGHT", "KEY_VOLUMEUP", "KEY_BACKSPACE"],
+            ["KEY_END", "KEY_DOWN", "KEY_PAGEDOWN", "KEY_VOLUMEDOWN", "KEY_5"],
+            ["KEY_INSERT", "KEY_DELETE", "KEY_ENTER", "KEY_PLAYPAUSE", "KEY_EQUAL"],
+        ]]
+        "#,
+    )
+    .unwrap();
+    let mut numpad = numpad(config);
+    for keys in numpad.config.layers().to_vec() {
+        numpad.layout.add_layer(keys).unwrap();
+    }
+    numpad.state.numlock = true;
+    // a short tap in the corner presses the 0 on lift
+    feed(&mut numpad, finger_down(0, 75, 950));
+    assert!(numpad.dummy_kb.events.is_empty());
+    feed(&mut numpad, finger_up(50));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP0),
+            KeyEvent::Up(EV_KEY::KEY_KP0)
+        ]
+    );
+
+    // holding it switches the layer
+    feed(&mut numpad, finger_down(1000, 75, 950));
+    feed(&mut numpad, finger_held(1300));
+    feed(&mut numpad, finger_up(1350));
+    assert_eq!(numpad.state.active_layer, 1);
+    feed(&mut numpad, finger_down(2000, 140, 209));
+    feed(&mut numpad, finger_up(2050));
+    assert_eq!(
+        numpad.dummy_kb.events[2..],
+        [
+            KeyEvent::Down(EV_KEY::KEY_HOME),
+            KeyEvent::Up(EV_KEY::KEY_HOME)
+        ]
+    );
+
+    // and back to the digits
+    feed(&mut numpad, finger_down(3000, 75, 950));
+    feed(&mut numpad, finger_held(3300));
+    feed(&mut numpad, finger_up(3350));
+    assert_eq!(numpad.state.active_layer, 0);
+    assert_eq!(numpad.dummy_kb.events.len(), 4);
+}
+
+#[test]
+fn test_grab_failure_turns_numlock_off() {
+    let mut numpad = numpad(Config::default());
+    numpad.state.numlock = true;
+    numpad.evdev.grab_busy = true;
+    feed(&mut numpad, finger_down(0, 140, 209));
+    feed(&mut numpad, finger_up(50));
+    assert!(!numpad.state.numlock);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK)
+        ]
+    );
+}
+
+#[test]
+fn test_hold_drag_brightness() {
+    let config: Config = toml::from_str("hold_drag_brightness = true").unwrap();
+    let mut numpad = numpad(config);
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(300));
+    assert!(numpad.state.numlock);
+    // dragging down dims the LED one level per tenth of the touchpad height, down to Low
+    feed(&mut numpad, finger_moved(350, 975, 100));
+    feed(&mut numpad, finger_moved(400, 975, 150));
+    feed(&mut numpad, finger_moved(450, 975, 400));
+    assert_eq!(numpad.state.brightness, Brightness::Low);
+    // and dragging up brightens it
+    feed(&mut numpad, finger_moved(500, 975, 290));
+    feed(&mut numpad, finger_up(550));
+    assert_eq!(numpad.state.brightness, Brightness::Half);
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![
+            Brightness::Full,
+            Brightness::Half,
+            Brightness::Low,
+            Brightness::Half
+        ]
+    );
+    // no keys other than numlock are pressed by the drag
+    assert_eq!(numpad.dummy_kb.events.len(), 2);
+}
+
+#[test]
+fn test_hold_drag_brightness_grab_failure() {
+    let config: Config = toml::from_str("hold_drag_brightness = true").unwrap();
+    let mut numpad = numpad(config);
+    numpad.evdev.grab_busy = true;
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(300));
+    // the drag would move the pointer, so numlock is turned back off
+    assert!(!numpad.state.numlock);
+    feed(&mut numpad, finger_moved(350, 975, 150));
+    feed(&mut numpad, finger_up(400));
+    assert_eq!(numpad.state.brightness, Brightness::Full);
+}
+
+#[test]
+fn test_numlock_margin() {
+    let mut numpad = numpad(Config::default());
+    numpad.layout.set_numlock_margin(0.2);
+    // the bbox is x: 950..=1000, y: 0..=90, so 10 units along x are the margin
+    feed(&mut numpad, finger_down(0, 955, 45));
+    feed(&mut numpad, finger_held(300));
+    feed(&mut numpad, finger_up(350));
+    assert!(!numpad.state.numlock);
+
+    feed(&mut numpad, finger_down(1000, 975, 45));
+    feed(&mut numpad, finger_held(1300));
+    feed(&mut numpad, finger_up(1350));
+    assert!(numpad.state.numlock);
+}
+
+#[test]
+fn test_invert_y() {
+    let mut numpad = numpad(Config::default());
+    numpad.layout.set_invert_y(true);
+    numpad.state.numlock = true;
+    // the bottom left key (0) is reported at the top
+    feed(&mut numpad, finger_down(0, 140, 1000 - 845));
+    feed(&mut numpad, finger_up(50));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP0),
+            KeyEvent::Up(EV_KEY::KEY_KP0)
+        ]
+    );
+}
+
+#[test]
+fn test_pointer_while_numlock() {
+    let config: Config = toml::from_str("pointer_while_numlock = true").unwrap();
+    let mut numpad = numpad(config);
+    numpad.toggle_numlock(time(0)).unwrap();
+    assert!(numpad.evdev.grabbed);
+    // dragging off the 7 key releases it, and then moves the pointer
+    feed(&mut numpad, finger_down(0, 140, 209));
+    feed(&mut numpad, finger_moved(50, 340, 209));
+    feed(&mut numpad, finger_moved(100, 440, 309));
+    feed(&mut numpad, finger_up(150));
+    // a touch that doesn't press a key moves it too
+    feed(&mut numpad, finger_down(500, 500, 980));
+    feed(&mut numpad, finger_moved(550, 300, 980));
+    feed(&mut numpad, finger_moved(600, 200, 880));
+    feed(&mut numpad, finger_up(650));
+    assert!(numpad.evdev.grabbed);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+            KeyEvent::Pointer(150, 150),
+            KeyEvent::Pointer(-150, -150),
+        ]
+    );
+}
+
+#[test]
+fn test_tap_in_numlock_bbox_over_key() {
+    let mut numpad = numpad(Config::default());
+    // over the backspace key in the top right
+    numpad.layout.set_numlock_bbox(0.77, 0.1, 0.95, 0.3);
+    numpad.state.numlock = true;
+    // a short tap types the key under the finger
+    feed(&mut numpad, finger_down(0, 860, 209));
+    feed(&mut numpad, finger_held(100));
+    assert!(numpad.dummy_kb.events.is_empty());
+    feed(&mut numpad, finger_up(150));
+    assert!(numpad.state.numlock);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_BACKSPACE),
+            KeyEvent::Up(EV_KEY::KEY_BACKSPACE)
+        ]
+    );
+
+    // a hold toggles numlock without typing it
+    numpad.dummy_kb.events.clear();
+    feed(&mut numpad, finger_down(1000, 860, 209));
+    feed(&mut numpad, finger_held(1300));
+    feed(&mut numpad, finger_up(1350));
+    assert!(!numpad.state.numlock);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK)
+        ]
+    );
+}
+
+#[test]
+fn test_double_tap_in_numlock_bbox_over_key() {
+    let config: Config = toml::from_str(r#"toggle_mode = "either""#).unwrap();
+    let mut numpad = numpad(config);
+    // over the backspace key in the top right
+    numpad.layout.set_numlock_bbox(0.77, 0.1, 0.95, 0.3);
+    numpad.state.numlock = true;
+    // the double tap turns numlock off without typing the key
+    feed(&mut numpad, finger_down(0, 860, 209));
+    feed(&mut numpad, finger_up(50));
+    assert!(numpad.dummy_kb.events.is_empty());
+    feed(&mut numpad, finger_down(150, 860, 209));
+    feed(&mut numpad, finger_up(200));
+    assert!(!numpad.state.numlock);
+    numpad.type_pending_tap_key(time(1000));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK)
+        ]
+    );
+
+    // a single tap types the key once a second tap can't follow anymore
+    numpad.state.numlock = true;
+    numpad.dummy_kb.events.clear();
+    feed(&mut numpad, finger_down(2000, 860, 209));
+    feed(&mut numpad, finger_up(2050));
+    numpad.type_pending_tap_key(time(2100));
+    assert!(numpad.dummy_kb.events.is_empty());
+    numpad.type_pending_tap_key(time(3000));
+    let backspace = vec![
+        KeyEvent::Down(EV_KEY::KEY_BACKSPACE),
+        KeyEvent::Up(EV_KEY::KEY_BACKSPACE),
+    ];
+    assert_eq!(numpad.dummy_kb.events, backspace);
+
+    // or before the next touch, if that comes first
+    numpad.dummy_kb.events.clear();
+    feed(&mut numpad, finger_down(4000, 860, 209));
+    feed(&mut numpad, finger_up(4050));
+    feed(&mut numpad, finger_down(4100, 140, 209));
+    feed(&mut numpad, finger_up(4150));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        [
+            backspace,
+            vec![
+                KeyEvent::Down(EV_KEY::KEY_KP7),
+                KeyEvent::Up(EV_KEY::KEY_KP7)
+            ]
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_regrab_after_grab_lost() {
+    let mut numpad = numpad(Config::default());
+    numpad.state.numlock = true;
+    feed(&mut numpad, finger_down(0, 140, 209));
+    feed(&mut numpad, finger_up(50));
+    assert!(numpad.evdev.grabbed);
+
+    // like after a device reset
+    numpad.evdev.grabbed = false;
+    numpad.check_grab();
+    assert!(numpad.evdev.grabbed);
+
+    // not grabbed again after ungrabbing on purpose
+    numpad.toggle_numlock(time(0)).unwrap();
+    assert!(!numpad.evdev.grabbed);
+    numpad.check_grab();
+    assert!(!numpad.evdev.grabbed);
+}
+
+#[test]
+fn test_disable_calc_bbox_features() {
+    let drag_calc = |numpad: &mut MockNumpad| {
+        feed(numpad, finger_down(0, 25, 45));
+        feed(numpad, finger_moved(50, 400, 45));
+        feed(numpad, finger_up(100));
+    };
+    let mut enabled = numpad(Config::default());
+    drag_calc(&mut enabled);
+    assert_eq!(
+        enabled.dummy_kb.events,
+        vec![
+            KeyEvent::MultiDown(vec![EV_KEY::KEY_CALC]),
+            KeyEvent::MultiUp(vec![EV_KEY::KEY_CALC])
+        ]
+    );
+
+    let config: Config =
+        toml::from_str("enable_calc_key = false\nenable_brightness_cycle = false").unwrap();
+    let mut numpad = numpad(config);
+    drag_calc(&mut numpad);
+    assert!(numpad.dummy_kb.events.is_empty());
+    // holding it doesn't cycle the brightness either
+    numpad.state.numlock = true;
+    feed(&mut numpad, finger_down(1000, 25, 45));
+    feed(&mut numpad, finger_held(1300));
+    feed(&mut numpad, finger_up(1350));
+    assert!(numpad.touchpad_i2c.brightness.is_empty());
+    assert_eq!(numpad.state.brightness, Brightness::Full);
+}
+
+#[test]
+fn test_read_errors() {
+    let mut numpad = numpad(Config::default());
+    numpad.state.numlock = true;
+    // a transient error only delays the events
+    numpad.evdev.failed_reads = 1;
+    numpad.evdev.extend(finger_down(0, 140, 209));
+    assert!(numpad.read_touchpad_events().unwrap());
+    assert!(numpad.dummy_kb.events.is_empty());
+    feed(&mut numpad, finger_up(50));
+    assert_eq!(numpad.dummy_kb.events.len(), 2);
+
+    // persistent errors are treated like a disconnect
+    numpad.evdev.failed_reads = 5;
+    for _ in 0..4 {
+        assert!(numpad.read_touchpad_events().unwrap());
+    }
+    assert!(!numpad.read_touchpad_events().unwrap());
+}
+
+#[test]
+fn test_emit_numlock_key_disabled() {
+    let config: Config = toml::from_str("emit_numlock_key = false").unwrap();
+    let mut numpad = numpad(config);
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(300));
+    feed(&mut numpad, finger_up(350));
+    assert!(numpad.state.numlock);
+    assert!(numpad.dummy_kb.events.is_empty());
+}
+
+#[test]
+fn test_drop_cleans_up() {
+    let mut evdev = MockEventSource::default();
+    let mut keyboard_evdev = MockEventSource::default();
+    let mut led = MockLed::default();
+    {
+        let config: Config = toml::from_str("pointer_while_numlock = true").unwrap();
+        let mut numpad = Numpad::new(
+            &mut evdev,
+            &mut keyboard_evdev,
+            &mut led,
+            MockKeyboard::default(),
+            NumpadLayout::m433ia(BBox::new(0, 1000, 0, 1000)),
+            config,
+            None,
+        );
+        numpad.toggle_numlock(time(0)).unwrap();
+        assert!(numpad.evdev.grabbed);
+    }
+    assert!(!evdev.grabbed);
+    assert_eq!(led.brightness.last(), Some(&Brightness::Zero));
+}
+
+#[test]
+fn test_brightness_levels() {
+    let config: Config = toml::from_str("brightness_levels = {on = \"Low\"}").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.brightness = Brightness::Half;
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(300));
+    feed(&mut numpad, finger_up(350));
+    assert!(numpad.state.numlock);
+    assert_eq!(numpad.touchpad_i2c.brightness, vec![Brightness::Low]);
+
+    // the system turning numlock on keeps the last brightness, since there's no level for it
+    numpad.handle_numlock_pressed(0, time(0));
+    numpad.handle_numlock_pressed(1, time(0));
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Low, Brightness::Zero, Brightness::Low]
+    );
+}
+
+#[test]
+fn test_replay_capture() {
+    // captured with the touchpad range scaled to the 0..1000 of the test layout
+    let capture = include_str!("../testdata/numlock_then_kp7.evtest");
+    let mut numpad = numpad(Config::default());
+    feed(&mut numpad, parse_evtest(capture).unwrap());
+    assert!(numpad.state.numlock);
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+        ]
+    );
+}
+
+#[test]
+fn test_external_mouse_turns_numlock_off() {
+    let mut numpad = numpad(Config::default());
+    numpad.toggle_numlock(time(0)).unwrap();
+    numpad.on_external_mouse(None).unwrap();
+    assert!(numpad.state.numlock);
+
+    numpad
+        .on_external_mouse(Some("USB Mouse".to_string()))
+        .unwrap();
+    assert!(!numpad.state.numlock);
+
+    // turning it back on while the mouse is still plugged in keeps it on
+    numpad.toggle_numlock(time(0)).unwrap();
+    numpad
+        .on_external_mouse(Some("USB Mouse".to_string()))
+        .unwrap();
+    assert!(numpad.state.numlock);
+}
+
+#[test]
+fn test_release_on_leave() {
+    let config: Config = toml::from_str("release_on_leave = true").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.numlock = true;
+    feed(&mut numpad, finger_down(0, 140, 209));
+    // too little to count as dragging, but off the key
+    feed(&mut numpad, finger_moved(50, 235, 209));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+        ]
+    );
+    // moving back doesn't press it again
+    feed(&mut numpad, finger_moved(80, 140, 209));
+    feed(&mut numpad, finger_up(100));
+    assert_eq!(numpad.dummy_kb.events.len(), 2);
+}
+
+#[test]
+fn test_tracking_id_lift() {
+    let mut numpad = numpad(Config::default());
+    numpad.state.numlock = true;
+    let mut down = vec![event(0, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), 5)];
+    down.extend(finger_down(0, 140, 209));
+    feed(&mut numpad, down);
+    // no BTN_TOOL_FINGER release
+    feed(
+        &mut numpad,
+        vec![
+            event(50, EventCode::EV_ABS(EV_ABS::ABS_MT_TRACKING_ID), -1),
+            event(50, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ],
+    );
+    assert_eq!(numpad.state.finger_state, FingerState::Lifted);
+    // a late BTN_TOOL_FINGER release doesn't release the key again
+    feed(&mut numpad, finger_up(50));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+        ]
+    );
+}
+
+#[test]
+fn test_toggle_jitter() {
+    let config: Config = toml::from_str("toggle_jitter = 0.02").unwrap();
+    let mut numpad = numpad(config);
+    // small movements are fine
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_moved(100, 980, 50));
+    feed(&mut numpad, finger_held(300));
+    feed(&mut numpad, finger_up(350));
+    assert!(numpad.state.numlock);
+
+    // dragging within the bbox cancels the toggle
+    feed(&mut numpad, finger_down(1000, 960, 30));
+    feed(&mut numpad, finger_moved(1100, 990, 60));
+    feed(&mut numpad, finger_held(1300));
+    feed(&mut numpad, finger_up(1350));
+    assert!(numpad.state.numlock);
+}
+
+#[test]
+fn test_activation_grace() {
+    let config: Config = toml::from_str("activation_grace_ms = 500").unwrap();
+    let mut numpad = numpad(config);
+    feed(&mut numpad, finger_down(0, 975, 45));
+    feed(&mut numpad, finger_held(250));
+    feed(&mut numpad, finger_up(300));
+    assert!(numpad.state.numlock);
+
+    // within the grace period since the toggle at 250ms
+    feed(&mut numpad, finger_down(600, 140, 209));
+    feed(&mut numpad, finger_up(650));
+    feed(&mut numpad, finger_down(800, 140, 209));
+    feed(&mut numpad, finger_up(850));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Up(EV_KEY::KEY_NUMLOCK),
+            KeyEvent::Down(EV_KEY::KEY_KP7),
+            KeyEvent::Up(EV_KEY::KEY_KP7),
+        ]
+    );
+}
+
+#[test]
+fn test_activation_grace_other_toggles() {
+    let config: Config = toml::from_str(
+        r#"
+        activation_grace_ms = 500
+        toggle_hotkey = "KEY_LEFTMETA+KEY_N"
+        "#,
+    )
+    .unwrap();
+    let mut numpad = numpad(config);
+    // over the backspace key in the top right
+    numpad.layout.set_numlock_bbox(0.77, 0.1, 0.95, 0.3);
+    numpad
+        .on_keyboard_key(EV_KEY::KEY_LEFTMETA, 1, time(0))
+        .unwrap();
+    numpad.on_keyboard_key(EV_KEY::KEY_N, 1, time(0)).unwrap();
+    assert!(numpad.state.numlock);
+    numpad.dummy_kb.events.clear();
+
+    // neither a key nor the key under the numlock bbox is typed during the grace period
+    feed(&mut numpad, finger_down(100, 140, 209));
+    feed(&mut numpad, finger_up(150));
+    feed(&mut numpad, finger_down(200, 860, 209));
+    feed(&mut numpad, finger_up(250));
+    assert!(numpad.dummy_kb.events.is_empty());
+
+    feed(&mut numpad, finger_down(600, 860, 209));
+    feed(&mut numpad, finger_up(650));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_BACKSPACE),
+            KeyEvent::Up(EV_KEY::KEY_BACKSPACE),
+        ]
+    );
+}
+
+#[test]
+fn test_brightness_cell() {
+    let config: Config = toml::from_str("brightness_cell = {row = 0, col = 0}").unwrap();
+    let mut numpad = numpad(config);
+    numpad.state.numlock = true;
+    feed(&mut numpad, finger_down(0, 140, 209));
+    feed(&mut numpad, finger_up(50));
+    assert_eq!(
+        numpad.touchpad_i2c.brightness,
+        vec![Brightness::Full.next()]
+    );
+    assert!(numpad.dummy_kb.events.is_empty());
+
+    // the other keys still work
+    feed(&mut numpad, finger_down(500, 320, 209));
+    feed(&mut numpad, finger_up(550));
+    assert_eq!(
+        numpad.dummy_kb.events,
+        vec![
+            KeyEvent::Down(EV_KEY::KEY_KP8),
+            KeyEvent::Up(EV_KEY::KEY_KP8),
+        ]
+    );
+}
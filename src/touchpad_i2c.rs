@@ -1,11 +1,20 @@
 use std::fmt::Debug;
 use std::io::ErrorKind::{NotFound, PermissionDenied};
+use std::str::FromStr;
 
-use anyhow::{Context, Error, Result};
+use anyhow::{anyhow, Context, Error, Result};
 use i2cdev::core::I2CDevice;
 use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy)]
+use crate::error::NumpadError;
+
+/// Brightness of the LED, with the raw register values.
+///
+/// Deserialized from the names of the levels, ignoring case, see [`FromStr`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String")]
 pub enum Brightness {
     Zero = 0,
     Low = 31,
@@ -32,8 +41,80 @@ impl std::fmt::Display for Brightness {
     }
 }
 
+impl TryFrom<u8> for Brightness {
+    type Error = Error;
+
+    /// Get the brightness level corresponding to the raw register value.
+    fn try_from(raw: u8) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|level| *level as u8 == raw)
+            .ok_or_else(|| anyhow!("Invalid brightness {}, expected 0, 31, 24 or 1", raw))
+    }
+}
+
+impl FromStr for Brightness {
+    type Err = Error;
+
+    /// Parse the level name, ignoring case. `off` is the same as `zero`.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("off") {
+            return Ok(Self::Zero);
+        }
+        Self::ALL
+            .into_iter()
+            .find(|level| level.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow!("Invalid brightness {}, expected off, low, half or full", s))
+    }
+}
+
+impl TryFrom<String> for Brightness {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
 impl Brightness {
-    fn next(&self) -> Self {
+    /// All the levels, from off to brightest.
+    pub const ALL: [Self; 4] = [Self::Zero, Self::Low, Self::Half, Self::Full];
+
+    /// Get the level nearest to a percentage of full brightness.
+    ///
+    /// Only the levels' register values are known to work, so `Low`, `Half` and `Full` stand
+    /// for a third, two thirds and all of it. `0` turns the LED off, and any other percentage
+    /// is at least `Low`. Percentages above `100` are clamped.
+    pub fn from_percent(percent: u8) -> Self {
+        if percent == 0 {
+            return Self::Zero;
+        }
+        let percent = percent.min(100) as usize;
+        Self::ALL[((percent * 3 + 50) / 100).max(1)]
+    }
+
+    /// Get the non-zero brightness level nearest to the given fraction of full brightness.
+    ///
+    /// `0.0` maps to `Low` and `1.0` to `Full`; values outside that range are clamped.
+    pub fn from_fraction(fraction: f32) -> Self {
+        use Brightness::*;
+        let levels = [Low, Half, Full];
+        let idx = (fraction.clamp(0.0, 1.0) * (levels.len() - 1) as f32).round() as usize;
+        levels[idx]
+    }
+
+    /// Get the non-zero brightness level that is `steps` levels brighter, or dimmer if negative.
+    ///
+    /// Stops at `Low` and `Full` instead of wrapping around.
+    pub fn step(&self, steps: i32) -> Self {
+        use Brightness::*;
+        let levels = [Low, Half, Full];
+        let idx = levels.iter().position(|level| level == self).unwrap_or(0) as i32;
+        levels[(idx + steps).clamp(0, levels.len() as i32 - 1) as usize]
+    }
+
+    /// Get the next non-zero brightness level, cycling `Low -> Half -> Full -> Low`.
+    pub fn next(&self) -> Self {
         use Brightness::*;
         match self {
             Zero => Default::default(), // Jump to default
@@ -42,63 +123,244 @@ impl Brightness {
             Full => Low,
         }
     }
+}
 
-    pub fn cycle(&mut self) -> Self {
-        *self = self.next();
-        *self
+/// The I2C messages that control the LED, which differ between models.
+///
+/// The brightness is set by writing `prefix`, the raw brightness value and `suffix` as one
+/// message. The default is the sequence of the M433IA, which most models use.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LedCommand {
+    /// Bytes written before the brightness value
+    prefix: Vec<u8>,
+    /// Bytes written after the brightness value
+    #[serde(default)]
+    suffix: Vec<u8>,
+    /// Messages written once after opening the device, for models that need the LED enabled
+    #[serde(default)]
+    init: Vec<Vec<u8>>,
+}
+
+impl Default for LedCommand {
+    fn default() -> Self {
+        Self {
+            prefix: vec![
+                0x05, 0x00, 0x3d, 0x03, 0x06, 0x00, 0x07, 0x00, 0x0d, 0x14, 0x03,
+            ],
+            suffix: vec![0xad],
+            init: Vec::new(),
+        }
     }
 }
 
+impl LedCommand {
+    /// Check that the command can set the brightness at all.
+    pub fn validate(&self) -> Result<()> {
+        if self.prefix.is_empty() {
+            return Err(anyhow!("the LED command's prefix should not be empty"));
+        }
+        if self.init.iter().any(Vec::is_empty) {
+            return Err(anyhow!(
+                "the LED command's init messages should not be empty"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the message that sets the brightness to the raw value.
+    fn message(&self, raw: u8) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(self.prefix.len() + 1 + self.suffix.len());
+        msg.extend_from_slice(&self.prefix);
+        msg.push(raw);
+        msg.extend_from_slice(&self.suffix);
+        msg
+    }
+}
+
+enum LedDevice {
+    I2c(LinuxI2CDevice),
+    /// Only log the brightness changes
+    DryRun,
+    /// The I2C device couldn't be opened
+    Unavailable,
+}
+
 pub struct TouchpadI2C {
-    dev: LinuxI2CDevice,
+    dev: LedDevice,
     i2c_id: u32,
+    /// Number of failed writes, to surface flaky hardware
+    errors: u64,
+    /// The messages to write for the model
+    command: LedCommand,
 }
 
 impl TouchpadI2C {
-    pub fn new(i2c_id: u32) -> Result<Self> {
-        const TOUCHPAD_ADDR: u16 = 0x15;
+    /// Address of the touchpad on the I2C bus, on most models.
+    pub(crate) const DEFAULT_ADDR: u16 = 0x15;
+
+    /// Open the touchpad's I2C device, to control the LED with the model's messages.
+    ///
+    /// If `candidate_addrs` is non-empty, each address is probed by turning the LED off, and the
+    /// first one that takes the write is used. Unlike a read, that can't take one of the
+    /// touchpad's reports. Otherwise, [`Self::DEFAULT_ADDR`] is used.
+    pub fn new(i2c_id: u32, candidate_addrs: &[u16], command: LedCommand) -> Result<Self> {
+        let off = command.message(Brightness::Zero as u8);
+        for &addr in candidate_addrs {
+            let mut dev = Self::open(i2c_id, addr)?;
+            match dev.write(&off) {
+                Ok(()) => {
+                    info!("Using touchpad at I2C address {:#04x}", addr);
+                    return Ok(Self::with_dev(LedDevice::I2c(dev), i2c_id, command));
+                }
+                Err(err) => debug!("No response at I2C address {:#04x}: {}", addr, err),
+            }
+        }
+        if !candidate_addrs.is_empty() {
+            warn!(
+                "None of the I2C addresses {:x?} responded, falling back to {:#04x}",
+                candidate_addrs,
+                Self::DEFAULT_ADDR
+            );
+        }
+        Ok(Self::with_dev(
+            LedDevice::I2c(Self::open(i2c_id, Self::DEFAULT_ADDR)?),
+            i2c_id,
+            command,
+        ))
+    }
+
+    /// Like [`Self::new`], but if the device can't be opened, only warn and ignore any
+    /// brightness changes. The numpad keys still work then.
+    pub fn new_or_unavailable(i2c_id: u32, candidate_addrs: &[u16], command: LedCommand) -> Self {
+        Self::new(i2c_id, candidate_addrs, command).unwrap_or_else(|err| {
+            warn!(
+                "Brightness control is unavailable, the numpad LED won't light up: {:#}",
+                err
+            );
+            Self::with_dev(LedDevice::Unavailable, i2c_id, LedCommand::default())
+        })
+    }
+
+    fn with_dev(dev: LedDevice, i2c_id: u32, command: LedCommand) -> Self {
+        let mut touchpad = Self {
+            dev,
+            i2c_id,
+            errors: 0,
+            command: LedCommand::default(),
+        };
+        touchpad.set_led_command(command);
+        touchpad
+    }
+
+    fn open(i2c_id: u32, addr: u16) -> Result<LinuxI2CDevice> {
         let dev = unsafe {
-            LinuxI2CDevice::force_new(format!("/dev/i2c-{}", i2c_id), TOUCHPAD_ADDR).map_err(
-                |err| {
-                    let mut context = format!("Unable to open Touchpad I2C at /dev/i2c-{}", i2c_id);
-                    let extra_context = match &err {
-                        LinuxI2CError::Io(e) => match e.kind() {
-                            NotFound => "Is i2c-dev kernel module loaded?",
-                            PermissionDenied => "Do you have the permission to read /dev/i2c-*?",
-                            _ => "",
-                        },
-                        LinuxI2CError::Nix(_) => "",
-                    };
-                    if !extra_context.is_empty() {
-                        context.push_str(". ");
-                        context.push_str(extra_context);
-                    };
-                    Error::new(err).context(context)
-                },
-            )?
+            LinuxI2CDevice::force_new(format!("/dev/i2c-{}", i2c_id), addr).map_err(|err| {
+                let hint = match &err {
+                    LinuxI2CError::Io(e) => match e.kind() {
+                        NotFound => "Is i2c-dev kernel module loaded?",
+                        PermissionDenied => "Do you have the permission to read /dev/i2c-*?",
+                        _ => "",
+                    },
+                    LinuxI2CError::Nix(_) => "",
+                };
+                Error::new(err).context(NumpadError::I2cUnavailable { i2c_id, hint })
+            })?
         };
-        Ok(Self { dev, i2c_id })
-    }
-
-    pub fn set_brightness(&mut self, brightness: Brightness) -> Result<()> {
-        let msg = [
-            0x05,
-            0x00,
-            0x3d,
-            0x03,
-            0x06,
-            0x00,
-            0x07,
-            0x00,
-            0x0d,
-            0x14,
-            0x03,
-            brightness as u8,
-            0xad,
-        ];
-        self.dev
-            .write(&msg)
-            .with_context(|| format!("Could not set touchpad brightness to {}", brightness))
+        Ok(dev)
+    }
+
+    /// Create a touchpad that only prints the brightness changes instead of doing them.
+    pub fn dry_run(i2c_id: u32) -> Self {
+        Self::with_dev(LedDevice::DryRun, i2c_id, LedCommand::default())
+    }
+
+    /// Get the I2C ID of the touchpad.
+    pub fn i2c_id(&self) -> u32 {
+        self.i2c_id
+    }
+
+    /// Get the number of failed writes since startup.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Use the model's LED messages, writing its init messages if it has any.
+    ///
+    /// Failing to write them is only logged, as the LED might still work without them.
+    fn set_led_command(&mut self, command: LedCommand) {
+        if let LedDevice::I2c(dev) = &mut self.dev {
+            for msg in &command.init {
+                debug!("Writing LED init message {:x?}", msg);
+                if let Err(err) = dev.write(msg) {
+                    self.errors += 1;
+                    warn!("Could not write LED init message {:x?}: {}", msg, err);
+                }
+            }
+        }
+        self.command = command;
+    }
+
+    fn write_raw(&mut self, raw: u8) -> Result<()> {
+        let dev = match &mut self.dev {
+            LedDevice::I2c(dev) => dev,
+            LedDevice::DryRun => {
+                println!("Dry run: set brightness to {}", raw);
+                return Ok(());
+            }
+            LedDevice::Unavailable => {
+                debug!(
+                    "Brightness control is unavailable, not setting it to {}",
+                    raw
+                );
+                return Ok(());
+            }
+        };
+        debug!("Setting brightness to {}", raw);
+        let result = dev.write(&self.command.message(raw));
+        if result.is_err() {
+            self.errors += 1;
+        }
+        result.with_context(|| {
+            format!(
+                "Could not set touchpad brightness to {} ({} I2C errors so far)",
+                raw, self.errors
+            )
+        })
+    }
+}
+
+/// Get the levels to set, in order, to fade from one level to another.
+///
+/// Only the levels' register values are known to work, so the fade goes through the levels in
+/// between. The last step is always `to`, and there are none if it's the same as `from`.
+pub(crate) fn fade_steps(from: Brightness, to: Brightness) -> Vec<Brightness> {
+    let rank = |level: Brightness| {
+        Brightness::ALL
+            .iter()
+            .position(|l| *l == level)
+            .unwrap_or(0)
+    };
+    let (from, to) = (rank(from), rank(to));
+    if from <= to {
+        Brightness::ALL[from + 1..=to].to_vec()
+    } else {
+        Brightness::ALL[to..from].iter().rev().copied().collect()
+    }
+}
+
+/// Controls the brightness of the numpad LED.
+pub(crate) trait LedControl {
+    /// Write a raw brightness register value. Values above `Brightness::Low` are clamped.
+    fn set_brightness_raw(&mut self, raw: u8) -> Result<()>;
+
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<()> {
+        self.set_brightness_raw(brightness as u8)
+    }
+}
+
+impl LedControl for TouchpadI2C {
+    fn set_brightness_raw(&mut self, raw: u8) -> Result<()> {
+        self.write_raw(raw.min(Brightness::Low as u8))
     }
 }
 
@@ -107,3 +369,64 @@ impl Debug for TouchpadI2C {
         f.write_str(&format!("TouchpadI2C: /dev/i2c-{}", self.i2c_id))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_led_command() {
+        let command = LedCommand::default();
+        assert_eq!(
+            command.message(Brightness::Half as u8),
+            vec![0x05, 0x00, 0x3d, 0x03, 0x06, 0x00, 0x07, 0x00, 0x0d, 0x14, 0x03, 24, 0xad]
+        );
+        assert!(command.validate().is_ok());
+
+        let command: LedCommand =
+            toml::from_str("prefix = [0x10, 0x20]\ninit = [[0x01, 0x02]]").unwrap();
+        assert_eq!(command.message(1), vec![0x10, 0x20, 1]);
+        assert_eq!(command.init, vec![vec![0x01, 0x02]]);
+        let command: LedCommand = toml::from_str("prefix = []").unwrap();
+        assert!(command.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_brightness() {
+        assert_eq!(Brightness::try_from(24).unwrap(), Brightness::Half);
+        assert_eq!(Brightness::try_from(0).unwrap(), Brightness::Zero);
+        assert!(Brightness::try_from(2).is_err());
+        assert_eq!("off".parse::<Brightness>().unwrap(), Brightness::Zero);
+        assert_eq!("Low".parse::<Brightness>().unwrap(), Brightness::Low);
+        assert_eq!("FULL".parse::<Brightness>().unwrap(), Brightness::Full);
+        assert!("dim".parse::<Brightness>().is_err());
+        assert!("".parse::<Brightness>().is_err());
+
+        let config: Config = toml::from_str(r#"default_brightness = "half""#).unwrap();
+        assert_eq!(config.default_brightness(), Brightness::Half);
+        assert!(toml::from_str::<Config>(r#"default_brightness = "dim""#).is_err());
+    }
+
+    #[test]
+    fn test_from_percent() {
+        use Brightness::*;
+        assert_eq!(Brightness::from_percent(0), Zero);
+        assert_eq!(Brightness::from_percent(1), Low);
+        assert_eq!(Brightness::from_percent(33), Low);
+        assert_eq!(Brightness::from_percent(50), Half);
+        assert_eq!(Brightness::from_percent(67), Half);
+        assert_eq!(Brightness::from_percent(100), Full);
+        assert_eq!(Brightness::from_percent(200), Full);
+    }
+
+    #[test]
+    fn test_fade_steps() {
+        use Brightness::*;
+        assert_eq!(fade_steps(Zero, Low), vec![Low]);
+        assert_eq!(fade_steps(Zero, Full), vec![Low, Half, Full]);
+        assert_eq!(fade_steps(Full, Half), vec![Half]);
+        assert_eq!(fade_steps(Half, Zero), vec![Low, Zero]);
+        assert_eq!(fade_steps(Half, Half), Vec::new());
+    }
+}
@@ -1,9 +1,13 @@
+use std::time::Duration;
+
 use evdev_rs::TimeVal;
+use log::debug;
 
 pub(crate) trait ElapsedSince {
     /// Calculate time elapsed since `other`.
     ///
-    /// Requires that self >= other
+    /// If `other` is later, like when the timestamps jump back after a device reset, this is
+    /// zero instead.
     fn elapsed_since(&self, other: Self) -> CustomDuration;
 }
 
@@ -21,6 +25,12 @@ impl CustomDuration {
     }
 }
 
+impl From<CustomDuration> for Duration {
+    fn from(duration: CustomDuration) -> Self {
+        Duration::from_micros(duration.micros)
+    }
+}
+
 impl PartialOrd for CustomDuration {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
@@ -33,10 +43,87 @@ impl Ord for CustomDuration {
     }
 }
 
+/// Detects when the system resumes from suspend.
+///
+/// `CLOCK_BOOTTIME` includes the time spent in suspend, while `CLOCK_MONOTONIC`
+/// doesn't. So the difference between them grows by the duration of each suspend.
+#[derive(Debug)]
+pub(crate) struct SuspendDetector {
+    suspended_millis: i64,
+}
+
+impl SuspendDetector {
+    /// Ignore any tiny drift between the clocks.
+    const MIN_SUSPEND_MILLIS: i64 = 500;
+
+    pub(crate) fn new() -> Self {
+        Self {
+            suspended_millis: Self::total_suspended_millis(),
+        }
+    }
+
+    fn clock_millis(clock: libc::clockid_t) -> i64 {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safety: ts is a valid timespec, and both clocks are supported since Linux 2.6.39
+        unsafe { libc::clock_gettime(clock, &mut ts) };
+        ts.tv_sec * 1000 + ts.tv_nsec / 1_000_000
+    }
+
+    fn total_suspended_millis() -> i64 {
+        Self::clock_millis(libc::CLOCK_BOOTTIME) - Self::clock_millis(libc::CLOCK_MONOTONIC)
+    }
+
+    /// Check whether the system was suspended since the last call.
+    pub(crate) fn resumed(&mut self) -> bool {
+        let suspended_millis = Self::total_suspended_millis();
+        let resumed = suspended_millis - self.suspended_millis >= Self::MIN_SUSPEND_MILLIS;
+        self.suspended_millis = suspended_millis;
+        resumed
+    }
+}
+
+/// The current time, on the clock of the evdev event timestamps.
+///
+/// For toggles that aren't caused by an event, so that they can be compared with the events.
+/// Evdev uses `CLOCK_REALTIME` unless told otherwise.
+pub(crate) fn now() -> TimeVal {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safety: ts is a valid timespec, and CLOCK_REALTIME is always supported
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+    TimeVal {
+        tv_sec: ts.tv_sec,
+        tv_usec: ts.tv_nsec / 1000,
+    }
+}
+
+/// Minutes since midnight in the local time zone.
+pub(crate) fn local_minutes() -> u32 {
+    // Safety: a null pointer is allowed, and tm is only read after localtime_r filled it
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        debug!("Couldn't get the local time, assuming midnight");
+        return 0;
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
 impl ElapsedSince for TimeVal {
     fn elapsed_since(&self, other: Self) -> CustomDuration {
-        debug_assert!(*self >= other, "Called on older timeval!");
         const MICROS_PER_SEC: i64 = 1_000_000;
+        if *self < other {
+            debug!(
+                "Timestamp {}.{:06} is before {}.{:06}, assuming no time elapsed",
+                self.tv_sec, self.tv_usec, other.tv_sec, other.tv_usec
+            );
+            return CustomDuration { micros: 0 };
+        }
         let (secs, usec) = if self.tv_usec >= other.tv_usec {
             ((self.tv_sec - other.tv_sec), (self.tv_usec - other.tv_usec))
         } else {
@@ -64,3 +151,71 @@ fn test_elapsed_since() {
     };
     assert_eq!(t2.elapsed_since(t1), CustomDuration { micros: 999_900 });
 }
+
+#[test]
+fn test_elapsed_since_equal() {
+    let t = TimeVal {
+        tv_sec: 100,
+        tv_usec: 200,
+    };
+    assert_eq!(t.elapsed_since(t), CustomDuration { micros: 0 });
+}
+
+#[test]
+fn test_elapsed_since_same_usec() {
+    let t1 = TimeVal {
+        tv_sec: 100,
+        tv_usec: 500_000,
+    };
+    let t2 = TimeVal {
+        tv_sec: 103,
+        tv_usec: 500_000,
+    };
+    assert_eq!(t2.elapsed_since(t1), CustomDuration::from_millis(3000));
+}
+
+#[test]
+fn test_elapsed_since_multiple_secs() {
+    let t1 = TimeVal {
+        tv_sec: 100,
+        tv_usec: 900_000,
+    };
+    let t2 = TimeVal {
+        tv_sec: 102,
+        tv_usec: 100_000,
+    };
+    // 1.2s, borrowing a second from tv_sec
+    assert_eq!(t2.elapsed_since(t1), CustomDuration::from_millis(1200));
+}
+
+#[test]
+fn test_elapsed_since_older() {
+    let t1 = TimeVal {
+        tv_sec: 100,
+        tv_usec: 200,
+    };
+    let t2 = TimeVal {
+        tv_sec: 99,
+        tv_usec: 900_000,
+    };
+    assert_eq!(t2.elapsed_since(t1), CustomDuration { micros: 0 });
+}
+
+#[test]
+fn test_elapsed_since_compare() {
+    let hold_duration = CustomDuration::from_millis(250);
+    let start = TimeVal {
+        tv_sec: 100,
+        tv_usec: 900_000,
+    };
+    let just_before = TimeVal {
+        tv_sec: 101,
+        tv_usec: 149_999,
+    };
+    let exactly = TimeVal {
+        tv_sec: 101,
+        tv_usec: 150_000,
+    };
+    assert!(just_before.elapsed_since(start) < hold_duration);
+    assert!(exactly.elapsed_since(start) >= hold_duration);
+}